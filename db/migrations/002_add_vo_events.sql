@@ -0,0 +1,21 @@
+-- db/migrations/002_add_vo_events.sql
+-- VirtualObjectEvents written by the Jetson gateway's SQLite sink.
+
+CREATE TABLE IF NOT EXISTS vo_events (
+  event_id        TEXT PRIMARY KEY,
+  ts_unix_ms      INTEGER NOT NULL,
+  ts_iso          TEXT NOT NULL,
+  device_id       TEXT NOT NULL,
+  zone_id         TEXT NOT NULL,
+  category        TEXT NOT NULL,
+  fields_json     TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_vo_events_ts
+  ON vo_events(ts_unix_ms);
+
+CREATE INDEX IF NOT EXISTS idx_vo_events_device
+  ON vo_events(device_id, ts_unix_ms);
+
+CREATE INDEX IF NOT EXISTS idx_vo_events_category
+  ON vo_events(category);
@@ -0,0 +1,8 @@
+-- db/migrations/003_add_vo_event_timestamps.sql
+-- Source and ingest times of VirtualObjectEvents. ts_unix_ms is the source
+-- time when the device sent one; clock_skewed marks devices whose clock was
+-- off by more than the gateway's timestamps.max_skew_ms.
+
+ALTER TABLE vo_events ADD COLUMN source_ts_ms INTEGER;
+ALTER TABLE vo_events ADD COLUMN ingest_ts_ms INTEGER;
+ALTER TABLE vo_events ADD COLUMN clock_skewed INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,84 @@
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::mqtt::{Client, Publish};
+
+/// Holds back the acknowledgement of one input, such as the PUBACK for an
+/// incoming publish or a feed line's checkpoint, while the event is on its
+/// way to durable storage.
+///
+/// Every holder (the spool, or each sink until it has flushed) keeps a
+/// clone. The ack is released when the last clone is dropped, unless one of
+/// them called `fail`, in which case the input has to be delivered again.
+#[derive(Clone)]
+pub struct AckToken(Arc<AckInner>);
+
+/// What a token hands back when released: its item, and the reason
+/// delivery failed, if it did.
+pub type Released<T> = (T, Result<(), String>);
+
+type Release = Box<dyn FnOnce(Result<(), String>) + Send>;
+
+struct AckInner {
+    release: Mutex<Option<Release>>,
+}
+
+impl AckToken {
+    /// `item` is sent back on `tx` once the token is released or failed.
+    pub fn new<T: Send + 'static>(item: T, tx: UnboundedSender<Released<T>>) -> AckToken {
+        let release: Release = Box::new(move |result| {
+            let _ = tx.send((item, result));
+        });
+        AckToken(Arc::new(AckInner {
+            release: Mutex::new(Some(release)),
+        }))
+    }
+
+    /// A token standing in for this one that first hands the outcome to
+    /// `f`, for bookkeeping that has to follow durability.
+    pub fn chain(self, f: impl FnOnce(&Result<(), String>) + Send + 'static) -> AckToken {
+        let release: Release = Box::new(move |result| {
+            f(&result);
+            if let Err(reason) = &result {
+                self.fail(reason);
+            }
+        });
+        AckToken(Arc::new(AckInner {
+            release: Mutex::new(Some(release)),
+        }))
+    }
+
+    pub fn fail(&self, reason: &str) {
+        if let Some(release) = self.0.release.lock().unwrap().take() {
+            release(Err(reason.to_string()));
+        }
+    }
+}
+
+impl Drop for AckInner {
+    fn drop(&mut self) {
+        let release = match self.release.get_mut() {
+            Ok(release) => release.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Some(release) = release {
+            release(Ok(()));
+        }
+    }
+}
+
+/// Sends released PUBACKs until a delivery fails, and returns why.
+///
+/// Runs beside the poll loop because `Client::ack` waits for room in
+/// the request channel, which only the poll loop drains.
+pub async fn send_acks(client: Client, mut rx: UnboundedReceiver<Released<Publish>>) -> String {
+    while let Some((publish, result)) = rx.recv().await {
+        if let Err(reason) = result {
+            return reason;
+        }
+        if let Err(err) = client.ack(&publish).await {
+            return format!("ack failed: {err}");
+        }
+    }
+    "ack channel closed".to_string()
+}
@@ -0,0 +1,237 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::config::TimestampConfig;
+use crate::RawAnalyticsEvent;
+
+/// Reads a timestamp as milliseconds since the epoch: RFC 3339 text, or an
+/// epoch number (or numeric string) whose unit follows from its size, so
+/// seconds, milliseconds, microseconds and nanoseconds from any date after
+/// 1973 are told apart.
+pub fn parse_timestamp(value: &Value) -> Option<u64> {
+    let epoch = match value {
+        Value::Number(n) => n.as_f64()?,
+        Value::String(text) => match text.trim().parse::<f64>() {
+            Ok(n) => n,
+            Err(_) => {
+                let time = OffsetDateTime::parse(text.trim(), &Rfc3339).ok()?;
+                return u64::try_from(time.unix_timestamp_nanos() / 1_000_000).ok();
+            }
+        },
+        _ => return None,
+    };
+    if !epoch.is_finite() || epoch < 0.0 {
+        return None;
+    }
+    let ms = match epoch {
+        e if e < 1e11 => e * 1e3,
+        e if e < 1e14 => e,
+        e if e < 1e17 => e / 1e3,
+        e => e / 1e6,
+    };
+    Some(ms as u64)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClockStatus {
+    /// Source timestamps that were present but unreadable.
+    pub invalid_timestamps: u64,
+    /// Events flagged `clock_skewed`.
+    pub skewed_events: u64,
+    pub devices: BTreeMap<String, DeviceClockStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceClockStatus {
+    /// Estimated device clock minus gateway clock.
+    pub offset_ms: i64,
+    pub skewed: bool,
+}
+
+struct DeviceClock {
+    /// Source minus ingest time of recent events. Transit only ever makes
+    /// these smaller, so the largest is the best estimate of the offset.
+    samples: VecDeque<i64>,
+    last_seen_ms: u64,
+}
+
+impl DeviceClock {
+    fn offset_ms(&self) -> i64 {
+        self.samples.iter().copied().max().unwrap_or_default()
+    }
+}
+
+/// Finds source timestamps and keeps a clock offset estimate per device.
+pub struct Clocks {
+    config: TimestampConfig,
+    devices: Mutex<HashMap<String, DeviceClock>>,
+    invalid: AtomicU64,
+    skewed: AtomicU64,
+}
+
+impl Clocks {
+    pub fn new(config: &TimestampConfig) -> Clocks {
+        Clocks {
+            config: config.clone(),
+            devices: Mutex::new(HashMap::new()),
+            invalid: AtomicU64::new(0),
+            skewed: AtomicU64::new(0),
+        }
+    }
+
+    /// The first configured timestamp field on the envelope, or else on its
+    /// payload.
+    pub fn source_ts(&self, raw: &RawAnalyticsEvent) -> Option<u64> {
+        let value = self.config.fields.iter().find_map(|field| {
+            raw.extra
+                .get(field)
+                .or_else(|| raw.payload.get(field))
+                .filter(|value| !value.is_null())
+        })?;
+        let ts = parse_timestamp(value);
+        if ts.is_none() && self.invalid.fetch_add(1, Ordering::Relaxed) == 0 {
+            eprintln!("jetson-gateway: unreadable source timestamp {value}; using the ingest time");
+        }
+        ts
+    }
+
+    /// Adds an event to its device's offset estimate and returns whether the
+    /// device is skewed past `max_skew_ms`.
+    pub fn observe(&self, device_id: &str, source_ts: u64, ingest_ts: u64) -> bool {
+        let sample = source_ts as i64 - ingest_ts as i64;
+        let offset = {
+            let mut devices = self.devices.lock().unwrap();
+            if !devices.contains_key(device_id) && devices.len() >= self.config.max_devices {
+                let oldest = devices
+                    .iter()
+                    .min_by_key(|(_, clock)| clock.last_seen_ms)
+                    .map(|(id, _)| id.clone());
+                if let Some(oldest) = oldest {
+                    devices.remove(&oldest);
+                }
+            }
+            let clock = devices
+                .entry(device_id.to_string())
+                .or_insert_with(|| DeviceClock {
+                    samples: VecDeque::with_capacity(self.config.skew_window),
+                    last_seen_ms: ingest_ts,
+                });
+            if clock.samples.len() >= self.config.skew_window {
+                clock.samples.pop_front();
+            }
+            clock.samples.push_back(sample);
+            clock.last_seen_ms = ingest_ts;
+            clock.offset_ms()
+        };
+        let skewed = offset.unsigned_abs() > self.config.max_skew_ms;
+        if skewed && self.skewed.fetch_add(1, Ordering::Relaxed) == 0 {
+            eprintln!(
+                "jetson-gateway: clock of device {device_id:?} is off by {offset} ms; flagging its events clock_skewed"
+            );
+        }
+        skewed
+    }
+
+    /// `None` when source timestamps are off.
+    pub fn status(&self) -> Option<ClockStatus> {
+        if self.config.fields.is_empty() {
+            return None;
+        }
+        let devices = self.devices.lock().unwrap();
+        Some(ClockStatus {
+            invalid_timestamps: self.invalid.load(Ordering::Relaxed),
+            skewed_events: self.skewed.load(Ordering::Relaxed),
+            devices: devices
+                .iter()
+                .map(|(id, clock)| {
+                    let offset_ms = clock.offset_ms();
+                    let status = DeviceClockStatus {
+                        offset_ms,
+                        skewed: offset_ms.unsigned_abs() > self.config.max_skew_ms,
+                    };
+                    (id.clone(), status)
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// 2024-01-01T00:00:00Z.
+    const NEW_YEAR_S: u64 = 1_704_067_200;
+
+    #[test]
+    fn epoch_units_follow_from_magnitude() {
+        let cases = [
+            (json!(0), Some(0)),
+            (json!(NEW_YEAR_S), Some(NEW_YEAR_S * 1000)),
+            (json!(1_704_067_200.5), Some(NEW_YEAR_S * 1000 + 500)),
+            (json!(1_704_067_200_123u64), Some(1_704_067_200_123)),
+            (json!(1_704_067_200_123_456u64), Some(1_704_067_200_123)),
+            (json!(1_704_067_200_123_456_789u64), Some(1_704_067_200_123)),
+            // Seconds up to 1e11, milliseconds to 1e14, microseconds to 1e17.
+            (json!(99_999_999_999u64), Some(99_999_999_999_000)),
+            (json!(100_000_000_000u64), Some(100_000_000_000)),
+            (json!(99_999_999_999_999u64), Some(99_999_999_999_999)),
+            (json!(100_000_000_000_000u64), Some(100_000_000_000)),
+            (json!(100_000_000_000_000_000u64), Some(100_000_000_000)),
+            (json!("1704067200123"), Some(1_704_067_200_123)),
+            (json!(" 1704067200 "), Some(NEW_YEAR_S * 1000)),
+            (json!(-1), None),
+            (json!("-1704067200"), None),
+            (json!("inf"), None),
+            (json!("NaN"), None),
+            (json!("1e400"), None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_timestamp(&value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn rfc3339_offsets_are_applied() {
+        let new_year = NEW_YEAR_S * 1000;
+        let cases = [
+            ("2024-01-01T00:00:00Z", Some(new_year)),
+            ("2024-01-01T00:00:00.123Z", Some(new_year + 123)),
+            ("2024-01-01T00:00:00.123999999Z", Some(new_year + 123)),
+            ("2024-01-01T02:00:00+02:00", Some(new_year)),
+            ("2023-12-31T19:00:00.250-05:00", Some(new_year + 250)),
+            ("2024-01-01T00:00:00-00:30", Some(new_year + 1_800_000)),
+            (" 2024-01-01T00:00:00Z\n", Some(new_year)),
+            ("1970-01-01T00:00:00Z", Some(0)),
+            ("1969-12-31T23:59:59Z", None),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse_timestamp(&json!(text)), expected, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn anything_else_is_no_timestamp() {
+        for value in [
+            json!(""),
+            json!("yesterday"),
+            json!("2024-01-01"),
+            json!("2024-01-01T00:00:00"),
+            json!("2024-13-01T00:00:00Z"),
+            json!("2024-01-01T00:00:00+25:00"),
+            json!("12:00:00Z"),
+            json!(null),
+            json!(true),
+            json!([NEW_YEAR_S]),
+            json!({"ts": NEW_YEAR_S}),
+        ] {
+            assert_eq!(parse_timestamp(&value), None, "{value}");
+        }
+    }
+}
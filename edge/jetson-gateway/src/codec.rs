@@ -0,0 +1,726 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use prost_reflect::{DescriptorPool, DynamicMessage, SerializeOptions};
+use serde_json::{Map, Number, Value};
+use std::borrow::Cow;
+use std::io::Read;
+
+use crate::config::{Config, Encoding, Subscription};
+use crate::mqtt::MessageProperties;
+use crate::GatewayError;
+
+/// Content-type parameters that name the protobuf message type.
+const MESSAGE_TYPE_PARAMS: &[&str] = &["proto", "messagetype", "type"];
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Decodes payloads into JSON, whatever their wire format, so the profiles
+/// only ever see JSON. Holds the protobuf descriptors, read once at startup.
+pub struct Codecs {
+    protobuf: Option<DescriptorPool>,
+    max_decompressed_bytes: usize,
+}
+
+/// One record of a message. A message holds one, or a batch of them: a
+/// JSON array, NDJSON lines, or a CBOR or MessagePack array or sequence.
+pub struct Record {
+    /// Position in the batch; `None` for a message that is not a batch.
+    pub index: Option<usize>,
+    /// The record's own bytes, decompressed, in the message's encoding;
+    /// `None` when the record is the whole message.
+    pub body: Option<Vec<u8>>,
+    pub value: Result<Value, GatewayError>,
+}
+
+impl Record {
+    fn whole(value: Value) -> Record {
+        Record {
+            index: None,
+            body: None,
+            value: Ok(value),
+        }
+    }
+}
+
+impl Codecs {
+    /// Reads `[protobuf] descriptor_set` and checks that it describes every
+    /// subscription's `protobuf_message`.
+    pub fn load(config: &Config) -> Result<Codecs, GatewayError> {
+        let max_decompressed_bytes = config.decoding.max_decompressed_bytes;
+        let Some(protobuf) = &config.protobuf else {
+            return Ok(Codecs {
+                protobuf: None,
+                max_decompressed_bytes,
+            });
+        };
+        let path = &protobuf.descriptor_set;
+        let bytes = std::fs::read(path).map_err(|err| {
+            GatewayError::Config(format!("cannot read {}: {err}", path.display()))
+        })?;
+        let pool = DescriptorPool::decode(bytes.as_slice())
+            .map_err(|err| GatewayError::Config(format!("{}: {err}", path.display())))?;
+        for sub in &config.subscriptions {
+            let Some(message) = &sub.protobuf_message else {
+                continue;
+            };
+            if pool.get_message_by_name(message).is_none() {
+                return Err(GatewayError::Config(format!(
+                    "subscription {:?}: {} does not describe message {message:?}",
+                    sub.topic,
+                    path.display()
+                )));
+            }
+        }
+        Ok(Codecs {
+            protobuf: Some(pool),
+            max_decompressed_bytes,
+        })
+    }
+
+    /// Decompresses a gzip or zstd `payload`, recognized by its magic
+    /// number, decodes it in the encoding its v5 content type names, or else
+    /// the one its subscription is configured with, and splits batches.
+    ///
+    /// An error here rejects the whole message; a batch record that cannot
+    /// be read carries its own error.
+    pub fn decode(
+        &self,
+        payload: &[u8],
+        sub: Option<&Subscription>,
+        properties: Option<&MessageProperties>,
+    ) -> Result<Vec<Record>, GatewayError> {
+        let payload = self.decompress(payload)?;
+        let content_type = properties.and_then(|p| p.content_type.as_deref());
+        let (encoding, message) = match content_type.and_then(from_content_type) {
+            Some((encoding, message)) => (encoding, message),
+            None => (sub.map(|sub| sub.encoding).unwrap_or_default(), None),
+        };
+        match encoding {
+            Encoding::Json => split_json(std::str::from_utf8(&payload)?),
+            Encoding::Cbor => Ok(split(decode_cbor(&payload)?, cbor_to_json, encode_cbor)),
+            Encoding::MessagePack => Ok(split(
+                decode_msgpack(&payload)?,
+                msgpack_to_json,
+                encode_msgpack,
+            )),
+            Encoding::Protobuf => {
+                let message = message
+                    .or_else(|| sub.and_then(|sub| sub.protobuf_message.as_deref()))
+                    .ok_or_else(|| {
+                        decode_err("protobuf payload without a message type".to_string())
+                    })?;
+                let value = self.decode_protobuf(&payload, message)?;
+                Ok(vec![Record::whole(value)])
+            }
+        }
+    }
+
+    fn decompress<'a>(&self, payload: &'a [u8]) -> Result<Cow<'a, [u8]>, GatewayError> {
+        let (name, reader): (_, Box<dyn Read + '_>) = if payload.starts_with(GZIP_MAGIC) {
+            let reader = flate2::read::MultiGzDecoder::new(payload);
+            ("gzip", Box::new(reader))
+        } else if payload.starts_with(ZSTD_MAGIC) {
+            let reader = zstd::stream::read::Decoder::with_buffer(payload)
+                .map_err(|err| decode_err(format!("zstd: {err}")))?;
+            ("zstd", Box::new(reader))
+        } else {
+            return Ok(Cow::Borrowed(payload));
+        };
+        // Reading one byte past the limit tells a body of exactly the limit
+        // from a larger one without inflating the rest of a zip bomb.
+        let limit = self.max_decompressed_bytes;
+        let mut body = Vec::new();
+        reader
+            .take(limit as u64 + 1)
+            .read_to_end(&mut body)
+            .map_err(|err| decode_err(format!("{name}: {err}")))?;
+        if body.len() > limit {
+            return Err(decode_err(format!(
+                "{name} body inflates past max_decompressed_bytes ({limit})"
+            )));
+        }
+        Ok(Cow::Owned(body))
+    }
+
+    fn decode_protobuf(&self, payload: &[u8], message: &str) -> Result<Value, GatewayError> {
+        let descriptor = self
+            .protobuf
+            .as_ref()
+            .and_then(|pool| pool.get_message_by_name(message))
+            .ok_or_else(|| decode_err(format!("unknown protobuf message {message:?}")))?;
+        let decoded = DynamicMessage::decode(descriptor, payload)
+            .map_err(|err| decode_err(format!("{message}: {err}")))?;
+        // The proto3 JSON mapping, but with the field names of the .proto so
+        // `device_id` stays `device_id`, and 64-bit integers as numbers.
+        let options = SerializeOptions::new()
+            .use_proto_field_name(true)
+            .stringify_64_bit_integers(false);
+        Ok(decoded.serialize_with_options(serde_json::value::Serializer, &options)?)
+    }
+}
+
+/// The encoding a content type names, and the protobuf message type if it
+/// carries one (`application/x-protobuf; proto=vendor.v1.Detection`).
+/// `None` for content types that say nothing about the encoding.
+fn from_content_type(content_type: &str) -> Option<(Encoding, Option<&str>)> {
+    let mut parts = content_type.split(';');
+    let essence = parts.next()?.trim().to_ascii_lowercase();
+    let encoding = match essence.as_str() {
+        "application/json" | "text/json" => Encoding::Json,
+        e if e.ends_with("+json") => Encoding::Json,
+        "application/cbor" => Encoding::Cbor,
+        e if e.ends_with("+cbor") => Encoding::Cbor,
+        "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+            Encoding::MessagePack
+        }
+        "application/protobuf" | "application/x-protobuf" | "application/vnd.google.protobuf" => {
+            Encoding::Protobuf
+        }
+        _ => return None,
+    };
+    let message = parts.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        MESSAGE_TYPE_PARAMS
+            .contains(&name.trim().to_ascii_lowercase().as_str())
+            .then(|| value.trim().trim_matches('"'))
+    });
+    Some((encoding, message))
+}
+
+/// A single JSON value, a JSON array of records, or NDJSON. Text is taken
+/// for NDJSON only when it is not one JSON value but its first line is, so
+/// a broken pretty-printed object is still reported as one error.
+fn split_json(text: &str) -> Result<Vec<Record>, GatewayError> {
+    let err = match serde_json::from_str(text) {
+        Ok(Value::Array(items)) => {
+            return Ok(items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| Record {
+                    index: Some(index),
+                    body: Some(item.to_string().into_bytes()),
+                    value: Ok(item),
+                })
+                .collect())
+        }
+        Ok(value) => return Ok(vec![Record::whole(value)]),
+        Err(err) => err,
+    };
+    let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+    if lines.len() < 2 || serde_json::from_str::<Value>(lines[0]).is_err() {
+        return Err(err.into());
+    }
+    Ok(lines
+        .into_iter()
+        .enumerate()
+        .map(|(index, line)| Record {
+            index: Some(index),
+            body: Some(line.as_bytes().to_vec()),
+            value: serde_json::from_str(line).map_err(GatewayError::from),
+        })
+        .collect())
+}
+
+/// Records out of the values of a CBOR or MessagePack message: a single
+/// array is a batch, as is a sequence of several values.
+fn split<V>(mut values: Vec<V>, to_json: fn(V) -> Value, encode: fn(&V) -> Vec<u8>) -> Vec<Record>
+where
+    V: IntoArray,
+{
+    if values.len() == 1 {
+        match values.pop().unwrap().into_array() {
+            Ok(items) => values = items,
+            Err(value) => return vec![Record::whole(to_json(value))],
+        }
+    }
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| Record {
+            index: Some(index),
+            body: Some(encode(&value)),
+            value: Ok(to_json(value)),
+        })
+        .collect()
+}
+
+trait IntoArray: Sized {
+    fn into_array(self) -> Result<Vec<Self>, Self>;
+}
+
+impl IntoArray for ciborium::Value {
+    fn into_array(self) -> Result<Vec<Self>, Self> {
+        match self {
+            ciborium::Value::Array(items) => Ok(items),
+            other => Err(other),
+        }
+    }
+}
+
+impl IntoArray for rmpv::Value {
+    fn into_array(self) -> Result<Vec<Self>, Self> {
+        match self {
+            rmpv::Value::Array(items) => Ok(items),
+            other => Err(other),
+        }
+    }
+}
+
+/// Every value of a CBOR sequence (RFC 8742), usually just one.
+fn decode_cbor(payload: &[u8]) -> Result<Vec<ciborium::Value>, GatewayError> {
+    let mut rest = payload;
+    let mut values = Vec::new();
+    while !rest.is_empty() || values.is_empty() {
+        let value = ciborium::from_reader(&mut rest).map_err(|err| {
+            use ciborium::de::Error;
+            let at = payload.len() - rest.len();
+            decode_err(match err {
+                Error::Syntax(offset) => format!("CBOR: syntax error at byte {}", at + offset),
+                Error::Semantic(_, msg) => format!("CBOR: {msg}"),
+                Error::Io(err) => format!("CBOR: {err}"),
+                Error::RecursionLimitExceeded => "CBOR: nested too deeply".to_string(),
+            })
+        })?;
+        values.push(value);
+    }
+    Ok(values)
+}
+
+/// Every value of a stream of concatenated MessagePack values.
+fn decode_msgpack(payload: &[u8]) -> Result<Vec<rmpv::Value>, GatewayError> {
+    let mut rest = payload;
+    let mut values = Vec::new();
+    while !rest.is_empty() || values.is_empty() {
+        let value = rmpv::decode::read_value(&mut rest)
+            .map_err(|err| decode_err(format!("MessagePack: {err}")))?;
+        values.push(value);
+    }
+    Ok(values)
+}
+
+fn encode_cbor(value: &ciborium::Value) -> Vec<u8> {
+    let mut body = Vec::new();
+    ciborium::into_writer(value, &mut body).expect("writing to a Vec does not fail");
+    body
+}
+
+fn encode_msgpack(value: &rmpv::Value) -> Vec<u8> {
+    let mut body = Vec::new();
+    rmpv::encode::write_value(&mut body, value).expect("writing to a Vec does not fail");
+    body
+}
+
+// Neither CBOR nor MessagePack maps onto JSON exactly. Byte strings become
+// base64 text, map keys that are not strings their JSON text, CBOR tags
+// their content, non-finite floats null, and invalid UTF-8 in MessagePack
+// strings U+FFFD.
+
+fn cbor_to_json(value: ciborium::Value) -> Value {
+    use ciborium::Value as C;
+    match value {
+        C::Null => Value::Null,
+        C::Bool(b) => Value::Bool(b),
+        C::Integer(n) => integer(i128::from(n)),
+        C::Float(f) => float(f),
+        C::Text(s) => Value::String(s),
+        C::Bytes(b) => Value::String(BASE64.encode(b)),
+        C::Tag(_, inner) => cbor_to_json(*inner),
+        C::Array(items) => Value::Array(items.into_iter().map(cbor_to_json).collect()),
+        C::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(k, v)| (key(cbor_to_json(k)), cbor_to_json(v)))
+                .collect(),
+        ),
+        // ciborium's Value is non-exhaustive.
+        _ => Value::Null,
+    }
+}
+
+fn msgpack_to_json(value: rmpv::Value) -> Value {
+    use rmpv::Value as M;
+    match value {
+        M::Nil => Value::Null,
+        M::Boolean(b) => Value::Bool(b),
+        M::Integer(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => Value::from(n),
+            (None, Some(n)) => Value::from(n),
+            (None, None) => Value::Null,
+        },
+        M::F32(f) => float(f64::from(f)),
+        M::F64(f) => float(f),
+        M::String(s) => Value::String(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        M::Binary(b) => Value::String(BASE64.encode(b)),
+        M::Array(items) => Value::Array(items.into_iter().map(msgpack_to_json).collect()),
+        M::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(k, v)| (key(msgpack_to_json(k)), msgpack_to_json(v)))
+                .collect::<Map<_, _>>(),
+        ),
+        M::Ext(_, b) => Value::String(BASE64.encode(b)),
+    }
+}
+
+fn integer(n: i128) -> Value {
+    if let Ok(n) = u64::try_from(n) {
+        Value::from(n)
+    } else if let Ok(n) = i64::try_from(n) {
+        Value::from(n)
+    } else {
+        // Beyond 64 bits; JSON numbers that large lose precision anyway.
+        float(n as f64)
+    }
+}
+
+fn float(f: f64) -> Value {
+    Number::from_f64(f).map_or(Value::Null, Value::Number)
+}
+
+fn key(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+fn decode_err(msg: String) -> GatewayError {
+    GatewayError::Decode(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_reflect::prost::Message;
+    use prost_reflect::prost_types::field_descriptor_proto::{Label, Type};
+    use prost_reflect::prost_types::{
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+    };
+    use serde_json::json;
+
+    const DETECTION: &str = "vendor.v1.Detection";
+
+    /// `message Detection { string device_id = 1; int64 count = 2;
+    /// repeated string labels = 3; }` in package `vendor.v1`.
+    fn descriptors() -> DescriptorPool {
+        let field = |name: &str, number, ty: Type, label: Label| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            r#type: Some(ty as i32),
+            label: Some(label as i32),
+            ..FieldDescriptorProto::default()
+        };
+        let file = FileDescriptorProto {
+            name: Some("vendor.proto".to_string()),
+            package: Some("vendor.v1".to_string()),
+            syntax: Some("proto3".to_string()),
+            message_type: vec![DescriptorProto {
+                name: Some("Detection".to_string()),
+                field: vec![
+                    field("device_id", 1, Type::String, Label::Optional),
+                    field("count", 2, Type::Int64, Label::Optional),
+                    field("labels", 3, Type::String, Label::Repeated),
+                ],
+                ..DescriptorProto::default()
+            }],
+            ..FileDescriptorProto::default()
+        };
+        DescriptorPool::from_file_descriptor_set(FileDescriptorSet { file: vec![file] }).unwrap()
+    }
+
+    fn codecs() -> Codecs {
+        Codecs {
+            protobuf: Some(descriptors()),
+            max_decompressed_bytes: 1024,
+        }
+    }
+
+    fn subscription(toml: &str) -> Subscription {
+        toml::from_str(&format!("topic = \"t\"\n{toml}")).unwrap()
+    }
+
+    fn content_type(content_type: &str) -> MessageProperties {
+        MessageProperties {
+            content_type: Some(content_type.to_string()),
+            ..MessageProperties::default()
+        }
+    }
+
+    fn values(records: Vec<Record>) -> Vec<Value> {
+        records.into_iter().map(|r| r.value.unwrap()).collect()
+    }
+
+    #[test]
+    fn content_types_name_the_encoding() {
+        let cases = [
+            ("application/json", Some((Encoding::Json, None))),
+            (
+                "application/vnd.acme+json; charset=utf-8",
+                Some((Encoding::Json, None)),
+            ),
+            ("Application/CBOR", Some((Encoding::Cbor, None))),
+            ("application/senml+cbor", Some((Encoding::Cbor, None))),
+            ("application/x-msgpack", Some((Encoding::MessagePack, None))),
+            (
+                "application/x-protobuf; proto=vendor.v1.Detection",
+                Some((Encoding::Protobuf, Some(DETECTION))),
+            ),
+            (
+                "application/protobuf; charset=binary; messageType=\"vendor.v1.Detection\"",
+                Some((Encoding::Protobuf, Some(DETECTION))),
+            ),
+            (
+                "application/vnd.google.protobuf",
+                Some((Encoding::Protobuf, None)),
+            ),
+            ("text/plain", None),
+            ("", None),
+        ];
+        for (content_type, expected) in cases {
+            assert_eq!(
+                from_content_type(content_type),
+                expected,
+                "{content_type:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_content_type_overrides_the_subscription_encoding() {
+        let codecs = codecs();
+        let payload = encode_cbor(&ciborium::Value::Map(vec![(
+            "kind".into(),
+            "person".into(),
+        )]));
+        let cbor = subscription("encoding = \"cbor\"");
+        let json = subscription("encoding = \"json\"");
+        let expected = vec![json!({"kind": "person"})];
+
+        let by_subscription = codecs.decode(&payload, Some(&cbor), None).unwrap();
+        assert_eq!(values(by_subscription), expected);
+        let props = content_type("application/cbor");
+        let by_content_type = codecs.decode(&payload, Some(&json), Some(&props)).unwrap();
+        assert_eq!(values(by_content_type), expected);
+        let props = content_type("application/json");
+        assert!(codecs.decode(&payload, Some(&cbor), Some(&props)).is_err());
+        // A content type that names no encoding leaves it to the subscription.
+        let props = content_type("application/octet-stream");
+        assert!(codecs.decode(&payload, Some(&cbor), Some(&props)).is_ok());
+    }
+
+    #[test]
+    fn cbor_maps_onto_json() {
+        use ciborium::Value as C;
+        let payload = encode_cbor(&C::Map(vec![
+            ("device_id".into(), "cam-1".into()),
+            ("count".into(), C::Integer((-3).into())),
+            ("score".into(), C::Float(0.5)),
+            ("nan".into(), C::Float(f64::NAN)),
+            ("raw".into(), C::Bytes(vec![1, 2, 3])),
+            (
+                "at".into(),
+                C::Tag(1, Box::new(C::Integer(1_700_000_000.into()))),
+            ),
+            (C::Integer(7.into()), C::Bool(true)),
+            ("tags".into(), C::Array(vec!["a".into(), C::Null])),
+        ]));
+        let records = codecs().decode(&payload, Some(&subscription("encoding = \"cbor\"")), None);
+        let records = records.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].index, None);
+        assert_eq!(
+            values(records),
+            [json!({
+                "device_id": "cam-1", "count": -3, "score": 0.5, "nan": null,
+                "raw": "AQID", "at": 1_700_000_000, "7": true, "tags": ["a", null],
+            })]
+        );
+    }
+
+    #[test]
+    fn cbor_arrays_and_sequences_are_batches() {
+        use ciborium::Value as C;
+        let codecs = codecs();
+        let cbor = subscription("encoding = \"cbor\"");
+        let first = C::Map(vec![("n".into(), C::Integer(1.into()))]);
+        let second = C::Map(vec![("n".into(), C::Integer(2.into()))]);
+
+        let array = encode_cbor(&C::Array(vec![first.clone(), second.clone()]));
+        let mut sequence = encode_cbor(&first);
+        sequence.extend(encode_cbor(&second));
+        for payload in [array, sequence] {
+            let records = codecs.decode(&payload, Some(&cbor), None).unwrap();
+            assert_eq!(records[1].index, Some(1));
+            assert_eq!(records[1].body.as_deref(), Some(&encode_cbor(&second)[..]));
+            assert_eq!(values(records), [json!({"n": 1}), json!({"n": 2})]);
+        }
+        let truncated = &encode_cbor(&first)[..2];
+        assert!(matches!(
+            codecs.decode(truncated, Some(&cbor), None),
+            Err(GatewayError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn msgpack_maps_onto_json() {
+        use rmpv::Value as M;
+        let codecs = codecs();
+        let msgpack = subscription("encoding = \"msgpack\"");
+        let record = M::Map(vec![
+            ("device_id".into(), "cam-1".into()),
+            ("count".into(), M::from(-3)),
+            ("big".into(), M::from(u64::MAX)),
+            ("score".into(), M::F32(0.5)),
+            ("raw".into(), M::Binary(vec![1, 2, 3])),
+            ("ext".into(), M::Ext(5, vec![9])),
+            (M::Boolean(false), M::Nil),
+        ]);
+        let payload = encode_msgpack(&record);
+        let decoded = values(codecs.decode(&payload, Some(&msgpack), None).unwrap());
+        assert_eq!(
+            decoded,
+            [json!({
+                "device_id": "cam-1", "count": -3, "big": u64::MAX, "score": 0.5,
+                "raw": "AQID", "ext": "CQ==", "false": null,
+            })]
+        );
+        // {"bad": "a\xff"}, a string that is not UTF-8.
+        let payload = [0x81, 0xa3, b'b', b'a', b'd', 0xa2, b'a', 0xff];
+        let decoded = values(codecs.decode(&payload, Some(&msgpack), None).unwrap());
+        assert_eq!(decoded, [json!({"bad": "a\u{fffd}"})]);
+
+        let batch = encode_msgpack(&M::Array(vec![M::from(1), M::from("two")]));
+        let props = content_type("application/vnd.msgpack");
+        let records = codecs.decode(&batch, None, Some(&props)).unwrap();
+        assert_eq!(records[0].index, Some(0));
+        assert_eq!(values(records), [json!(1), json!("two")]);
+        assert!(codecs.decode(&batch[..2], Some(&msgpack), None).is_err());
+    }
+
+    #[test]
+    fn protobuf_uses_the_message_type_from_the_content_type_or_subscription() {
+        let codecs = codecs();
+        let pool = descriptors();
+        let mut message = DynamicMessage::new(pool.get_message_by_name(DETECTION).unwrap());
+        message.set_field_by_name("device_id", prost_reflect::Value::String("cam-1".into()));
+        message.set_field_by_name("count", prost_reflect::Value::I64(1 << 40));
+        message.set_field_by_name(
+            "labels",
+            prost_reflect::Value::List(vec![prost_reflect::Value::String("car".into())]),
+        );
+        let payload = message.encode_to_vec();
+        let expected = [json!({"device_id": "cam-1", "count": 1u64 << 40, "labels": ["car"]})];
+
+        let sub = subscription(&format!(
+            "encoding = \"protobuf\"\nprotobuf_message = \"{DETECTION}\""
+        ));
+        assert_eq!(
+            values(codecs.decode(&payload, Some(&sub), None).unwrap()),
+            expected
+        );
+        let props = content_type(&format!("application/x-protobuf; proto={DETECTION}"));
+        assert_eq!(
+            values(codecs.decode(&payload, None, Some(&props)).unwrap()),
+            expected
+        );
+
+        let props = content_type("application/x-protobuf");
+        assert!(codecs.decode(&payload, None, Some(&props)).is_err());
+        let props = content_type("application/x-protobuf; proto=vendor.v1.Missing");
+        assert!(codecs.decode(&payload, None, Some(&props)).is_err());
+        let props = content_type(&format!("application/x-protobuf; proto={DETECTION}"));
+        assert!(codecs.decode(&[0xff], None, Some(&props)).is_err());
+    }
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(body: &[u8]) -> Vec<u8> {
+        zstd::stream::encode_all(body, 3).unwrap()
+    }
+
+    #[test]
+    fn compressed_bodies_are_inflated_up_to_the_limit() {
+        let codecs = codecs();
+        let limit = codecs.max_decompressed_bytes;
+        let record = br#"{"kind":"person"}"#;
+        for compress in [gzip, zstd] {
+            let records = codecs.decode(&compress(record), None, None).unwrap();
+            assert_eq!(values(records), [json!({"kind": "person"})]);
+
+            // A JSON string padded to exactly the limit still fits.
+            let exact = format!("\"{}\"", "a".repeat(limit - 2));
+            assert!(codecs
+                .decode(&compress(exact.as_bytes()), None, None)
+                .is_ok());
+            let over = format!("\"{}\"", "a".repeat(limit - 1));
+            let err = codecs.decode(&compress(over.as_bytes()), None, None);
+            assert!(matches!(err, Err(GatewayError::Decode(_))));
+
+            // A bomb is refused without inflating it all.
+            let bomb = compress(&vec![b' '; 8 << 20]);
+            assert!(bomb.len() < 64 << 10);
+            assert!(codecs.decode(&bomb, None, None).is_err());
+        }
+        // Concatenated gzip members are one body.
+        let mut members = gzip(b"[1,");
+        members.extend(gzip(b"2]"));
+        let records = codecs.decode(&members, None, None).unwrap();
+        assert_eq!(values(records), [json!(1), json!(2)]);
+        assert!(codecs.decode(&gzip(record)[..12], None, None).is_err());
+    }
+
+    #[test]
+    fn json_arrays_are_split_into_records() {
+        let records = codecs()
+            .decode(br#"[{"n": 1}, {"n": 2}, 3]"#, None, None)
+            .unwrap();
+        let indexes: Vec<_> = records.iter().map(|r| r.index).collect();
+        assert_eq!(indexes, [Some(0), Some(1), Some(2)]);
+        assert_eq!(records[1].body.as_deref(), Some(&br#"{"n":2}"#[..]));
+        assert_eq!(
+            values(records),
+            [json!({"n": 1}), json!({"n": 2}), json!(3)]
+        );
+
+        let records = codecs().decode(br#"{"n": [1, 2]}"#, None, None).unwrap();
+        assert_eq!(records[0].index, None);
+        assert!(records[0].body.is_none());
+        assert!(codecs().decode(b"[]", None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ndjson_lines_are_records_that_fail_alone() {
+        let codecs = codecs();
+        let text = "{\"n\": 1}\n\n  \n{\"n\": 2}\r\n{\"n\": \n{\"n\": 4}\n";
+        let records = codecs.decode(text.as_bytes(), None, None).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[2].index, Some(2));
+        assert_eq!(records[2].body.as_deref(), Some(&b"{\"n\": "[..]));
+        let outcome: Vec<_> = records.into_iter().map(|r| r.value.ok()).collect();
+        assert_eq!(
+            outcome,
+            [
+                Some(json!({"n": 1})),
+                Some(json!({"n": 2})),
+                None,
+                Some(json!({"n": 4}))
+            ]
+        );
+
+        // One value with a trailing newline is not a batch.
+        let records = codecs.decode(b"{\"n\": 1}\n", None, None).unwrap();
+        assert_eq!(records[0].index, None);
+        // Nor is a broken pretty-printed object, or a broken first line.
+        assert!(codecs.decode(b"{\n  \"n\": 1,\n}\n", None, None).is_err());
+        assert!(codecs
+            .decode(b"{\"n\": \n{\"n\": 2}\n", None, None)
+            .is_err());
+        assert!(codecs.decode(b"\xff\n{}", None, None).is_err());
+    }
+}
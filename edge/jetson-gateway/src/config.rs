@@ -0,0 +1,1384 @@
+use clap::{Parser, Subcommand};
+use rumqttc::{QoS, TlsConfiguration, Transport};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::mapping::Mapping;
+use crate::topic_pattern::TopicPattern;
+use crate::topic_template::TopicTemplate;
+use crate::{tls, GatewayError};
+
+const DEFAULT_CONFIG_PATH: &str = "/etc/jetson-gateway/gateway.toml";
+
+/// Command-line flags. Every flag overrides the matching config file entry;
+/// flags with an `env` name can also be set through the environment.
+#[derive(Debug, Parser)]
+#[command(
+    name = "jetson-gateway",
+    about = "Jetson MQTT analytics gateway for Javaspectre"
+)]
+pub struct Cli {
+    /// Path to the TOML config file
+    #[arg(long, short, env = "GATEWAY_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Broker host name or address
+    #[arg(long, env = "MQTT_HOST")]
+    pub broker_host: Option<String>,
+
+    /// Broker TCP port
+    #[arg(long, env = "MQTT_PORT")]
+    pub broker_port: Option<u16>,
+
+    /// MQTT client id
+    #[arg(long, env = "MQTT_CLIENT_ID")]
+    pub client_id: Option<String>,
+
+    /// MQTT user name
+    #[arg(long, env = "MQTT_USERNAME")]
+    pub username: Option<String>,
+
+    /// File holding the MQTT password
+    #[arg(long, env = "MQTT_PASSWORD_FILE")]
+    pub password_file: Option<PathBuf>,
+
+    /// Topic filter to subscribe to; repeat to subscribe to several.
+    /// Replaces the subscriptions from the config file.
+    #[arg(long = "subscribe", value_name = "FILTER")]
+    pub subscribe: Vec<String>,
+
+    /// QoS used for subscriptions given with --subscribe
+    #[arg(long, default_value_t = 1)]
+    pub qos: u8,
+
+    /// Adds an MQTT sink publishing to this topic template,
+    /// e.g. `vo/{zone_id}/{category}`
+    #[arg(long, env = "OUTPUT_TOPIC")]
+    pub output_topic: Option<String>,
+
+    /// Device id used when an event does not carry one
+    #[arg(long, env = "DEVICE_ID")]
+    pub device_id: Option<String>,
+
+    /// Zone id used when an event does not carry one
+    #[arg(long, env = "ZONE_ID")]
+    pub zone_id: Option<String>,
+
+    /// Tails this DeepStream NDJSON file as the `vision` feed
+    #[arg(long, env = "DEEPSTREAM_FEED_PATH")]
+    pub deepstream_feed: Option<PathBuf>,
+
+    /// Tails this AugSound NDJSON file as the `audio` feed
+    #[arg(long, env = "AUDIO_FEED_PATH")]
+    pub audio_feed: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Runs the gateway when no command is given.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Publishes dead-lettered messages to their original topics again, for
+    /// the running gateway to take another pass at (e.g. after a normalizer
+    /// fix)
+    ReplayDlq {
+        /// Dead-letter files to replay; defaults to `dead_letter.path` and
+        /// its rotated files, oldest first
+        files: Vec<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub broker: BrokerConfig,
+    pub subscriptions: Vec<Subscription>,
+    pub feeds: Vec<FeedConfig>,
+    pub sinks: Vec<SinkConfig>,
+    pub spool: Option<SpoolConfig>,
+    pub protobuf: Option<ProtobufConfig>,
+    pub decoding: DecodingConfig,
+    pub dead_letter: Option<DeadLetterConfig>,
+    pub timestamps: TimestampConfig,
+    pub event_ids: EventIdConfig,
+    pub dedup: Option<DedupConfig>,
+    pub schemas: Option<SchemaConfig>,
+    pub schema_drift: Option<SchemaDriftConfig>,
+    pub reconnect: ReconnectConfig,
+    pub health: HealthConfig,
+    pub shutdown: ShutdownConfig,
+    pub defaults: Defaults,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub keep_alive_secs: u64,
+    pub channel_capacity: usize,
+    pub mqtt_version: MqttVersion,
+    /// When false, the broker keeps the subscriber's session (subscriptions
+    /// and unacknowledged messages) across reconnects.
+    pub clean_session: bool,
+    /// MQTT v5 only: how long the broker keeps a persistent session after
+    /// the connection drops.
+    pub session_expiry_secs: u32,
+    /// Send the PUBACK for an incoming publish only once the event is
+    /// durable: fsynced to the spool, or flushed by every sink without one.
+    pub manual_acks: bool,
+    pub username: Option<String>,
+    /// The password is never read from the config itself: it comes from
+    /// this file, or from the environment variable named by `password_env`.
+    pub password_file: Option<PathBuf>,
+    pub password_env: Option<String>,
+    /// Connects over TLS when present.
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum MqttVersion {
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+/// `[broker.tls]`. The client key, like the password, comes from a file or
+/// an environment variable.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM CA bundle; the system roots when unset.
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate chain for mutual TLS.
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+    pub client_key_env: Option<String>,
+    /// Name sent as the SNI and that the broker certificate must be issued
+    /// for, when it differs from `broker.host` (e.g. a broker reached by IP
+    /// address, or behind a proxy routing on SNI).
+    pub server_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Subscription {
+    pub topic: String,
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    /// Format of the payloads arriving through this subscription.
+    #[serde(default)]
+    pub profile: PayloadProfile,
+    /// How the payloads are encoded, unless an MQTT v5 content type says
+    /// otherwise.
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Fully qualified protobuf message type of the payloads, e.g.
+    /// `vendor.v1.Detection`.
+    #[serde(default)]
+    pub protobuf_message: Option<String>,
+    /// Where device, zone and kind sit in the topic, e.g.
+    /// `analytics/{device_id}/events`.
+    #[serde(default)]
+    pub topic_pattern: Option<TopicPattern>,
+    /// Builds the envelope of vendor payloads that do not follow the
+    /// analytics layout.
+    #[serde(default)]
+    pub mapping: Option<Mapping>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadProfile {
+    /// `{device_id, zone_id, kind, payload}` envelopes.
+    #[default]
+    Analytics,
+    /// DeepStream `nvmsgconv` messages, minimal or full schema.
+    DeepStream,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Protobuf,
+}
+
+/// `[protobuf]`: message types for protobuf payloads.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProtobufConfig {
+    /// A `FileDescriptorSet`, as written by
+    /// `protoc --include_imports --descriptor_set_out=<file>`.
+    pub descriptor_set: PathBuf,
+}
+
+/// `[decoding]`: limits on reading incoming payloads.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecodingConfig {
+    /// Largest size a gzip or zstd payload may inflate to; larger ones are
+    /// rejected.
+    pub max_decompressed_bytes: usize,
+}
+
+/// `[timestamps]`: where events carry the time they happened, and how far
+/// a device clock may drift from ours.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimestampConfig {
+    /// Keys holding the source timestamp, tried in order on the envelope and
+    /// then on its payload. Empty turns source timestamps off.
+    pub fields: Vec<String>,
+    /// Clock offset past which a device's events are flagged.
+    pub max_skew_ms: u64,
+    /// Recent events per device the offset is estimated from.
+    pub skew_window: usize,
+    /// Devices tracked at once; the longest silent one makes room.
+    pub max_devices: usize,
+}
+
+/// `[event_ids]`: how event ids are made.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventIdConfig {
+    pub format: IdFormat,
+    /// Derive the id from the event's content instead of the clock, so a
+    /// replayed message gets the same id again.
+    pub deterministic: bool,
+    /// Put in front of every id.
+    pub prefix: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdFormat {
+    /// 26 Crockford base32 characters.
+    #[default]
+    Ulid,
+    /// Hyphenated lowercase hex.
+    UuidV7,
+}
+
+/// `[dedup]`: drops MQTT records already seen within a time window.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    pub window_secs: u64,
+    /// Keys remembered at most; the oldest make room.
+    pub max_entries: usize,
+    /// JSON Pointer to a record's unique id, e.g. `/payload/msg_id`.
+    /// Records without one, and all records when unset, are keyed on a
+    /// hash of their topic and bytes.
+    pub key: Option<String>,
+    /// Keeps the keys across restarts.
+    pub path: PathBuf,
+    pub checkpoint_interval_ms: u64,
+}
+
+/// `[schemas]`: JSON Schemas that events' `fields` must match, one per
+/// category.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchemaConfig {
+    /// Holds `<category>.json` files. Categories without one pass
+    /// unchecked.
+    pub dir: PathBuf,
+    pub policy: SchemaPolicy,
+    /// Violations listed per event at most.
+    pub max_violations: usize,
+}
+
+/// `[schema_drift]`: infers each `(device_id, category)` stream's payload
+/// schema and reports fields that appear, disappear or change type.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchemaDriftConfig {
+    /// Samples a stream's schema is inferred from before drift is reported.
+    pub min_samples: u64,
+    /// Recent samples presence rates and type counts are taken over.
+    pub window: u64,
+    /// Share of samples a field must have been in for its absence to count
+    /// as drift; rarer fields come and go unreported.
+    pub min_presence: f64,
+    /// Samples in a row without such a field before it is reported gone.
+    pub absent_after: u64,
+    /// Streams tracked at once; the longest silent one makes room.
+    pub max_streams: usize,
+    /// Paths tracked per stream; more are ignored.
+    pub max_fields: usize,
+    /// Keeps the inferred schemas across restarts.
+    pub path: PathBuf,
+    pub checkpoint_interval_ms: u64,
+}
+
+/// What happens to an event whose fields do not match their schema.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaPolicy {
+    /// Delivered as usual, with `schema_violations` listing what is wrong.
+    #[default]
+    Tag,
+    /// Tagged and delivered only to the sinks marked `quarantine`.
+    Quarantine,
+}
+
+/// An NDJSON file tailed for events, such as the DeepStream and AugSound
+/// feeds. `name` defaults to the kind and names the checkpoint file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub kind: FeedKind,
+    pub path: PathBuf,
+    /// Holds `<name>.offset`, the position up to which lines are durable.
+    #[serde(default = "default_feed_checkpoint_dir")]
+    pub checkpoint_dir: PathBuf,
+    #[serde(default = "default_checkpoint_interval_ms")]
+    pub checkpoint_interval_ms: u64,
+    /// Where to begin when there is no checkpoint yet.
+    #[serde(default)]
+    pub start: FeedStart,
+    /// Longer lines are skipped.
+    #[serde(default = "default_max_line_bytes")]
+    pub max_line_bytes: usize,
+    /// Lines handed to the sinks but not yet durable; reading pauses at
+    /// this many.
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedKind {
+    Vision,
+    Audio,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedStart {
+    #[default]
+    Beginning,
+    End,
+}
+
+/// One entry of the `[[sinks]]` list. `name` defaults to the sink type and
+/// shows up in logs and health reports.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SinkConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default = "default_sink_queue_capacity")]
+    pub queue_capacity: usize,
+    /// Takes the events `[schemas]` quarantines, and only those.
+    #[serde(default)]
+    pub quarantine: bool,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkKind {
+    Stdout,
+    File(FileSinkConfig),
+    Mqtt(MqttSinkConfig),
+    Socket(SocketSinkConfig),
+    Sqlite(SqliteSinkConfig),
+    Snapshots(SnapshotSinkConfig),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSinkConfig {
+    pub path: PathBuf,
+    /// fsync on every flush, so a flushed event survives power loss.
+    #[serde(default)]
+    pub fsync: bool,
+}
+
+/// Republishes events on `topic`, a template such as `vo/{zone_id}/{category}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttSinkConfig {
+    pub topic: String,
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    /// Defaults to `<broker.client_id>-<sink name>`.
+    #[serde(default)]
+    pub client_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketSinkConfig {
+    pub path: PathBuf,
+    pub buffer_capacity: usize,
+    pub reconnect_delay_ms: u64,
+}
+
+/// The default path is the catalog file Persistence.js opens from the
+/// working directory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqliteSinkConfig {
+    pub path: PathBuf,
+    pub batch_size: usize,
+    pub busy_timeout_ms: u64,
+}
+
+/// Excavates events' fields into virtual objects and writes what it found
+/// as a row of the catalog's `snapshots` table every `interval_secs`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotSinkConfig {
+    pub path: PathBuf,
+    /// Javaspectre session the snapshots belong to; created if missing.
+    pub session_id: String,
+    pub label: String,
+    pub interval_secs: u64,
+    /// Same limits as `VirtualObjectExcavator.js`.
+    pub max_depth: usize,
+    pub max_array_sample: usize,
+    /// Events excavated per snapshot; the rest are only counted.
+    pub max_events: usize,
+    pub busy_timeout_ms: u64,
+}
+
+/// Durable queue in front of the sinks; enabled when `[spool]` is present.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpoolConfig {
+    pub dir: PathBuf,
+    /// Disk budget for all segments together.
+    pub max_bytes: u64,
+    pub segment_bytes: u64,
+    /// What to give up once `max_bytes` is reached.
+    pub overflow: OverflowPolicy,
+    /// How often appends are fsynced; 0 syncs every append.
+    pub fsync_interval_ms: u64,
+    /// Minimum time between rewrites of a sink's cursor file.
+    pub checkpoint_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+}
+
+/// Where messages that cannot be normalized go; enabled when
+/// `[dead_letter]` is present. Set `topic`, `path` or both.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    /// Published to with the broker connection of `[broker]`.
+    pub topic: Option<String>,
+    pub qos: u8,
+    /// NDJSON file, rotated to `<path>.1` ... `<path>.<keep_files>`.
+    pub path: Option<PathBuf>,
+    pub max_file_bytes: u64,
+    pub keep_files: u32,
+    /// Dead letters waiting to be written; more are refused and counted,
+    /// and their messages delivered again.
+    pub queue_capacity: usize,
+}
+
+/// Backoff between attempts to reconnect the subscriber. Each consecutive
+/// failure multiplies the delay, up to `max_delay_ms`, and jitter spreads a
+/// fleet's retries out.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub multiplier: f64,
+    /// Fraction of each delay that is randomized: 0.5 waits between half
+    /// and all of it.
+    pub jitter: f64,
+    /// Consecutive failures after which the gateway reports a crash loop.
+    pub crash_loop_after: u32,
+    /// A connection that stays up this long resets the failure count.
+    pub stable_after_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// How often connection and sink health is logged; 0 disables the report.
+    pub log_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long the sinks get to write out what they hold after SIGINT or
+    /// SIGTERM before the process exits anyway.
+    pub drain_timeout_secs: u64,
+}
+
+/// Values applied to normalized events when the payload leaves them empty.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Defaults {
+    pub device_id: Option<String>,
+    pub zone_id: Option<String>,
+    pub category: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            broker: BrokerConfig::default(),
+            subscriptions: vec![Subscription {
+                topic: "analytics/+/events".to_string(),
+                qos: default_qos(),
+                profile: PayloadProfile::default(),
+                encoding: Encoding::default(),
+                protobuf_message: None,
+                topic_pattern: None,
+                mapping: None,
+            }],
+            feeds: Vec::new(),
+            sinks: Vec::new(),
+            spool: None,
+            protobuf: None,
+            decoding: DecodingConfig::default(),
+            dead_letter: None,
+            timestamps: TimestampConfig::default(),
+            event_ids: EventIdConfig::default(),
+            dedup: None,
+            schemas: None,
+            schema_drift: None,
+            reconnect: ReconnectConfig::default(),
+            health: HealthConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            defaults: Defaults::default(),
+        }
+    }
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "jetson-gateway".to_string(),
+            keep_alive_secs: 30,
+            channel_capacity: 10,
+            mqtt_version: MqttVersion::V311,
+            clean_session: true,
+            session_expiry_secs: 86_400,
+            manual_acks: false,
+            username: None,
+            password_file: None,
+            password_env: None,
+            tls: None,
+        }
+    }
+}
+
+impl Default for SocketSinkConfig {
+    fn default() -> Self {
+        SocketSinkConfig {
+            path: PathBuf::from("/run/javaspectre/ingest.sock"),
+            buffer_capacity: 10_000,
+            reconnect_delay_ms: 1000,
+        }
+    }
+}
+
+impl Default for SqliteSinkConfig {
+    fn default() -> Self {
+        SqliteSinkConfig {
+            path: PathBuf::from("javaspectre-catalog.sqlite3"),
+            batch_size: 100,
+            busy_timeout_ms: 5000,
+        }
+    }
+}
+
+impl Default for SnapshotSinkConfig {
+    fn default() -> Self {
+        SnapshotSinkConfig {
+            path: PathBuf::from("javaspectre-catalog.sqlite3"),
+            session_id: "jetson-gateway".to_string(),
+            label: "edge".to_string(),
+            interval_secs: 60,
+            max_depth: 6,
+            max_array_sample: 8,
+            max_events: 1000,
+            busy_timeout_ms: 5000,
+        }
+    }
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        SpoolConfig {
+            dir: PathBuf::from("/var/lib/jetson-gateway/spool"),
+            max_bytes: 1 << 30,
+            segment_bytes: 8 << 20,
+            overflow: OverflowPolicy::DropOldest,
+            fsync_interval_ms: 100,
+            checkpoint_interval_ms: 1000,
+        }
+    }
+}
+
+impl Default for DecodingConfig {
+    fn default() -> Self {
+        DecodingConfig {
+            max_decompressed_bytes: 16 << 20,
+        }
+    }
+}
+
+impl Default for TimestampConfig {
+    fn default() -> Self {
+        TimestampConfig {
+            fields: ["ts", "timestamp", "@timestamp", "ts_unix_ms", "time"]
+                .map(String::from)
+                .to_vec(),
+            max_skew_ms: 30_000,
+            skew_window: 16,
+            max_devices: 1024,
+        }
+    }
+}
+
+impl Default for EventIdConfig {
+    fn default() -> Self {
+        EventIdConfig {
+            format: IdFormat::default(),
+            deterministic: false,
+            prefix: "voevt_".to_string(),
+        }
+    }
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            window_secs: 600,
+            max_entries: 100_000,
+            key: None,
+            path: PathBuf::from("/var/lib/jetson-gateway/dedup.state"),
+            checkpoint_interval_ms: default_checkpoint_interval_ms(),
+        }
+    }
+}
+
+impl Default for SchemaConfig {
+    fn default() -> Self {
+        SchemaConfig {
+            dir: PathBuf::from("/etc/jetson-gateway/schemas"),
+            policy: SchemaPolicy::Tag,
+            max_violations: 10,
+        }
+    }
+}
+
+impl Default for SchemaDriftConfig {
+    fn default() -> Self {
+        SchemaDriftConfig {
+            min_samples: 3,
+            window: 100,
+            min_presence: 0.9,
+            absent_after: 10,
+            max_streams: 1024,
+            max_fields: 256,
+            path: PathBuf::from("/var/lib/jetson-gateway/schema-drift.ndjson"),
+            checkpoint_interval_ms: default_checkpoint_interval_ms(),
+        }
+    }
+}
+
+impl Default for DeadLetterConfig {
+    fn default() -> Self {
+        DeadLetterConfig {
+            topic: None,
+            qos: default_qos(),
+            path: None,
+            max_file_bytes: 16 << 20,
+            keep_files: 4,
+            queue_capacity: 1024,
+        }
+    }
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay_ms: 1000,
+            max_delay_ms: 60_000,
+            multiplier: 2.0,
+            jitter: 0.5,
+            crash_loop_after: 5,
+            stable_after_secs: 60,
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            log_interval_secs: 60,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain_timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Defaults {
+            device_id: None,
+            zone_id: None,
+            category: "analytics".to_string(),
+        }
+    }
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_sink_queue_capacity() -> usize {
+    1024
+}
+
+fn default_feed_checkpoint_dir() -> PathBuf {
+    PathBuf::from("/var/lib/jetson-gateway/feeds")
+}
+
+fn default_checkpoint_interval_ms() -> u64 {
+    1000
+}
+
+fn default_max_line_bytes() -> usize {
+    1 << 20
+}
+
+fn default_max_in_flight() -> usize {
+    512
+}
+
+impl FeedConfig {
+    pub fn new(kind: FeedKind, path: PathBuf) -> FeedConfig {
+        FeedConfig {
+            name: None,
+            kind,
+            path,
+            checkpoint_dir: default_feed_checkpoint_dir(),
+            checkpoint_interval_ms: default_checkpoint_interval_ms(),
+            start: FeedStart::default(),
+            max_line_bytes: default_max_line_bytes(),
+            max_in_flight: default_max_in_flight(),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self.kind.name().to_string(),
+        }
+    }
+}
+
+impl FeedKind {
+    /// The kind named by an event's `kind`, for events in the feed formats.
+    pub fn from_name(name: &str) -> Option<FeedKind> {
+        match name {
+            "vision" => Some(FeedKind::Vision),
+            "audio" => Some(FeedKind::Audio),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FeedKind::Vision => "vision",
+            FeedKind::Audio => "audio",
+        }
+    }
+
+    /// The category StreamGuard gives events from this feed.
+    pub fn category(self) -> &'static str {
+        match self {
+            FeedKind::Vision => "vision-compliance",
+            FeedKind::Audio => "audio-compliance",
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// Username and password, with the password read fresh from its file
+    /// or environment variable.
+    pub fn credentials(&self) -> Result<Option<(String, String)>, GatewayError> {
+        let Some(username) = &self.username else {
+            return Ok(None);
+        };
+        let password = read_secret(
+            "broker password",
+            self.password_file.as_deref(),
+            self.password_env.as_deref(),
+        )?;
+        Ok(Some((username.clone(), password.unwrap_or_default())))
+    }
+
+    /// Where rumqttc connects and how: TCP, or TLS with certificates read
+    /// fresh from their files. With `tls.server_name` it connects to a new
+    /// `tls::SniTunnel`, which does the TLS and has to live as long as the
+    /// connection.
+    pub fn endpoint(&self) -> Result<(String, Transport, Option<tls::SniTunnel>), GatewayError> {
+        let Some(tls) = &self.tls else {
+            return Ok((self.host.clone(), Transport::tcp(), None));
+        };
+        match &tls.server_name {
+            Some(name) => {
+                let tunnel = tls::SniTunnel::start(self, tls, name)?;
+                let path = tunnel.path().display().to_string();
+                Ok((path, Transport::Unix, Some(tunnel)))
+            }
+            None => {
+                let config = tls::client_config(tls)?;
+                Ok((
+                    self.host.clone(),
+                    Transport::tls_with_config(TlsConfiguration::Rustls(config)),
+                    None,
+                ))
+            }
+        }
+    }
+}
+
+impl Subscription {
+    pub fn qos(&self) -> QoS {
+        // Checked in `Config::validate`.
+        rumqttc::qos(self.qos).unwrap_or(QoS::AtLeastOnce)
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        rumqttc::matches(topic, self.filter())
+    }
+
+    /// The topic filter: a shared subscription `$share/<group>/<filter>`
+    /// delivers topics matching `<filter>`.
+    pub fn filter(&self) -> &str {
+        match self.topic.strip_prefix("$share/") {
+            Some(rest) => rest.split_once('/').map_or(rest, |(_, filter)| filter),
+            None => &self.topic,
+        }
+    }
+}
+
+impl Config {
+    /// The first subscription matching `topic`, which decides how its
+    /// messages are read.
+    pub fn subscription_for(&self, topic: &str) -> Option<&Subscription> {
+        self.subscriptions.iter().find(|sub| sub.matches(topic))
+    }
+}
+
+impl SinkConfig {
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self.kind.type_name().to_string(),
+        }
+    }
+}
+
+impl SinkKind {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            SinkKind::Stdout => "stdout",
+            SinkKind::File(_) => "file",
+            SinkKind::Mqtt(_) => "mqtt",
+            SinkKind::Socket(_) => "socket",
+            SinkKind::Sqlite(_) => "sqlite",
+            SinkKind::Snapshots(_) => "snapshots",
+        }
+    }
+
+    fn validate(&self, name: &str) -> Result<(), GatewayError> {
+        match self {
+            SinkKind::Stdout | SinkKind::File(_) => {}
+            SinkKind::Mqtt(mqtt) => {
+                TopicTemplate::parse(&mqtt.topic)?;
+                if mqtt.qos > 2 {
+                    return Err(config_err(&format!("sink {name:?}: qos must be 0, 1 or 2")));
+                }
+            }
+            SinkKind::Socket(socket) => {
+                if socket.buffer_capacity == 0 {
+                    return Err(config_err(&format!(
+                        "sink {name:?}: buffer_capacity must be at least 1"
+                    )));
+                }
+            }
+            SinkKind::Sqlite(sqlite) => {
+                if sqlite.batch_size == 0 {
+                    return Err(config_err(&format!(
+                        "sink {name:?}: batch_size must be at least 1"
+                    )));
+                }
+            }
+            SinkKind::Snapshots(snapshots) => {
+                if snapshots.session_id.is_empty() {
+                    return Err(config_err(&format!(
+                        "sink {name:?}: session_id must not be empty"
+                    )));
+                }
+                if snapshots.interval_secs == 0 || snapshots.max_events == 0 {
+                    return Err(config_err(&format!(
+                        "sink {name:?}: interval_secs and max_events must be at least 1"
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MqttSinkConfig {
+    pub fn qos(&self) -> QoS {
+        rumqttc::qos(self.qos).unwrap_or(QoS::AtLeastOnce)
+    }
+}
+
+impl DeadLetterConfig {
+    pub fn qos(&self) -> QoS {
+        rumqttc::qos(self.qos).unwrap_or(QoS::AtLeastOnce)
+    }
+
+    /// The file and those of its rotated predecessors that exist, oldest
+    /// first.
+    pub fn files(&self) -> Vec<PathBuf> {
+        let Some(path) = &self.path else {
+            return Vec::new();
+        };
+        let mut files: Vec<PathBuf> = (1..=self.keep_files)
+            .rev()
+            .map(|n| rotated_path(path, n))
+            .collect();
+        files.push(path.clone());
+        files.retain(|file| file.exists());
+        files
+    }
+
+    fn validate(&self, subscriptions: &[Subscription]) -> Result<(), GatewayError> {
+        if self.topic.is_none() && self.path.is_none() {
+            return Err(config_err("dead_letter needs a topic, a path or both"));
+        }
+        if let Some(topic) = &self.topic {
+            if !rumqttc::valid_topic(topic) {
+                return Err(config_err(&format!("invalid dead_letter.topic {topic:?}")));
+            }
+            // Dead letters coming back in would be rejected again, forever.
+            if let Some(sub) = subscriptions.iter().find(|sub| sub.matches(topic)) {
+                return Err(config_err(&format!(
+                    "dead_letter.topic {topic:?} matches subscription {:?}",
+                    sub.topic
+                )));
+            }
+        }
+        if self.qos > 2 {
+            return Err(config_err("dead_letter.qos must be 0, 1 or 2"));
+        }
+        if self.max_file_bytes == 0 || self.queue_capacity == 0 {
+            return Err(config_err(
+                "dead_letter.max_file_bytes and dead_letter.queue_capacity must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `<path>.<n>`, the n-th most recent rotation of a file.
+pub fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+impl Config {
+    /// Builds the effective config: built-in defaults, then the config
+    /// file, then env vars and flags.
+    pub fn from_cli(cli: Cli) -> Result<Config, GatewayError> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Config::default(),
+        };
+        config.apply_cli(cli);
+        // Without any configured sink, keep printing events like before.
+        if config.sinks.is_empty() {
+            config.sinks.push(SinkConfig {
+                name: None,
+                queue_capacity: default_sink_queue_capacity(),
+                quarantine: false,
+                kind: SinkKind::Stdout,
+            });
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, GatewayError> {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            GatewayError::Config(format!("cannot read {}: {err}", path.display()))
+        })?;
+        toml::from_str(&text)
+            .map_err(|err| GatewayError::Config(format!("{}: {err}", path.display())))
+    }
+
+    fn apply_cli(&mut self, cli: Cli) {
+        if let Some(host) = cli.broker_host {
+            self.broker.host = host;
+        }
+        if let Some(port) = cli.broker_port {
+            self.broker.port = port;
+        }
+        if let Some(client_id) = cli.client_id {
+            self.broker.client_id = client_id;
+        }
+        if cli.username.is_some() {
+            self.broker.username = cli.username;
+        }
+        if cli.password_file.is_some() {
+            self.broker.password_file = cli.password_file;
+            self.broker.password_env = None;
+        }
+        if !cli.subscribe.is_empty() {
+            self.subscriptions = cli
+                .subscribe
+                .into_iter()
+                .map(|topic| Subscription {
+                    topic,
+                    qos: cli.qos,
+                    profile: PayloadProfile::default(),
+                    encoding: Encoding::default(),
+                    protobuf_message: None,
+                    topic_pattern: None,
+                    mapping: None,
+                })
+                .collect();
+        }
+        for (kind, path) in [
+            (FeedKind::Vision, cli.deepstream_feed),
+            (FeedKind::Audio, cli.audio_feed),
+        ] {
+            let Some(path) = path else { continue };
+            match self
+                .feeds
+                .iter_mut()
+                .find(|feed| feed.name() == kind.name())
+            {
+                Some(feed) => feed.path = path,
+                None => self.feeds.push(FeedConfig::new(kind, path)),
+            }
+        }
+        if let Some(topic) = cli.output_topic {
+            self.sinks.push(SinkConfig {
+                name: Some("output-topic".to_string()),
+                queue_capacity: default_sink_queue_capacity(),
+                quarantine: false,
+                kind: SinkKind::Mqtt(MqttSinkConfig {
+                    topic,
+                    qos: default_qos(),
+                    retain: false,
+                    client_id: None,
+                }),
+            });
+        }
+        if cli.device_id.is_some() {
+            self.defaults.device_id = cli.device_id;
+        }
+        if cli.zone_id.is_some() {
+            self.defaults.zone_id = cli.zone_id;
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), GatewayError> {
+        let broker = &self.broker;
+        if broker.host.trim().is_empty() {
+            return Err(config_err("broker.host must not be empty"));
+        }
+        if broker.port == 0 {
+            return Err(config_err("broker.port must not be 0"));
+        }
+        if broker.client_id.trim().is_empty() {
+            return Err(config_err("broker.client_id must not be empty"));
+        }
+        // rumqttc asserts on keep-alives below 5 seconds.
+        if broker.keep_alive_secs < 5 {
+            return Err(config_err("broker.keep_alive_secs must be at least 5"));
+        }
+        if broker.channel_capacity == 0 {
+            return Err(config_err("broker.channel_capacity must be at least 1"));
+        }
+        // Unacked messages are only redelivered within the same session.
+        if broker.manual_acks && broker.clean_session {
+            return Err(config_err(
+                "broker.manual_acks requires broker.clean_session = false",
+            ));
+        }
+        if broker.password_file.is_some() && broker.password_env.is_some() {
+            return Err(config_err(
+                "set only one of broker.password_file and broker.password_env",
+            ));
+        }
+        if broker.username.is_none()
+            && (broker.password_file.is_some() || broker.password_env.is_some())
+        {
+            return Err(config_err("a broker password needs broker.username"));
+        }
+        if let Some(tls) = &broker.tls {
+            if tls.client_key_file.is_some() && tls.client_key_env.is_some() {
+                return Err(config_err(
+                    "set only one of broker.tls.client_key_file and broker.tls.client_key_env",
+                ));
+            }
+            if tls.client_cert_file.is_none()
+                && (tls.client_key_file.is_some() || tls.client_key_env.is_some())
+            {
+                return Err(config_err(
+                    "a broker.tls client key needs broker.tls.client_cert_file",
+                ));
+            }
+        }
+        // Fail at startup rather than on the first connect when a secret or
+        // certificate is missing or unreadable.
+        broker.credentials()?;
+        if let Some(tls) = &broker.tls {
+            tls::client_config(tls)?;
+            if let Some(name) = &tls.server_name {
+                tls::server_name(name)?;
+            }
+        }
+        if self.subscriptions.is_empty() && self.feeds.is_empty() {
+            return Err(config_err("at least one subscription or feed is required"));
+        }
+        for sub in &self.subscriptions {
+            if !rumqttc::valid_filter(&sub.topic) {
+                return Err(config_err(&format!(
+                    "invalid subscription filter {:?}",
+                    sub.topic
+                )));
+            }
+            if sub.qos > 2 {
+                return Err(config_err(&format!(
+                    "subscription {:?}: qos must be 0, 1 or 2",
+                    sub.topic
+                )));
+            }
+            if sub.encoding == Encoding::Protobuf
+                && (sub.protobuf_message.is_none() || self.protobuf.is_none())
+            {
+                return Err(config_err(&format!(
+                    "subscription {:?}: protobuf encoding needs protobuf_message and [protobuf]",
+                    sub.topic
+                )));
+            }
+            if sub.mapping.is_some() && sub.profile != PayloadProfile::Analytics {
+                return Err(config_err(&format!(
+                    "subscription {:?}: mapping needs profile = \"analytics\"",
+                    sub.topic
+                )));
+            }
+            if let Some(pattern) = &sub.topic_pattern {
+                if !pattern.overlaps(sub.filter()) {
+                    return Err(config_err(&format!(
+                        "subscription {:?}: topic_pattern cannot match any of its topics",
+                        sub.topic
+                    )));
+                }
+            }
+        }
+        let mut feed_names = HashSet::new();
+        for feed in &self.feeds {
+            let name = feed.name();
+            if !feed_names.insert(name.clone()) {
+                return Err(config_err(&format!(
+                    "duplicate feed name {name:?}; set `name` to tell them apart"
+                )));
+            }
+            if feed.path.as_os_str().is_empty() || feed.path.file_name().is_none() {
+                return Err(config_err(&format!("feed {name:?}: path must name a file")));
+            }
+            if feed.max_line_bytes == 0 || feed.max_in_flight == 0 {
+                return Err(config_err(&format!(
+                    "feed {name:?}: max_line_bytes and max_in_flight must be at least 1"
+                )));
+            }
+        }
+        let mut names = HashSet::new();
+        for sink in &self.sinks {
+            let name = sink.name();
+            if !names.insert(name.clone()) {
+                return Err(config_err(&format!(
+                    "duplicate sink name {name:?}; set `name` to tell them apart"
+                )));
+            }
+            if sink.queue_capacity == 0 {
+                return Err(config_err(&format!(
+                    "sink {name:?}: queue_capacity must be at least 1"
+                )));
+            }
+            sink.kind.validate(&name)?;
+        }
+        let reconnect = &self.reconnect;
+        if reconnect.initial_delay_ms == 0 || reconnect.max_delay_ms < reconnect.initial_delay_ms {
+            return Err(config_err(
+                "reconnect.initial_delay_ms must be at least 1 and at most reconnect.max_delay_ms",
+            ));
+        }
+        if reconnect.multiplier.is_nan() || reconnect.multiplier < 1.0 {
+            return Err(config_err("reconnect.multiplier must be at least 1"));
+        }
+        if !(0.0..=1.0).contains(&reconnect.jitter) {
+            return Err(config_err("reconnect.jitter must be between 0 and 1"));
+        }
+        if reconnect.crash_loop_after == 0 {
+            return Err(config_err("reconnect.crash_loop_after must be at least 1"));
+        }
+        if let Some(spool) = &self.spool {
+            if spool.segment_bytes == 0 || spool.max_bytes < 2 * spool.segment_bytes {
+                return Err(config_err(
+                    "spool.max_bytes must be at least twice spool.segment_bytes",
+                ));
+            }
+        }
+        if self.decoding.max_decompressed_bytes == 0 {
+            return Err(config_err(
+                "decoding.max_decompressed_bytes must be at least 1",
+            ));
+        }
+        if let Some(dead_letter) = &self.dead_letter {
+            dead_letter.validate(&self.subscriptions)?;
+        }
+        if let Some(dedup) = &self.dedup {
+            if dedup.window_secs == 0 || dedup.max_entries == 0 {
+                return Err(config_err(
+                    "dedup.window_secs and dedup.max_entries must be at least 1",
+                ));
+            }
+            if dedup
+                .key
+                .as_deref()
+                .is_some_and(|key| !key.starts_with('/'))
+            {
+                return Err(config_err(
+                    "dedup.key must be a JSON Pointer such as /payload/msg_id",
+                ));
+            }
+        }
+        let quarantine = self.sinks.iter().any(|sink| sink.quarantine);
+        match &self.schemas {
+            Some(schemas) => {
+                if schemas.max_violations == 0 {
+                    return Err(config_err("schemas.max_violations must be at least 1"));
+                }
+                if schemas.policy == SchemaPolicy::Quarantine && !quarantine {
+                    return Err(config_err(
+                        "schemas.policy = \"quarantine\" needs a sink with quarantine = true",
+                    ));
+                }
+                if schemas.policy == SchemaPolicy::Tag && quarantine {
+                    return Err(config_err(
+                        "quarantine sinks need schemas.policy = \"quarantine\"",
+                    ));
+                }
+                if self.sinks.iter().all(|sink| sink.quarantine) {
+                    return Err(config_err(
+                        "at least one sink must not be a quarantine sink",
+                    ));
+                }
+            }
+            None if quarantine => {
+                return Err(config_err("quarantine sinks need [schemas]"));
+            }
+            None => {}
+        }
+        if let Some(drift) = &self.schema_drift {
+            if drift.min_samples == 0
+                || drift.window == 0
+                || drift.absent_after == 0
+                || drift.max_streams == 0
+                || drift.max_fields == 0
+            {
+                return Err(config_err(
+                    "schema_drift.min_samples, window, absent_after, max_streams and max_fields must be at least 1",
+                ));
+            }
+            if !(0.0..=1.0).contains(&drift.min_presence) {
+                return Err(config_err(
+                    "schema_drift.min_presence must be between 0 and 1",
+                ));
+            }
+        }
+        // Ids go into MQTT topics through the {event_id} placeholder.
+        if self.event_ids.prefix.contains(['+', '#', '/', '\0']) {
+            return Err(config_err(
+                "event_ids.prefix must not contain +, #, / or NUL",
+            ));
+        }
+        if self.timestamps.skew_window == 0 || self.timestamps.max_devices == 0 {
+            return Err(config_err(
+                "timestamps.skew_window and timestamps.max_devices must be at least 1",
+            ));
+        }
+        if self.defaults.category.is_empty() {
+            return Err(config_err("defaults.category must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+/// Reads a secret from `file` (trailing newline trimmed) or from the
+/// environment variable `env`; `None` when neither is configured.
+pub fn read_secret(
+    what: &str,
+    file: Option<&Path>,
+    env: Option<&str>,
+) -> Result<Option<String>, GatewayError> {
+    if let Some(path) = file {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            GatewayError::Config(format!("cannot read {what} from {}: {err}", path.display()))
+        })?;
+        return Ok(Some(text.trim_end_matches(['\r', '\n']).to_string()));
+    }
+    if let Some(var) = env {
+        return std::env::var(var).map(Some).map_err(|_| {
+            GatewayError::Config(format!("{what}: environment variable {var} is not set"))
+        });
+    }
+    Ok(None)
+}
+
+fn config_err(msg: &str) -> GatewayError {
+    GatewayError::Config(msg.to_string())
+}
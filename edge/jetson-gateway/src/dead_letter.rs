@@ -0,0 +1,734 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+use crate::ack::AckToken;
+use crate::config::{rotated_path, Config, DeadLetterConfig};
+use crate::mqtt::{self, Client, Event, MessageProperties};
+use crate::{now_ms, GatewayError};
+
+/// How long `replay` waits for the broker to acknowledge what it published.
+const REPLAY_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `close` waits for the broker to acknowledge the last dead
+/// letters published to the topic.
+const CLOSE_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RejectCode {
+    InvalidUtf8,
+    /// Not JSON, or JSON not in the shape the profile expects.
+    InvalidJson,
+    /// Not valid CBOR, MessagePack or protobuf.
+    InvalidEncoding,
+    /// Well-formed, but refused by the profile's parser.
+    InvalidPayload,
+}
+
+/// Where a dead letter came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// A message on a subscription; the letter's topic is its topic.
+    #[default]
+    Mqtt,
+    /// A line of a feed file; the letter's topic is the file's path.
+    Feed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reason {
+    pub code: RejectCode,
+    pub detail: String,
+}
+
+impl Reason {
+    /// The reason a normalizer error stands for.
+    pub fn from_error(err: &GatewayError) -> Reason {
+        match err {
+            GatewayError::Utf8(err) => Reason {
+                code: RejectCode::InvalidUtf8,
+                detail: err.to_string(),
+            },
+            GatewayError::Decode(detail) => Reason {
+                code: RejectCode::InvalidEncoding,
+                detail: detail.clone(),
+            },
+            GatewayError::Serde(err) => Reason {
+                code: RejectCode::InvalidJson,
+                detail: err.to_string(),
+            },
+            GatewayError::Payload(detail) => Reason {
+                code: RejectCode::InvalidPayload,
+                detail: detail.clone(),
+            },
+            other => Reason {
+                code: RejectCode::InvalidPayload,
+                detail: other.to_string(),
+            },
+        }
+    }
+}
+
+/// A rejected message, one JSON line in the dead-letter file or one publish
+/// on the dead-letter topic. The body is kept whole so `replay-dlq` can send
+/// exactly what arrived.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// Absent from letters written before feeds had dead letters.
+    #[serde(default)]
+    pub source: Source,
+    pub topic: String,
+    pub received_ms: u64,
+    pub bytes: usize,
+    pub body_base64: String,
+    pub reason: Reason,
+    /// Position of the record in a batch; the body is then that record's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record: Option<usize>,
+    /// MQTT v5 properties of the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MessageProperties>,
+}
+
+impl DeadLetter {
+    pub fn new(
+        topic: String,
+        body: &[u8],
+        reason: Reason,
+        properties: Option<MessageProperties>,
+    ) -> DeadLetter {
+        DeadLetter {
+            source: Source::Mqtt,
+            topic,
+            received_ms: now_ms(),
+            bytes: body.len(),
+            body_base64: BASE64.encode(body),
+            reason,
+            record: None,
+            mqtt: properties,
+        }
+    }
+
+    pub fn body(&self) -> Result<Vec<u8>, GatewayError> {
+        BASE64
+            .decode(&self.body_base64)
+            .map_err(|err| GatewayError::Payload(format!("body_base64: {err}")))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetterStatus {
+    /// Dead letters appended to the file.
+    pub written: u64,
+    /// Dead letters the broker acknowledged on the topic; at QoS 0, which
+    /// it does not acknowledge, those handed to the client.
+    pub published: u64,
+    /// Dead letters a destination could not take.
+    pub failed: u64,
+    /// Dead letters refused by a full queue.
+    pub dropped: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct Health {
+    written: AtomicU64,
+    published: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Health {
+    fn error(&self, err: String) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        let mut last = self.last_error.lock().unwrap();
+        // Once per distinct error, not once per dead letter.
+        if last.as_deref() != Some(err.as_str()) {
+            eprintln!("jetson-gateway: dead letter: {err}");
+            *last = Some(err);
+        }
+    }
+}
+
+/// Health of the dead-letter queue, for the health report.
+#[derive(Clone)]
+pub struct DeadLetterMonitor(Option<Arc<Health>>);
+
+impl DeadLetterMonitor {
+    pub fn status(&self) -> Option<DeadLetterStatus> {
+        let health = self.0.as_ref()?;
+        Some(DeadLetterStatus {
+            written: health.written.load(Ordering::Relaxed),
+            published: health.published.load(Ordering::Relaxed),
+            failed: health.failed.load(Ordering::Relaxed),
+            dropped: health.dropped.load(Ordering::Relaxed),
+            last_error: health.last_error.lock().unwrap().clone(),
+        })
+    }
+}
+
+type Queued = (DeadLetter, Option<AckToken>);
+
+/// Writes dead letters in the background. A message's ack is held until its
+/// dead letter has been written to the file and acknowledged by the broker
+/// on the topic (at QoS 0, handed to the client), so that a crash in between
+/// gets it redelivered instead of lost. A dead letter that cannot be kept
+/// fails the ack, and the message is delivered again.
+pub struct DeadLetters {
+    tx: Option<mpsc::Sender<Queued>>,
+    health: Option<Arc<Health>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl DeadLetters {
+    /// Opens the dead-letter file and connection; without `[dead_letter]`
+    /// rejected messages are only logged.
+    pub async fn start(config: &Config) -> Result<DeadLetters, GatewayError> {
+        let Some(dlq) = &config.dead_letter else {
+            return Ok(DeadLetters {
+                tx: None,
+                health: None,
+                task: None,
+            });
+        };
+        let file = match &dlq.path {
+            Some(path) => Some(RotatingFile::open(path, dlq).await?),
+            None => None,
+        };
+        let health = Arc::new(Health::default());
+        let topic = match &dlq.topic {
+            Some(name) => {
+                let client_id = format!("{}-dead-letter", config.broker.client_id);
+                let unacked = Arc::new(Unacked::default());
+                let client = connect(config, client_id, name, &unacked, &health)?;
+                Some(Topic {
+                    name: name.clone(),
+                    qos: dlq.qos(),
+                    client,
+                    unacked,
+                })
+            }
+            None => None,
+        };
+        let (tx, rx) = mpsc::channel(dlq.queue_capacity);
+        let task = tokio::spawn(write_dead_letters(rx, file, topic, Arc::clone(&health)));
+        Ok(DeadLetters {
+            tx: Some(tx),
+            health: Some(health),
+            task: Some(task),
+        })
+    }
+
+    /// Queues a dead letter; the token is released once it is kept, and
+    /// failed if the queue is full.
+    pub fn send(&self, letter: DeadLetter, ack: Option<AckToken>) {
+        let (Some(tx), Some(health)) = (&self.tx, &self.health) else {
+            return;
+        };
+        if let Err(err) = tx.try_send((letter, ack)) {
+            if let (_, Some(ack)) = err.into_inner() {
+                ack.fail("dead-letter queue full");
+            }
+            if health.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                eprintln!("jetson-gateway: dead-letter queue full, refusing dead letters");
+            }
+        }
+    }
+
+    pub fn monitor(&self) -> DeadLetterMonitor {
+        DeadLetterMonitor(self.health.clone())
+    }
+
+    /// Writes out what is queued and disconnects.
+    pub async fn close(mut self) {
+        self.tx = None;
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+/// The dead-letter topic and the connection publishing to it.
+struct Topic {
+    name: String,
+    qos: QoS,
+    client: Client,
+    unacked: Arc<Unacked>,
+}
+
+/// Dead letters published at QoS 1 or 2 that the broker has not yet
+/// acknowledged, oldest first, each holding its message's ack. A broker
+/// acknowledges publishes in the order it received them.
+#[derive(Default)]
+struct Unacked {
+    acks: Mutex<VecDeque<Option<AckToken>>>,
+    drained: Notify,
+}
+
+impl Unacked {
+    /// Releases the oldest dead letter's ack, or fails it if the broker
+    /// refused the publish.
+    fn acknowledged(&self, rejected: bool, topic: &str, health: &Health) {
+        let ack = {
+            let mut acks = self.acks.lock().unwrap();
+            let ack = acks.pop_front();
+            if acks.is_empty() {
+                self.drained.notify_waiters();
+            }
+            ack
+        };
+        if rejected {
+            if let Some(Some(ack)) = &ack {
+                ack.fail("dead letter refused by the broker");
+            }
+            health.error(format!("publish to {topic}: refused by the broker"));
+        } else {
+            health.published.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Fails the acks of the dead letters the broker can no longer
+    /// acknowledge, so that their messages are delivered again.
+    fn abandon(&self) {
+        let acks: Vec<_> = self.acks.lock().unwrap().drain(..).flatten().collect();
+        for ack in acks {
+            ack.fail("dead-letter connection closed before the broker acknowledged");
+        }
+        self.drained.notify_waiters();
+    }
+
+    async fn wait_drained(&self) {
+        loop {
+            let drained = self.drained.notified();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+            if self.acks.lock().unwrap().is_empty() {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+/// Connects a publishing client and keeps its event loop polled; rumqttc
+/// reconnects on the next poll after an error and resends unacknowledged
+/// publishes.
+fn connect(
+    config: &Config,
+    client_id: String,
+    topic: &str,
+    unacked: &Arc<Unacked>,
+    health: &Arc<Health>,
+) -> Result<Client, GatewayError> {
+    let (client, mut eventloop) = mqtt::connect(&config.broker, &client_id, false)?;
+    let (topic, unacked, health) = (topic.to_string(), Arc::clone(unacked), Arc::clone(health));
+    tokio::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::PubAck { rejected, .. }) => {
+                    unacked.acknowledged(rejected, &topic, &health);
+                }
+                Ok(Event::Disconnected) => break,
+                Ok(_) => {}
+                Err(err) if mqtt::requests_done(&err) => break,
+                Err(err) => {
+                    eprintln!("jetson-gateway: {client_id}: {err}; reconnecting in 1s");
+                    sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+        unacked.abandon();
+    });
+    Ok(client)
+}
+
+async fn write_dead_letters(
+    mut rx: mpsc::Receiver<Queued>,
+    mut file: Option<RotatingFile>,
+    topic: Option<Topic>,
+    health: Arc<Health>,
+) {
+    while let Some((letter, ack)) = rx.recv().await {
+        let mut line = serde_json::to_vec(&letter).expect("dead letters are plain JSON");
+        let mut failed = None;
+        if let Some(topic) = &topic {
+            // Queued before the publish, whose PUBACK could otherwise
+            // arrive first.
+            let acknowledged = topic.qos != QoS::AtMostOnce;
+            if acknowledged {
+                topic.unacked.acks.lock().unwrap().push_back(ack.clone());
+            }
+            let publish = topic.client.publish(
+                topic.name.clone(),
+                topic.qos,
+                false,
+                line.clone(),
+                None,
+                None,
+            );
+            match publish.await {
+                Ok(()) if acknowledged => {}
+                Ok(()) => {
+                    health.published.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(err) => {
+                    if acknowledged {
+                        topic.unacked.acks.lock().unwrap().pop_back();
+                    }
+                    failed = Some(format!("publish to {}: {err}", topic.name));
+                }
+            }
+        }
+        if let Some(file) = &mut file {
+            line.push(b'\n');
+            match file.write(&line).await {
+                Ok(()) => {
+                    health.written.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(err) => failed = Some(format!("{}: {err}", file.path.display())),
+            }
+        }
+        if let Some(err) = failed {
+            // Acked, the message would be lost with its dead letter.
+            if let Some(ack) = ack {
+                ack.fail(&format!("dead letter not kept: {err}"));
+            }
+            health.error(err);
+        }
+    }
+    if let Some(topic) = topic {
+        let _ = timeout(CLOSE_ACK_TIMEOUT, topic.unacked.wait_drained()).await;
+        let _ = topic.client.try_disconnect();
+    }
+}
+
+/// An append-only file that moves to `<path>.1` once it would grow past
+/// `max_file_bytes`, pushing older ones up to `<path>.<keep_files>`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+    keep: u32,
+}
+
+impl RotatingFile {
+    async fn open(path: &Path, config: &DeadLetterConfig) -> Result<RotatingFile, GatewayError> {
+        let file = open_append(path).await.map_err(|err| {
+            GatewayError::Config(format!("cannot open {}: {err}", path.display()))
+        })?;
+        let len = file.metadata().await?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            len,
+            max_bytes: config.max_file_bytes,
+            keep: config.keep_files,
+        })
+    }
+
+    /// Appends `line` and syncs it, as the ack that waits on it expects.
+    /// tokio's `File` only reports a failed write on flush.
+    async fn write(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self.len > 0 && self.len + line.len() as u64 > self.max_bytes {
+            self.rotate().await?;
+        }
+        self.file.write_all(line).await?;
+        self.len += line.len() as u64;
+        self.file.flush().await?;
+        self.file.sync_data().await
+    }
+
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        for n in (1..self.keep).rev() {
+            match fs::rename(rotated_path(&self.path, n), rotated_path(&self.path, n + 1)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, rotated_path(&self.path, 1)).await?;
+        } else {
+            fs::remove_file(&self.path).await?;
+        }
+        self.file = open_append(&self.path).await?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+async fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// What `replay` got through.
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub published: u64,
+    /// Lines that are not dead letters.
+    pub unreadable: u64,
+    /// Publishes the broker did not acknowledge in time or refused.
+    pub unacked: u64,
+    /// Publishes the broker took but no subscription received (v5), so
+    /// nothing will normalize them.
+    pub unrouted: u64,
+    /// Dead letters of feed lines, not replayed: their topic is the feed's
+    /// path, which no subscription reads.
+    pub feed: u64,
+}
+
+/// Publishes the dead letters of MQTT messages in `files` to their original
+/// topics, with their original v5 properties, for the gateway to normalize
+/// again.
+pub async fn replay(config: &Config, files: &[PathBuf]) -> Result<ReplaySummary, GatewayError> {
+    let mut letters = Vec::new();
+    let mut summary = ReplaySummary::default();
+    for path in files {
+        let text = fs::read_to_string(path).await.map_err(|err| {
+            GatewayError::Config(format!("cannot read {}: {err}", path.display()))
+        })?;
+        for (n, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parsed = serde_json::from_str::<DeadLetter>(line)
+                .map_err(GatewayError::from)
+                .and_then(|letter| Ok((letter.body()?, letter)));
+            match parsed {
+                Ok((_, letter)) if letter.source == Source::Feed => summary.feed += 1,
+                Ok(entry) => letters.push(entry),
+                Err(err) => {
+                    eprintln!("jetson-gateway: {}:{}: {err}", path.display(), n + 1);
+                    summary.unreadable += 1;
+                }
+            }
+        }
+    }
+    if letters.is_empty() {
+        return Ok(summary);
+    }
+
+    let client_id = format!("{}-replay", config.broker.client_id);
+    let (client, mut eventloop) = mqtt::connect(&config.broker, &client_id, false)?;
+    let (ack_tx, mut ack_rx) = mpsc::unbounded_channel();
+    // Connection errors end the replay instead of retrying: the publishes
+    // not acknowledged by then are reported.
+    let poll = tokio::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::PubAck { rejected, unrouted }) => {
+                    let _ = ack_tx.send((rejected, unrouted));
+                }
+                Ok(Event::Disconnected) => return Ok(()),
+                Ok(_) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    });
+    for (body, letter) in letters {
+        let properties = letter.mqtt.as_ref();
+        let published = client
+            .republish(letter.topic, QoS::AtLeastOnce, body, properties)
+            .await;
+        if let Err(err) = published {
+            // The event loop is gone; its error says why.
+            return Err(match poll.await {
+                Ok(Err(err)) => err,
+                _ => err,
+            });
+        }
+        summary.published += 1;
+    }
+    let (mut answered, mut acked) = (0, 0);
+    let wait = async {
+        while answered < summary.published {
+            let Some((rejected, unrouted)) = ack_rx.recv().await else {
+                break;
+            };
+            answered += 1;
+            acked += u64::from(!rejected && !unrouted);
+            summary.unrouted += u64::from(unrouted);
+        }
+    };
+    let _ = timeout(REPLAY_ACK_TIMEOUT, wait).await;
+    summary.unacked = summary.published - acked - summary.unrouted;
+    if client.try_disconnect().is_ok() {
+        if let Ok(Ok(Err(err))) = timeout(REPLAY_ACK_TIMEOUT, poll).await {
+            eprintln!("jetson-gateway: {err}");
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn letter() -> DeadLetter {
+        let reason = Reason {
+            code: RejectCode::InvalidJson,
+            detail: "expected value".to_string(),
+        };
+        DeadLetter::new("sensors/1".to_string(), b"{", reason, None)
+    }
+
+    #[tokio::test]
+    async fn a_dead_letter_not_kept_fails_its_ack() {
+        let dir = std::env::temp_dir().join(format!("dead-letter-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await.unwrap();
+        let config = DeadLetterConfig::default();
+        let (acks, mut released) = unbounded_channel();
+        let health = Arc::new(Health::default());
+
+        let (tx, rx) = mpsc::channel(4);
+        let file = RotatingFile::open(&dir.join("dlq.ndjson"), &config)
+            .await
+            .unwrap();
+        let task = tokio::spawn(write_dead_letters(
+            rx,
+            Some(file),
+            None,
+            Arc::clone(&health),
+        ));
+        tx.send((letter(), Some(AckToken::new(1, acks.clone()))))
+            .await
+            .unwrap();
+        drop(tx);
+        task.await.unwrap();
+        assert_eq!(released.recv().await, Some((1, Ok(()))));
+        assert_eq!(health.written.load(Ordering::Relaxed), 1);
+
+        // A write error, as from a full disk.
+        let (tx, rx) = mpsc::channel(4);
+        let file = RotatingFile::open(Path::new("/dev/full"), &config)
+            .await
+            .unwrap();
+        let task = tokio::spawn(write_dead_letters(
+            rx,
+            Some(file),
+            None,
+            Arc::clone(&health),
+        ));
+        tx.send((letter(), Some(AckToken::new(2, acks.clone()))))
+            .await
+            .unwrap();
+        drop(tx);
+        task.await.unwrap();
+        let (item, result) = released.recv().await.unwrap();
+        assert_eq!(item, 2);
+        assert!(result
+            .unwrap_err()
+            .starts_with("dead letter not kept: /dev/full"));
+        assert_eq!(health.failed.load(Ordering::Relaxed), 1);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn a_published_dead_letter_holds_its_ack_until_the_broker_has_it() {
+        let (acks, mut released) = unbounded_channel();
+        let unacked = Unacked::default();
+        let health = Health::default();
+        for n in 1..=3 {
+            let ack = AckToken::new(n, acks.clone());
+            unacked.acks.lock().unwrap().push_back(Some(ack));
+        }
+        assert!(released.try_recv().is_err());
+
+        unacked.acknowledged(false, "dlq", &health);
+        assert_eq!(released.try_recv(), Ok((1, Ok(()))));
+        unacked.acknowledged(true, "dlq", &health);
+        assert_eq!(
+            released.try_recv(),
+            Ok((2, Err("dead letter refused by the broker".to_string())))
+        );
+        // The connection went away with the third unacknowledged.
+        unacked.abandon();
+        let (item, result) = released.try_recv().unwrap();
+        assert_eq!(item, 3);
+        assert!(result.is_err());
+        assert_eq!(
+            (
+                health.published.load(Ordering::Relaxed),
+                health.failed.load(Ordering::Relaxed)
+            ),
+            (1, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_skips_feed_lines() {
+        let path = std::env::temp_dir().join(format!("dead-letter-replay-{}", std::process::id()));
+        let feed = DeadLetter {
+            source: Source::Feed,
+            ..DeadLetter::new(
+                "/var/log/feed.ndjson".to_string(),
+                b"{",
+                letter().reason,
+                None,
+            )
+        };
+        let text = format!(
+            "{}\nnot a dead letter\n",
+            serde_json::to_string(&feed).unwrap()
+        );
+        fs::write(&path, text).await.unwrap();
+        // Nothing to publish, so no broker is needed.
+        let summary = replay(&Config::default(), std::slice::from_ref(&path))
+            .await
+            .unwrap();
+        assert_eq!(
+            (summary.published, summary.feed, summary.unreadable),
+            (0, 1, 1)
+        );
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[test]
+    fn letters_without_a_source_are_mqtt_messages() {
+        let mut line = serde_json::to_value(letter()).unwrap();
+        assert_eq!(line["source"], "mqtt");
+        line.as_object_mut().unwrap().remove("source");
+        let read: DeadLetter = serde_json::from_value(line).unwrap();
+        assert_eq!(read.source, Source::Mqtt);
+        assert_eq!(read.body().unwrap(), b"{");
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_fails_the_ack() {
+        let (tx, _rx) = mpsc::channel(1);
+        let dead_letters = DeadLetters {
+            tx: Some(tx),
+            health: Some(Arc::new(Health::default())),
+            task: None,
+        };
+        let (acks, mut released) = unbounded_channel();
+        dead_letters.send(letter(), Some(AckToken::new(1, acks.clone())));
+        dead_letters.send(letter(), Some(AckToken::new(2, acks)));
+        assert_eq!(
+            released.recv().await,
+            Some((2, Err("dead-letter queue full".to_string())))
+        );
+        assert_eq!(dead_letters.monitor().status().unwrap().dropped, 1);
+    }
+}
@@ -0,0 +1,427 @@
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::ack::AckToken;
+use crate::config::DedupConfig;
+use crate::{now_ms, GatewayError};
+
+/// Returned by `Dedup::admit` for a record seen within the window.
+pub struct Duplicate;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DedupStatus {
+    /// Keys remembered.
+    pub entries: usize,
+    pub duplicates: u64,
+    pub last_error: Option<String>,
+}
+
+/// Acks of duplicates that arrived while the original was in flight; they
+/// share its outcome.
+type Waiting = Arc<Mutex<Vec<AckToken>>>;
+
+struct Entry {
+    seen_ms: u64,
+    /// The event it came with is durable. Until then a failed delivery
+    /// forgets the key so the broker's redelivery gets through.
+    durable: bool,
+    /// Set while the event is in flight under manual acks.
+    waiting: Option<Waiting>,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<u128, Entry>,
+    /// Keys by first sight, for expiry and eviction. Keys forgotten since
+    /// are skipped when they come up.
+    order: VecDeque<(u128, u64)>,
+    /// Changed since the state file was written.
+    dirty: bool,
+}
+
+impl Cache {
+    fn expire(&mut self, now: u64, window_ms: u64, max_entries: usize) {
+        while let Some(&(key, seen_ms)) = self.order.front() {
+            if now.saturating_sub(seen_ms) < window_ms && self.entries.len() <= max_entries {
+                break;
+            }
+            self.order.pop_front();
+            if self
+                .entries
+                .get(&key)
+                .is_some_and(|entry| entry.seen_ms == seen_ms)
+            {
+                self.entries.remove(&key);
+                self.dirty = true;
+            }
+        }
+    }
+
+    fn insert(&mut self, key: u128, seen_ms: u64, durable: bool, waiting: Option<Waiting>) {
+        self.entries.insert(
+            key,
+            Entry {
+                seen_ms,
+                durable,
+                waiting,
+            },
+        );
+        self.order.push_back((key, seen_ms));
+        self.dirty = true;
+    }
+}
+
+struct Shared {
+    config: DedupConfig,
+    cache: Mutex<Cache>,
+    duplicates: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Shared {
+    fn window_ms(&self) -> u64 {
+        self.config.window_secs.saturating_mul(1000)
+    }
+
+    /// Replaces the state file atomically with the durable keys, one
+    /// `<key hex> <first seen ms>` line each.
+    fn save(&self) {
+        let lines: Vec<String> = {
+            let mut cache = self.cache.lock().unwrap();
+            if !cache.dirty {
+                return;
+            }
+            cache.expire(now_ms(), self.window_ms(), self.config.max_entries);
+            cache.dirty = false;
+            cache
+                .order
+                .iter()
+                .filter(|(key, seen_ms)| {
+                    cache
+                        .entries
+                        .get(key)
+                        .is_some_and(|entry| entry.durable && entry.seen_ms == *seen_ms)
+                })
+                .map(|(key, seen_ms)| format!("{key:032x} {seen_ms}"))
+                .collect()
+        };
+        let result = (|| {
+            let path = &self.config.path;
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)?;
+            }
+            let tmp = path.with_extension("tmp");
+            let mut file = BufWriter::new(File::create(&tmp)?);
+            for line in &lines {
+                writeln!(file, "{line}")?;
+            }
+            file.into_inner()?.sync_all()?;
+            fs::rename(&tmp, path)
+        })();
+        if let Err(err) = result {
+            // Try again at the next checkpoint.
+            self.cache.lock().unwrap().dirty = true;
+            let err = format!("dedup state write failed: {err}");
+            let mut last_error = self.last_error.lock().unwrap();
+            if last_error.as_deref() != Some(err.as_str()) {
+                eprintln!("jetson-gateway: {err}");
+            }
+            *last_error = Some(err);
+        }
+    }
+}
+
+/// Drops MQTT records seen within `[dedup].window_secs`, such as QoS 1
+/// redeliveries and publisher retries. Keys are remembered across restarts
+/// in the state file. Without `[dedup]` every record is admitted.
+pub struct Dedup {
+    shared: Option<Arc<Shared>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Dedup {
+    /// Loads the state file and starts writing it every
+    /// `checkpoint_interval_ms`.
+    pub fn start(config: Option<&DedupConfig>) -> Result<Dedup, GatewayError> {
+        let Some(config) = config else {
+            return Ok(Dedup {
+                shared: None,
+                task: Mutex::new(None),
+            });
+        };
+        let shared = Arc::new(Shared {
+            config: config.clone(),
+            cache: Mutex::new(load(&config.path)?),
+            duplicates: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+        });
+        shared
+            .cache
+            .lock()
+            .unwrap()
+            .expire(now_ms(), shared.window_ms(), config.max_entries);
+        let interval = Duration::from_millis(config.checkpoint_interval_ms.max(1));
+        let task = tokio::spawn({
+            let shared = Arc::clone(&shared);
+            async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    let shared = Arc::clone(&shared);
+                    let _ = tokio::task::spawn_blocking(move || shared.save()).await;
+                }
+            }
+        });
+        Ok(Dedup {
+            shared: Some(shared),
+            task: Mutex::new(Some(task)),
+        })
+    }
+
+    /// The record's key: its value at `[dedup].key` when configured and
+    /// present, else a hash of the topic and the record's bytes. `None`
+    /// when dedup is off.
+    pub fn key(&self, topic: &str, body: &[u8], value: Option<&Value>) -> Option<u128> {
+        let shared = self.shared.as_ref()?;
+        let mut hasher = Sha256::new();
+        let configured = shared
+            .config
+            .key
+            .as_deref()
+            .and_then(|pointer| value?.pointer(pointer));
+        match configured {
+            Some(key) => {
+                hasher.update(b"key\0");
+                hasher.update(key.to_string());
+            }
+            None => {
+                hasher.update(b"body\0");
+                hasher.update(topic);
+                hasher.update([0]);
+                hasher.update(body);
+            }
+        }
+        let digest = hasher.finalize();
+        Some(u128::from_be_bytes(
+            digest[..16].try_into().expect("digest has 32 bytes"),
+        ))
+    }
+
+    /// Remembers `key`, or counts a `Duplicate` if it was seen within the
+    /// window. The returned token stands in for `ack`: it marks the key
+    /// durable once released, and forgets it if delivery fails. A duplicate
+    /// of an event still in flight holds its `ack` until the original's
+    /// outcome is known and then shares it, so a failed original gets the
+    /// duplicate redelivered too.
+    pub fn admit(
+        &self,
+        key: Option<u128>,
+        ack: Option<AckToken>,
+    ) -> Result<Option<AckToken>, Duplicate> {
+        let (Some(shared), Some(key)) = (&self.shared, key) else {
+            return Ok(ack);
+        };
+        let now = now_ms();
+        {
+            let mut cache = shared.cache.lock().unwrap();
+            cache.expire(now, shared.window_ms(), shared.config.max_entries);
+            if let Some(entry) = cache.entries.get(&key) {
+                if let (Some(waiting), Some(ack)) = (&entry.waiting, ack) {
+                    waiting.lock().unwrap().push(ack);
+                }
+                shared.duplicates.fetch_add(1, Ordering::Relaxed);
+                return Err(Duplicate);
+            }
+            // Without manual acks nothing waits for durability.
+            let waiting = ack.as_ref().map(|_| Waiting::default());
+            cache.insert(key, now, ack.is_none(), waiting.clone());
+            let Some(ack) = ack else { return Ok(None) };
+            let waiting = waiting.expect("set with the ack");
+            let shared = Arc::clone(shared);
+            Ok(Some(ack.chain(move |result| {
+                let duplicates = {
+                    let mut cache = shared.cache.lock().unwrap();
+                    match result {
+                        Ok(()) => {
+                            if let Some(entry) = cache.entries.get_mut(&key) {
+                                entry.durable = true;
+                                entry.waiting = None;
+                                cache.dirty = true;
+                            }
+                        }
+                        Err(_) => {
+                            cache.entries.remove(&key);
+                        }
+                    }
+                    // Taken under the cache lock, so no duplicate joins after.
+                    std::mem::take(&mut *waiting.lock().unwrap())
+                };
+                if let Err(reason) = result {
+                    for ack in &duplicates {
+                        ack.fail(reason);
+                    }
+                }
+            })))
+        }
+    }
+
+    /// `None` when dedup is off.
+    pub fn status(&self) -> Option<DedupStatus> {
+        let shared = self.shared.as_ref()?;
+        Some(DedupStatus {
+            entries: shared.cache.lock().unwrap().entries.len(),
+            duplicates: shared.duplicates.load(Ordering::Relaxed),
+            last_error: shared.last_error.lock().unwrap().clone(),
+        })
+    }
+
+    /// Stops the checkpoints and writes the state file a last time.
+    pub fn close(&self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        if let Some(shared) = &self.shared {
+            shared.save();
+        }
+    }
+}
+
+/// Reads a state file written by `save`. A missing file is an empty cache;
+/// unreadable lines are skipped.
+fn load(path: &Path) -> Result<Cache, GatewayError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Cache::default()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut cache = Cache::default();
+    let mut skipped = 0;
+    for line in text.lines() {
+        let mut parts = line.split(' ');
+        let entry = (|| {
+            let key = u128::from_str_radix(parts.next()?, 16).ok()?;
+            let seen_ms = parts.next()?.parse().ok()?;
+            Some((key, seen_ms))
+        })();
+        match entry {
+            Some((key, seen_ms)) => cache.insert(key, seen_ms, true, None),
+            None => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        eprintln!(
+            "jetson-gateway: skipped {skipped} unreadable lines of {}",
+            path.display()
+        );
+    }
+    cache.dirty = false;
+    Ok(cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ack::Released;
+    use tokio::sync::mpsc;
+
+    fn config(name: &str) -> DedupConfig {
+        let path = std::env::temp_dir().join(format!(
+            "jetson-gateway-dedup-{name}-{}.state",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        DedupConfig {
+            path,
+            ..DedupConfig::default()
+        }
+    }
+
+    #[test]
+    fn keys_expire_after_the_window() {
+        let mut cache = Cache::default();
+        cache.insert(1, 1_000, true, None);
+        cache.insert(2, 5_000, true, None);
+        cache.expire(10_999, 10_000, 10);
+        assert_eq!(cache.entries.len(), 2);
+        cache.expire(11_000, 10_000, 10);
+        assert!(!cache.entries.contains_key(&1));
+        assert!(cache.entries.contains_key(&2));
+    }
+
+    #[test]
+    fn oldest_keys_make_room_past_max_entries() {
+        let mut cache = Cache::default();
+        for key in 0..5 {
+            cache.insert(key, 1_000 + key as u64, true, None);
+        }
+        cache.expire(2_000, 10_000, 3);
+        let mut kept: Vec<_> = cache.entries.keys().copied().collect();
+        kept.sort_unstable();
+        assert_eq!(kept, [2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn durable_keys_survive_a_restart() {
+        let config = config("restart");
+        let dedup = Dedup::start(Some(&config)).unwrap();
+        let durable = dedup.key("site/a", b"one", None);
+        let (tx, _rx) = mpsc::unbounded_channel::<Released<u32>>();
+        let in_flight = dedup.key("site/a", b"two", None);
+        assert!(dedup.admit(durable, None).is_ok());
+        let _held = dedup.admit(in_flight, Some(AckToken::new(1, tx))).ok();
+        dedup.close();
+
+        let dedup = Dedup::start(Some(&config)).unwrap();
+        assert!(dedup.admit(durable, None).is_err());
+        // Not durable when the state was written, so let through again.
+        assert!(dedup.admit(in_flight, None).is_ok());
+        assert_eq!(dedup.status().unwrap().duplicates, 1);
+    }
+
+    #[tokio::test]
+    async fn duplicate_in_flight_shares_the_original_outcome() {
+        let dedup = Dedup::start(Some(&config("in-flight"))).unwrap();
+        let key = dedup.key("site/a", b"reading", None);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let original = dedup.admit(key, Some(AckToken::new("original", tx.clone())));
+        let original = original.ok().flatten().unwrap();
+        assert!(dedup
+            .admit(key, Some(AckToken::new("duplicate", tx.clone())))
+            .is_err());
+        assert!(
+            rx.try_recv().is_err(),
+            "duplicate acked before the original"
+        );
+
+        original.fail("sink down");
+        let mut released = vec![rx.try_recv().unwrap(), rx.try_recv().unwrap()];
+        released.sort_by_key(|(name, _)| *name);
+        assert_eq!(
+            released,
+            [
+                ("duplicate", Err("sink down".to_string())),
+                ("original", Err("sink down".to_string())),
+            ]
+        );
+
+        // Forgotten, so the redelivery gets through and, once durable,
+        // later copies are acked at once.
+        let redelivered = dedup.admit(key, Some(AckToken::new("redelivered", tx.clone())));
+        drop(redelivered.ok().flatten().unwrap());
+        assert_eq!(rx.try_recv().unwrap(), ("redelivered", Ok(())));
+        assert!(dedup
+            .admit(key, Some(AckToken::new("late", tx.clone())))
+            .is_err());
+        assert_eq!(rx.try_recv().unwrap(), ("late", Ok(())));
+    }
+}
@@ -0,0 +1,380 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::GatewayError;
+
+/// Category of events from DeepStream's message converter.
+pub const CATEGORY: &str = "deepstream";
+
+/// Object types `nvmsgconv` writes as a sub-object of `object` in the full
+/// schema, holding the type's attributes.
+const OBJECT_TYPES: &[&str] = &["vehicle", "person", "face", "bag", "bicycle", "roadsign"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Schema {
+    Minimal,
+    Full,
+}
+
+/// The full schema (`msg-conv-payload-type` 0): one object per message with
+/// sensor, place and analytics module metadata.
+#[derive(Debug, Deserialize)]
+struct FullMessage {
+    messageid: Option<String>,
+    mdsversion: Option<String>,
+    #[serde(rename = "@timestamp")]
+    timestamp: Option<String>,
+    place: Option<Place>,
+    sensor: Option<Sensor>,
+    #[serde(rename = "analyticsModule")]
+    analytics_module: Option<AnalyticsModule>,
+    object: Option<FullObject>,
+    event: Option<EventInfo>,
+    #[serde(rename = "videoPath")]
+    video_path: Option<String>,
+}
+
+/// The minimal schema (`msg-conv-payload-type` 1): every object of a frame
+/// as a `|`-separated string.
+#[derive(Debug, Deserialize)]
+struct MinimalMessage {
+    version: Option<String>,
+    id: Option<Value>,
+    #[serde(rename = "@timestamp")]
+    timestamp: Option<String>,
+    #[serde(rename = "sensorId")]
+    sensor_id: String,
+    #[serde(default)]
+    objects: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Sensor {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub sensor_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coordinate: Option<Coordinate>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Place {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub place_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnalyticsModule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub event_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Location {
+    pub lat: f64,
+    pub lon: f64,
+    #[serde(default)]
+    pub alt: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Coordinate {
+    pub x: f64,
+    pub y: f64,
+    #[serde(default)]
+    pub z: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct FullObject {
+    id: Option<String>,
+    confidence: Option<f64>,
+    bbox: Option<FullBbox>,
+    location: Option<Location>,
+    coordinate: Option<Coordinate>,
+    /// The type sub-object (`vehicle`, `person`, ...) among the rest.
+    #[serde(flatten)]
+    rest: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FullBbox {
+    topleftx: f64,
+    toplefty: f64,
+    bottomrightx: f64,
+    bottomrighty: f64,
+}
+
+/// Pixel box of a detection.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Bbox {
+    pub left: f64,
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectedObject {
+    /// Tracker id; `None` for untracked objects, which DeepStream writes
+    /// as `-1`.
+    pub tracking_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bbox: Option<Bbox>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coordinate: Option<Coordinate>,
+    /// The type's attributes: an object in the full schema, the strings
+    /// after `#` in the minimal one.
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub attributes: Value,
+}
+
+/// `fields` of a DeepStream event. Both schemas map onto it; what a schema
+/// does not carry is left out.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeepStreamFields {
+    pub schema: Schema,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// `@timestamp` as sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    pub sensor: Sensor,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub place: Option<Place>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub analytics_module: Option<AnalyticsModule>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<EventInfo>,
+    pub objects: Vec<DetectedObject>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_path: Option<String>,
+}
+
+impl DeepStreamFields {
+    pub fn device_id(&self) -> Option<&str> {
+        self.sensor.id.as_deref().filter(|id| !id.is_empty())
+    }
+
+    pub fn zone_id(&self) -> Option<&str> {
+        self.place
+            .as_ref()
+            .and_then(|place| place.id.as_deref())
+            .filter(|id| !id.is_empty())
+    }
+}
+
+/// Parses an `nvmsgconv` payload in either schema. The minimal schema is
+/// told apart by its `sensorId`.
+pub fn parse(value: Value) -> Result<DeepStreamFields, GatewayError> {
+    let Some(object) = value.as_object() else {
+        return Err(not_nvmsgconv());
+    };
+    if object.contains_key("sensorId") {
+        parse_minimal(serde_json::from_value(value)?)
+    } else if object.contains_key("sensor") || object.contains_key("object") {
+        Ok(parse_full(serde_json::from_value(value)?))
+    } else {
+        Err(not_nvmsgconv())
+    }
+}
+
+fn parse_full(message: FullMessage) -> DeepStreamFields {
+    let objects = message.object.map(full_object).into_iter().collect();
+    DeepStreamFields {
+        schema: Schema::Full,
+        message_id: message.messageid,
+        version: message.mdsversion,
+        timestamp: message.timestamp,
+        sensor: message.sensor.unwrap_or_default(),
+        place: message.place,
+        analytics_module: message.analytics_module,
+        event: message.event,
+        objects,
+        video_path: message.video_path.filter(|path| !path.is_empty()),
+    }
+}
+
+fn full_object(mut object: FullObject) -> DetectedObject {
+    let (label, attributes) = OBJECT_TYPES
+        .iter()
+        .find_map(|name| {
+            object
+                .rest
+                .remove(*name)
+                .map(|attributes| (Some(name.to_string()), attributes))
+        })
+        .unwrap_or((None, Value::Null));
+    DetectedObject {
+        tracking_id: object.id.as_deref().and_then(tracking_id),
+        label,
+        confidence: object.confidence,
+        bbox: object.bbox.map(|b| Bbox {
+            left: b.topleftx,
+            top: b.toplefty,
+            right: b.bottomrightx,
+            bottom: b.bottomrighty,
+        }),
+        location: object.location,
+        coordinate: object.coordinate,
+        attributes,
+    }
+}
+
+fn parse_minimal(message: MinimalMessage) -> Result<DeepStreamFields, GatewayError> {
+    let objects = message
+        .objects
+        .iter()
+        .map(|object| minimal_object(object))
+        .collect::<Result<_, _>>()?;
+    Ok(DeepStreamFields {
+        schema: Schema::Minimal,
+        message_id: message.id.map(|id| match id {
+            Value::String(id) => id,
+            other => other.to_string(),
+        }),
+        version: message.version,
+        timestamp: message.timestamp,
+        sensor: Sensor {
+            id: Some(message.sensor_id),
+            ..Sensor::default()
+        },
+        place: None,
+        analytics_module: None,
+        event: None,
+        objects,
+        video_path: None,
+    })
+}
+
+/// `id|left|top|right|bottom|label`, optionally followed by `|#|` and the
+/// type's attributes.
+fn minimal_object(text: &str) -> Result<DetectedObject, GatewayError> {
+    let (head, attributes) = match text.split_once("|#|") {
+        Some((head, attributes)) => (head, Some(attributes)),
+        None => (text.strip_suffix("|#").unwrap_or(text), None),
+    };
+    let parts: Vec<&str> = head.split('|').collect();
+    let [id, left, top, right, bottom, label] = parts[..] else {
+        return Err(GatewayError::Payload(format!(
+            "malformed nvmsgconv object {text:?}"
+        )));
+    };
+    let coord = |s: &str| {
+        s.trim().parse::<f64>().map_err(|_| {
+            GatewayError::Payload(format!("malformed bbox in nvmsgconv object {text:?}"))
+        })
+    };
+    Ok(DetectedObject {
+        tracking_id: tracking_id(id),
+        label: Some(label.to_string()).filter(|l| !l.is_empty()),
+        confidence: None,
+        bbox: Some(Bbox {
+            left: coord(left)?,
+            top: coord(top)?,
+            right: coord(right)?,
+            bottom: coord(bottom)?,
+        }),
+        location: None,
+        coordinate: None,
+        attributes: attributes.map_or(Value::Null, |a| {
+            Value::Array(a.split('|').map(|s| Value::String(s.to_string())).collect())
+        }),
+    })
+}
+
+/// Untracked objects carry `-1`, or the same as an unsigned 64-bit value.
+fn tracking_id(id: &str) -> Option<u64> {
+    id.trim().parse().ok().filter(|&id| id != u64::MAX)
+}
+
+fn not_nvmsgconv() -> GatewayError {
+    GatewayError::Payload("not an nvmsgconv message (no sensorId, sensor or object)".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Payloads in the layouts of NVIDIA's documented `nvmsgconv` samples.
+    const FIXTURES: &str = include_str!("../../../fixtures/deepstream/cases.json");
+
+    #[derive(Deserialize)]
+    struct Fixtures {
+        cases: Vec<Case>,
+    }
+
+    #[derive(Deserialize)]
+    struct Case {
+        name: String,
+        payload: Value,
+        #[serde(default)]
+        device_id: Option<String>,
+        #[serde(default)]
+        zone_id: Option<String>,
+        #[serde(default)]
+        fields: Option<Value>,
+        #[serde(default)]
+        error: Option<String>,
+    }
+
+    #[test]
+    fn nvmsgconv_payloads_parse_in_both_schemas() {
+        let fixtures: Fixtures = serde_json::from_str(FIXTURES).unwrap();
+        for case in fixtures.cases {
+            let name = &case.name;
+            match (parse(case.payload), case.error) {
+                (Ok(fields), None) => {
+                    assert_eq!(fields.device_id(), case.device_id.as_deref(), "{name}");
+                    assert_eq!(fields.zone_id(), case.zone_id.as_deref(), "{name}");
+                    assert_eq!(
+                        serde_json::to_value(&fields).unwrap(),
+                        case.fields.unwrap(),
+                        "fixture {name}"
+                    );
+                }
+                (Err(err), Some(expected)) => {
+                    assert!(err.to_string().contains(&expected), "{name}: {err}");
+                }
+                (Ok(fields), Some(_)) => panic!("{name}: parsed as {fields:?}"),
+                (Err(err), None) => panic!("{name}: {err}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+use crate::config::{EventIdConfig, IdFormat};
+use crate::VirtualObjectEvent;
+
+/// Bits after the 48-bit millisecond timestamp that belong to us: ULID
+/// keeps all 80, UUIDv7 loses 6 to its version and variant.
+const ULID_BITS: u32 = 80;
+const UUID_BITS: u32 = 74;
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Hands out event ids that sort by time. Within one millisecond, or when
+/// the clock steps back, the previous id's random part is incremented, so
+/// ids are unique and strictly increasing for the life of the process.
+pub struct EventIds {
+    config: EventIdConfig,
+    /// Millisecond and random part of the last id.
+    last: Mutex<(u64, u128)>,
+}
+
+impl EventIds {
+    pub fn new(config: &EventIdConfig) -> EventIds {
+        EventIds {
+            config: config.clone(),
+            last: Mutex::new((0, 0)),
+        }
+    }
+
+    /// The id for `event`. In deterministic mode it is derived from the
+    /// event's content and `origin`, where in the input it came from (such
+    /// as topic and record index), so a replayed message gets the id it had
+    /// before while identical records of one batch still get their own.
+    /// Without a source timestamp the time part is 0, so such ids sort
+    /// before the rest but stay the same across replays.
+    pub fn assign(&self, event: &VirtualObjectEvent, origin: &str) -> String {
+        let bits = match self.config.format {
+            IdFormat::Ulid => ULID_BITS,
+            IdFormat::UuidV7 => UUID_BITS,
+        };
+        let mask = (1u128 << bits) - 1;
+        let (ms, random) = if self.config.deterministic {
+            (
+                event.source_ts.unwrap_or(0),
+                content_hash(event, origin) & mask,
+            )
+        } else {
+            self.next(event.ingest_ts, mask)
+        };
+        let ms = u128::from(ms & 0xffff_ffff_ffff);
+        let id = match self.config.format {
+            IdFormat::Ulid => ulid((ms << ULID_BITS) | random),
+            IdFormat::UuidV7 => uuid_v7(ms, random),
+        };
+        format!("{}{id}", self.config.prefix)
+    }
+
+    fn next(&self, ms: u64, mask: u128) -> (u64, u128) {
+        let mut last = self.last.lock().unwrap();
+        let (last_ms, last_random) = *last;
+        *last = if ms > last_ms {
+            (ms, rand::thread_rng().gen::<u128>() & mask)
+        } else if last_random < mask {
+            (last_ms, last_random + 1)
+        } else {
+            // Random part used up: borrow the next millisecond.
+            (last_ms + 1, rand::thread_rng().gen::<u128>() & (mask >> 1))
+        };
+        *last
+    }
+}
+
+/// SHA-256 over what makes the event the event, not when it arrived.
+fn content_hash(event: &VirtualObjectEvent, origin: &str) -> u128 {
+    let content = serde_json::json!([
+        event.device_id,
+        event.zone_id,
+        event.category,
+        event.source_ts,
+        event.fields,
+        origin,
+    ]);
+    let digest = Sha256::digest(content.to_string().as_bytes());
+    u128::from_be_bytes(digest[..16].try_into().expect("digest has 32 bytes"))
+}
+
+/// 26 Crockford base32 characters, the first carrying 3 bits.
+fn ulid(value: u128) -> String {
+    (0..26)
+        .rev()
+        .map(|n| CROCKFORD[(value >> (n * 5)) as usize & 31] as char)
+        .collect()
+}
+
+fn uuid_v7(ms: u128, random: u128) -> String {
+    let rand_a = (random >> 62) & 0xfff;
+    let rand_b = random & ((1 << 62) - 1);
+    let value = (ms << 80) | (0x7 << 76) | (rand_a << 64) | (0b10 << 62) | rand_b;
+    let hex = format!("{value:032x}");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ids(format: IdFormat, deterministic: bool) -> EventIds {
+        EventIds::new(&EventIdConfig {
+            format,
+            deterministic,
+            prefix: String::new(),
+        })
+    }
+
+    fn event(source_ts: Option<u64>, ingest_ts: u64) -> VirtualObjectEvent {
+        serde_json::from_value(json!({
+            "event_id": "",
+            "ts_unix_ms": source_ts.unwrap_or(ingest_ts),
+            "source_ts": source_ts,
+            "ingest_ts": ingest_ts,
+            "device_id": "cam-1",
+            "zone_id": "dock",
+            "category": "detection",
+            "fields": { "count": 3 },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn ids_increase_within_one_millisecond() {
+        for format in [IdFormat::Ulid, IdFormat::UuidV7] {
+            let ids = ids(format, false);
+            let event = event(None, 1_700_000_000_000);
+            let mut previous = ids.assign(&event, "");
+            for _ in 0..1000 {
+                let id = ids.assign(&event, "");
+                assert!(id > previous, "{id} after {previous}");
+                previous = id;
+            }
+        }
+    }
+
+    #[test]
+    fn ids_keep_increasing_when_the_clock_steps_back() {
+        let ids = ids(IdFormat::Ulid, false);
+        let later = ids.assign(&event(None, 1_700_000_000_500), "");
+        let earlier = ids.assign(&event(None, 1_700_000_000_000), "");
+        assert!(earlier > later);
+    }
+
+    #[test]
+    fn uuid_v7_has_its_version_and_variant() {
+        let id = ids(IdFormat::UuidV7, false).assign(&event(None, 1_700_000_000_000), "");
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[..13], "018bcfe5-6800");
+        assert_eq!(&id[14..15], "7");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"), "{id}");
+    }
+
+    #[test]
+    fn deterministic_ids_follow_content_and_origin() {
+        for format in [IdFormat::Ulid, IdFormat::UuidV7] {
+            let ids = ids(format, true);
+            let event = event(Some(1_700_000_000_000), 1_700_000_000_250);
+            let id = ids.assign(&event, "site/cam-1");
+            assert_eq!(ids.assign(&event, "site/cam-1"), id);
+            let replayed = VirtualObjectEvent {
+                ingest_ts: 1_700_000_009_000,
+                ..event.clone()
+            };
+            assert_eq!(ids.assign(&replayed, "site/cam-1"), id);
+            assert_ne!(ids.assign(&event, "site/cam-1[1]"), id);
+        }
+    }
+
+    #[test]
+    fn deterministic_ids_without_a_source_timestamp_survive_replay() {
+        for format in [IdFormat::Ulid, IdFormat::UuidV7] {
+            let ids = ids(format, true);
+            let first = ids.assign(&event(None, 1_800_000_000_000), "t");
+            let replayed = ids.assign(&event(None, 1_800_000_009_000), "t");
+            assert_eq!(first, replayed);
+        }
+        let ids = ids(IdFormat::Ulid, true);
+        let with_source = ids.assign(&event(Some(1_700_000_000_000), 1_800_000_000_000), "t");
+        let without = ids.assign(&event(None, 1_800_000_000_000), "t");
+        // The first 10 characters carry the millisecond timestamp.
+        assert_eq!(
+            &with_source[..10],
+            &ulid(1_700_000_000_000u128 << ULID_BITS)[..10]
+        );
+        assert_eq!(&without[..10], "0000000000");
+    }
+}
@@ -0,0 +1,245 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Strings longer than this are cut in a value's preview.
+const PREVIEW_CHARS: usize = 64;
+
+/// Ids are unique for the life of the process, as `genId` makes them.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// What `VirtualObjectExcavator.excavate` returns. `dom_sheets` stays empty:
+/// events carry no DOM.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Excavation {
+    pub virtual_objects: Vec<VirtualObject>,
+    pub relationships: Vec<Relationship>,
+    pub dom_sheets: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualObject {
+    pub id: String,
+    /// `struct` for objects and arrays, `value` for the rest.
+    pub category: &'static str,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub value_type: &'static str,
+    /// An object's keys, in order, and the type of each value.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Map<String, Value>>,
+    /// How many of an array's sampled elements have each type, in the
+    /// order the types first appear.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub element_types: Option<Map<String, Value>>,
+    /// A scalar, with long strings cut; null for anything else.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_preview: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Relationship {
+    pub from: String,
+    pub to: String,
+    /// `field` or `element`.
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+}
+
+/// Walks JSON trees into virtual objects and the relationships between
+/// them, like `src/core/VirtualObjectExcavator.js`.
+pub struct Excavator {
+    max_depth: usize,
+    max_array_sample: usize,
+}
+
+impl Excavator {
+    pub fn new(max_depth: usize, max_array_sample: usize) -> Excavator {
+        Excavator {
+            max_depth,
+            max_array_sample,
+        }
+    }
+
+    /// Adds what `value` holds to `into`, rooted at path `#`.
+    pub fn excavate(&self, value: &Value, into: &mut Excavation) {
+        self.walk(value, gen_id(), "#".to_string(), 0, into);
+    }
+
+    fn walk(&self, value: &Value, id: String, path: String, depth: usize, out: &mut Excavation) {
+        // As in the JS, the relationship to a value past max_depth is still
+        // recorded; only the value itself is left out.
+        if depth > self.max_depth {
+            return;
+        }
+        match value {
+            Value::Object(object) => {
+                out.virtual_objects.push(VirtualObject {
+                    id: id.clone(),
+                    category: "struct",
+                    path: path.clone(),
+                    value_type: "object",
+                    fields: Some(
+                        object
+                            .iter()
+                            .map(|(key, value)| (key.clone(), type_of(value).into()))
+                            .collect(),
+                    ),
+                    element_types: None,
+                    value_preview: None,
+                });
+                for (key, value) in object {
+                    let child = gen_id();
+                    out.relationships.push(Relationship {
+                        from: id.clone(),
+                        to: child.clone(),
+                        kind: "field",
+                        name: Some(key.clone()),
+                        index: None,
+                    });
+                    self.walk(value, child, format!("{path}.{key}"), depth + 1, out);
+                }
+            }
+            Value::Array(items) => {
+                let sampled = &items[..items.len().min(self.max_array_sample)];
+                let mut element_types = Map::new();
+                for item in sampled {
+                    let count = element_types
+                        .entry(type_of(item))
+                        .or_insert(Value::from(0u64));
+                    *count = Value::from(count.as_u64().unwrap_or(0) + 1);
+                }
+                out.virtual_objects.push(VirtualObject {
+                    id: id.clone(),
+                    category: "struct",
+                    path: path.clone(),
+                    value_type: "array",
+                    fields: None,
+                    element_types: Some(element_types),
+                    value_preview: None,
+                });
+                for (index, item) in sampled.iter().enumerate() {
+                    let child = gen_id();
+                    out.relationships.push(Relationship {
+                        from: id.clone(),
+                        to: child.clone(),
+                        kind: "element",
+                        name: None,
+                        index: Some(index),
+                    });
+                    self.walk(item, child, format!("{path}[{index}]"), depth + 1, out);
+                }
+            }
+            _ => out.virtual_objects.push(VirtualObject {
+                id,
+                category: "value",
+                path,
+                value_type: type_of(value),
+                fields: None,
+                element_types: None,
+                value_preview: Some(preview(value)),
+            }),
+        }
+    }
+}
+
+/// `vo_` and a base 36 counter.
+fn gen_id() -> String {
+    format!("vo_{}", base36(NEXT_ID.fetch_add(1, Ordering::Relaxed) + 1))
+}
+
+fn base36(mut n: u64) -> String {
+    let mut digits = Vec::new();
+    while n > 0 {
+        digits.push(char::from_digit((n % 36) as u32, 36).expect("digit below 36"));
+        n /= 36;
+    }
+    digits.iter().rev().collect()
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn preview(value: &Value) -> Value {
+    match value {
+        Value::String(text) if text.chars().count() > PREVIEW_CHARS => {
+            let cut: String = text.chars().take(PREVIEW_CHARS - 3).collect();
+            Value::String(format!("{cut}..."))
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_) => value.clone(),
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    /// Generated by running `VirtualObjectExcavator` itself over the inputs.
+    const FIXTURES: &str = include_str!("../../../fixtures/excavator/cases.json");
+
+    #[derive(Deserialize)]
+    struct Fixtures {
+        cases: Vec<Case>,
+    }
+
+    #[derive(Deserialize)]
+    struct Case {
+        name: String,
+        max_depth: usize,
+        max_array_sample: usize,
+        input: Value,
+        expected: String,
+    }
+
+    /// Renumbers ids from `vo_1`, as a fresh JS module would.
+    fn renumber(excavation: &mut Excavation) {
+        let number = |id: &str| u64::from_str_radix(&id["vo_".len()..], 36).unwrap();
+        let Some(root) = excavation.virtual_objects.first() else {
+            return;
+        };
+        let offset = number(&root.id) - 1;
+        let rebase = |id: &mut String| {
+            let n = number(id) - offset;
+            *id = format!("vo_{}", base36(n));
+        };
+        for object in &mut excavation.virtual_objects {
+            rebase(&mut object.id);
+        }
+        for relationship in &mut excavation.relationships {
+            rebase(&mut relationship.from);
+            rebase(&mut relationship.to);
+        }
+    }
+
+    #[test]
+    fn excavations_match_the_js_excavator() {
+        let fixtures: Fixtures = serde_json::from_str(FIXTURES).unwrap();
+        for case in fixtures.cases {
+            let excavator = Excavator::new(case.max_depth, case.max_array_sample);
+            let mut excavation = Excavation::default();
+            excavator.excavate(&case.input, &mut excavation);
+            renumber(&mut excavation);
+            assert_eq!(
+                serde_json::to_string(&excavation).unwrap(),
+                case.expected,
+                "fixture {}",
+                case.name
+            );
+        }
+    }
+}
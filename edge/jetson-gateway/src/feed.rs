@@ -0,0 +1,812 @@
+use futures_util::StreamExt;
+use inotify::{EventStream, Inotify, WatchMask};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{self, File as StdFile};
+use std::io::{ErrorKind, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+
+use crate::ack::{AckToken, Released};
+use crate::config::{Config, FeedConfig, FeedKind, FeedStart};
+use crate::dead_letter::{DeadLetter, DeadLetters, Reason, Source};
+use crate::sink::Fanout;
+use crate::spool::file_safe;
+use crate::{GatewayError, Normalizer, RawAnalyticsEvent, VirtualObjectEvent};
+
+const CHECKPOINT_EXT: &str = "offset";
+const READ_CHUNK_BYTES: usize = 64 * 1024;
+
+/// How often a feed is checked without an inotify event, which covers a
+/// full inotify queue and directories inotify cannot watch.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A byte offset in one particular file. The inode tells a rotated file
+/// apart from its successor at the same path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position {
+    inode: u64,
+    offset: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineState {
+    InFlight,
+    Done,
+    Failed,
+}
+
+/// Lines handed to the sinks, in file order, and how far they are durable.
+struct Progress {
+    /// Line ends, and where each line stands.
+    pending: VecDeque<(Position, LineState)>,
+    /// Sequence number of the front of `pending`.
+    first_seq: u64,
+    /// Every line before this position is durable or was skipped.
+    committed: Option<Position>,
+    /// Lines handed on and not released yet.
+    in_flight: usize,
+    /// A line failed delivery. The checkpoint stays before it, so a restart
+    /// reads it again, and later lines are no longer tracked.
+    held: bool,
+}
+
+impl Progress {
+    fn push(&mut self, end: Position, done: bool) -> u64 {
+        let seq = self.first_seq + self.pending.len() as u64;
+        if !done {
+            self.in_flight += 1;
+        }
+        if self.held {
+            self.first_seq += 1;
+            return seq;
+        }
+        let state = if done {
+            LineState::Done
+        } else {
+            LineState::InFlight
+        };
+        self.pending.push_back((end, state));
+        self.advance();
+        seq
+    }
+
+    fn release(&mut self, seq: u64, delivered: bool) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        if let Some(index) = seq.checked_sub(self.first_seq) {
+            if let Some(entry) = self.pending.get_mut(index as usize) {
+                entry.1 = if delivered {
+                    LineState::Done
+                } else {
+                    LineState::Failed
+                };
+            }
+        }
+        self.advance();
+    }
+
+    fn advance(&mut self) {
+        while let Some(&(end, state)) = self.pending.front() {
+            match state {
+                LineState::InFlight => return,
+                LineState::Done => self.committed = Some(end),
+                LineState::Failed => self.held = true,
+            }
+            if self.held {
+                // Nothing past the failed line will be checkpointed.
+                self.first_seq += self.pending.len() as u64;
+                self.pending.clear();
+                return;
+            }
+            self.pending.pop_front();
+            self.first_seq += 1;
+        }
+    }
+}
+
+/// State of one feed shared between its reader, its checkpointer and the
+/// health report.
+struct FeedShared {
+    name: String,
+    path: PathBuf,
+    progress: Mutex<Progress>,
+    /// Signalled whenever lines are released, for a reader waiting on
+    /// `max_in_flight`.
+    released: Notify,
+    lines: AtomicU64,
+    skipped: AtomicU64,
+    failed: AtomicU64,
+    rotations: AtomicU64,
+    truncations: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl FeedShared {
+    /// Logs an error unless it repeats the previous one.
+    fn error(&self, msg: String) {
+        let mut last = self.last_error.lock().unwrap();
+        if last.as_deref() != Some(msg.as_str()) {
+            eprintln!("jetson-gateway: feed {}: {msg}", self.name);
+            *last = Some(msg);
+        }
+    }
+
+    fn status(&self) -> FeedStatus {
+        let progress = self.progress.lock().unwrap();
+        FeedStatus {
+            name: self.name.clone(),
+            path: self.path.display().to_string(),
+            lines: self.lines.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            rotations: self.rotations.load(Ordering::Relaxed),
+            truncations: self.truncations.load(Ordering::Relaxed),
+            in_flight: progress.in_flight as u64,
+            committed_offset: progress.committed.map(|p| p.offset),
+            held: progress.held,
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedStatus {
+    pub name: String,
+    pub path: String,
+    pub lines: u64,
+    /// Lines that were not valid JSON or longer than `max_line_bytes`.
+    pub skipped: u64,
+    /// Lines a sink failed to deliver.
+    pub failed: u64,
+    pub rotations: u64,
+    pub truncations: u64,
+    pub in_flight: u64,
+    pub committed_offset: Option<u64>,
+    /// The checkpoint is held before a failed line until a restart.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub held: bool,
+    pub last_error: Option<String>,
+}
+
+/// Read-only view of the feeds for the health report.
+#[derive(Clone)]
+pub struct FeedMonitor(Vec<Arc<FeedShared>>);
+
+impl FeedMonitor {
+    pub fn status(&self) -> Vec<FeedStatus> {
+        self.0.iter().map(|feed| feed.status()).collect()
+    }
+}
+
+/// The NDJSON files tailed for events.
+///
+/// Each feed has a reader task, which follows the file through rotation and
+/// truncation and hands complete lines to the sinks, and a checkpointer
+/// task. A line counts as delivered once its ack token is released, the
+/// same point at which an MQTT message would be acknowledged, and the
+/// checkpointer writes the position up to which all lines are delivered to
+/// `<checkpoint_dir>/<name>.offset`. A restart resumes from there, so lines
+/// are neither skipped nor, short of a crash, replayed; a line a sink failed
+/// to deliver holds the checkpoint before it, so the restart reads it again.
+/// Lines that are not JSON go to the dead-letter queue, with the feed's path
+/// as their topic, and count as delivered once their dead letter is kept;
+/// one that is not holds the checkpoint like a failed delivery.
+pub struct Feeds {
+    feeds: Vec<Feed>,
+    stop: watch::Sender<bool>,
+}
+
+struct Feed {
+    shared: Arc<FeedShared>,
+    reader: Option<JoinHandle<()>>,
+    checkpointer: JoinHandle<()>,
+}
+
+impl Feeds {
+    pub fn start(
+        config: &Config,
+        normalizer: &Arc<Normalizer>,
+        sinks: &Arc<Fanout>,
+        dead_letters: &Arc<DeadLetters>,
+    ) -> Result<Feeds, GatewayError> {
+        let (stop, stop_rx) = watch::channel(false);
+        let mut feeds = Vec::new();
+        for feed in &config.feeds {
+            let name = feed.name();
+            fs::create_dir_all(&feed.checkpoint_dir)?;
+            let checkpoint_path = feed
+                .checkpoint_dir
+                .join(format!("{}.{CHECKPOINT_EXT}", file_safe(&name)));
+            let checkpoint = match fs::read_to_string(&checkpoint_path) {
+                Ok(text) => parse_checkpoint(&text),
+                Err(err) if err.kind() == ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            let shared = Arc::new(FeedShared {
+                name,
+                path: feed.path.clone(),
+                progress: Mutex::new(Progress {
+                    pending: VecDeque::new(),
+                    first_seq: 0,
+                    committed: checkpoint,
+                    in_flight: 0,
+                    held: false,
+                }),
+                released: Notify::new(),
+                lines: AtomicU64::new(0),
+                skipped: AtomicU64::new(0),
+                failed: AtomicU64::new(0),
+                rotations: AtomicU64::new(0),
+                truncations: AtomicU64::new(0),
+                last_error: Mutex::new(None),
+            });
+            let (ack_tx, ack_rx) = mpsc::unbounded_channel();
+            let checkpointer = tokio::spawn(write_checkpoints(
+                Arc::clone(&shared),
+                checkpoint_path,
+                Duration::from_millis(feed.checkpoint_interval_ms.max(1)),
+                ack_rx,
+            ));
+            let reader = Reader {
+                shared: Arc::clone(&shared),
+                kind: feed.kind,
+                max_line_bytes: feed.max_line_bytes,
+                max_in_flight: feed.max_in_flight,
+                normalizer: Arc::clone(normalizer),
+                sinks: Arc::clone(sinks),
+                dead_letters: Arc::clone(dead_letters),
+                ack_tx,
+                stop: stop_rx.clone(),
+                file: None,
+                line: Vec::new(),
+                discarding: false,
+            };
+            let reader = tokio::spawn(reader.run(feed.clone(), checkpoint));
+            feeds.push(Feed {
+                shared,
+                reader: Some(reader),
+                checkpointer,
+            });
+        }
+        Ok(Feeds { feeds, stop })
+    }
+
+    pub fn monitor(&self) -> FeedMonitor {
+        FeedMonitor(self.feeds.iter().map(|f| Arc::clone(&f.shared)).collect())
+    }
+
+    /// Stops reading. Lines already handed to the sinks stay in flight.
+    pub async fn stop(&mut self) {
+        let _ = self.stop.send(true);
+        for feed in &mut self.feeds {
+            if let Some(reader) = feed.reader.take() {
+                let _ = reader.await;
+            }
+        }
+    }
+
+    /// Waits for the final checkpoints, which are written once the sinks
+    /// have released every line.
+    pub async fn finish(mut self) {
+        self.stop().await;
+        for feed in self.feeds {
+            let _ = feed.checkpointer.await;
+        }
+    }
+}
+
+/// Records released lines and rewrites the checkpoint file at most every
+/// `interval`, and once more when the last line has been released.
+async fn write_checkpoints(
+    shared: Arc<FeedShared>,
+    path: PathBuf,
+    interval: Duration,
+    mut acks: UnboundedReceiver<Released<u64>>,
+) {
+    let mut written = shared.progress.lock().unwrap().committed;
+    let mut ticker = tokio::time::interval(interval);
+    let mut open = true;
+    while open {
+        tokio::select! {
+            released = acks.recv() => match released {
+                Some((seq, result)) => {
+                    if let Err(reason) = &result {
+                        shared.failed.fetch_add(1, Ordering::Relaxed);
+                        shared.error(format!(
+                            "a line failed delivery ({reason}); checkpoint held until restart"
+                        ));
+                    }
+                    shared.progress.lock().unwrap().release(seq, result.is_ok());
+                    shared.released.notify_waiters();
+                    continue;
+                }
+                None => open = false,
+            },
+            _ = ticker.tick() => {}
+        }
+        let committed = shared.progress.lock().unwrap().committed;
+        if committed == written {
+            continue;
+        }
+        let Some(position) = committed else { continue };
+        match write_checkpoint(&path, position) {
+            Ok(()) => written = committed,
+            Err(err) => shared.error(format!("checkpoint write failed: {err}")),
+        }
+    }
+}
+
+/// The file being read and how far.
+struct OpenFile {
+    file: File,
+    inode: u64,
+    pos: u64,
+}
+
+/// Raised when a stop is requested while the reader waits for room.
+struct Stopped;
+
+struct Reader {
+    shared: Arc<FeedShared>,
+    kind: FeedKind,
+    max_line_bytes: usize,
+    max_in_flight: usize,
+    normalizer: Arc<Normalizer>,
+    sinks: Arc<Fanout>,
+    dead_letters: Arc<DeadLetters>,
+    ack_tx: UnboundedSender<Released<u64>>,
+    stop: watch::Receiver<bool>,
+    file: Option<OpenFile>,
+    /// The line read so far, without its newline.
+    line: Vec<u8>,
+    /// Inside a line longer than `max_line_bytes`, skipping to its end.
+    discarding: bool,
+}
+
+impl Reader {
+    async fn run(mut self, config: FeedConfig, checkpoint: Option<Position>) {
+        let mut events = match watch_dir(&config.path) {
+            Ok(events) => Some(events),
+            Err(err) => {
+                self.shared.error(format!(
+                    "inotify unavailable ({err}), polling every {}s",
+                    RESCAN_INTERVAL.as_secs()
+                ));
+                None
+            }
+        };
+        self.resume(checkpoint, config.start).await;
+        println!(
+            "jetson-gateway: feed {} tailing {}",
+            self.shared.name,
+            config.path.display()
+        );
+        let mut rescan = tokio::time::interval(RESCAN_INTERVAL);
+        while !*self.stop.borrow() {
+            if self.catch_up().await.is_err() {
+                break;
+            }
+            tokio::select! {
+                _ = self.stop.changed() => {}
+                _ = rescan.tick() => {}
+                event = next_event(&mut events) => {
+                    if event.is_none() {
+                        events = None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Opens the file at the checkpoint. A checkpoint in a file that has
+    /// since been rotated resumes in the rotated file when it is still in
+    /// the same directory.
+    async fn resume(&mut self, checkpoint: Option<Position>, start: FeedStart) {
+        let path = self.shared.path.clone();
+        let Some(checkpoint) = checkpoint else {
+            if !self.open(&path, 0).await || start == FeedStart::Beginning {
+                return;
+            }
+            let Some(file) = &mut self.file else { return };
+            match file.file.seek(SeekFrom::End(0)).await {
+                Ok(end) => {
+                    file.pos = end;
+                    // So a restart before the first line does not skip
+                    // what was appended in between.
+                    self.shared.progress.lock().unwrap().committed = Some(Position {
+                        inode: file.inode,
+                        offset: end,
+                    });
+                }
+                Err(err) => self.shared.error(format!("seek failed: {err}")),
+            }
+            return;
+        };
+        let current = fs::metadata(&path).ok().map(|meta| meta.ino());
+        if current == Some(checkpoint.inode) {
+            self.open(&path, checkpoint.offset).await;
+            return;
+        }
+        if let Some(rotated) = find_inode(&path, checkpoint.inode) {
+            println!(
+                "jetson-gateway: feed {}: resuming in rotated file {}",
+                self.shared.name,
+                rotated.display()
+            );
+            self.open(&rotated, checkpoint.offset).await;
+            return;
+        }
+        eprintln!(
+            "jetson-gateway: feed {}: checkpointed file is gone, reading {} from the start",
+            self.shared.name,
+            path.display()
+        );
+        self.open(&path, 0).await;
+    }
+
+    /// Opens `path` at `offset`, or at the start when the file is shorter.
+    /// Returns false when there is no such file (yet).
+    async fn open(&mut self, path: &Path, offset: u64) -> bool {
+        let mut file = match File::open(path).await {
+            Ok(file) => file,
+            Err(err) => {
+                if err.kind() != ErrorKind::NotFound {
+                    self.shared
+                        .error(format!("cannot open {}: {err}", path.display()));
+                }
+                return false;
+            }
+        };
+        let meta = match file.metadata().await {
+            Ok(meta) => meta,
+            Err(err) => {
+                self.shared
+                    .error(format!("cannot stat {}: {err}", path.display()));
+                return false;
+            }
+        };
+        let offset = if meta.len() < offset { 0 } else { offset };
+        if let Err(err) = file.seek(SeekFrom::Start(offset)).await {
+            self.shared.error(format!("seek failed: {err}"));
+            return false;
+        }
+        self.file = Some(OpenFile {
+            file,
+            inode: meta.ino(),
+            pos: offset,
+        });
+        self.line.clear();
+        self.discarding = false;
+        true
+    }
+
+    /// Reads everything new, following the path to a new file after a
+    /// rotation and back to the start after a truncation.
+    async fn catch_up(&mut self) -> Result<(), Stopped> {
+        loop {
+            if self.file.is_none() {
+                let path = self.shared.path.clone();
+                if !self.open(&path, 0).await {
+                    return Ok(());
+                }
+            }
+            self.read_available().await?;
+            let Some(file) = &self.file else {
+                continue;
+            };
+            let meta = match fs::metadata(&self.shared.path) {
+                Ok(meta) => meta,
+                // Moved away and not recreated yet; the writer may still
+                // append to the file we have open.
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(err) => {
+                    self.shared
+                        .error(format!("cannot stat {}: {err}", self.shared.path.display()));
+                    return Ok(());
+                }
+            };
+            if meta.ino() != file.inode {
+                // Rotated: finish the old file, including whatever the
+                // writer appended before it switched over.
+                self.read_available().await?;
+                self.end_of_file().await?;
+                self.file = None;
+                self.shared.rotations.fetch_add(1, Ordering::Relaxed);
+                println!(
+                    "jetson-gateway: feed {}: {} rotated",
+                    self.shared.name,
+                    self.shared.path.display()
+                );
+                continue;
+            }
+            if meta.len() < file.pos {
+                self.shared.truncations.fetch_add(1, Ordering::Relaxed);
+                eprintln!(
+                    "jetson-gateway: feed {}: {} truncated, reading from the start",
+                    self.shared.name,
+                    self.shared.path.display()
+                );
+                let path = self.shared.path.clone();
+                self.file = None;
+                self.open(&path, 0).await;
+                continue;
+            }
+            return Ok(());
+        }
+    }
+
+    /// Reads up to the end of the open file and hands on every complete
+    /// line. A trailing partial line is kept until its newline arrives.
+    async fn read_available(&mut self) -> Result<(), Stopped> {
+        let mut chunk = vec![0; READ_CHUNK_BYTES];
+        loop {
+            let Some(file) = &mut self.file else {
+                return Ok(());
+            };
+            let n = match file.file.read(&mut chunk).await {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(err) => {
+                    self.shared.error(format!("read failed: {err}"));
+                    return Ok(());
+                }
+            };
+            let (inode, start) = (file.inode, file.pos);
+            file.pos += n as u64;
+            let mut line_start = 0;
+            for (i, _) in chunk[..n].iter().enumerate().filter(|(_, &b)| b == b'\n') {
+                self.append(&chunk[line_start..i]);
+                let end = Position {
+                    inode,
+                    offset: start + i as u64 + 1,
+                };
+                self.end_line(end).await?;
+                line_start = i + 1;
+            }
+            self.append(&chunk[line_start..n]);
+        }
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        if self.discarding {
+            return;
+        }
+        if self.line.len() + bytes.len() > self.max_line_bytes {
+            self.discarding = true;
+            self.line.clear();
+            return;
+        }
+        self.line.extend_from_slice(bytes);
+    }
+
+    /// The writer has moved on, so a partial last line is all there is.
+    async fn end_of_file(&mut self) -> Result<(), Stopped> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if self.line.is_empty() && !self.discarding {
+            return Ok(());
+        }
+        let end = Position {
+            inode: file.inode,
+            offset: file.pos,
+        };
+        self.end_line(end).await
+    }
+
+    async fn end_line(&mut self, end: Position) -> Result<(), Stopped> {
+        let line = std::mem::take(&mut self.line);
+        let discarded = std::mem::replace(&mut self.discarding, false);
+        let parsed = if discarded {
+            self.shared.skipped.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+                "jetson-gateway: feed {}: skipped a line longer than {} bytes",
+                self.shared.name, self.max_line_bytes
+            );
+            Ok(None)
+        } else {
+            self.parse(&line, end)
+        };
+        let outcome = match parsed {
+            Ok(event) => event.map(Ok),
+            Err(err) => {
+                let topic = self.shared.path.display().to_string();
+                Some(Err(DeadLetter {
+                    source: Source::Feed,
+                    ..DeadLetter::new(topic, &line, Reason::from_error(&err), None)
+                }))
+            }
+        };
+        self.line = line;
+        self.line.clear();
+
+        let Some(outcome) = outcome else {
+            self.shared.progress.lock().unwrap().push(end, true);
+            return Ok(());
+        };
+        self.wait_for_room().await?;
+        let seq = self.shared.progress.lock().unwrap().push(end, false);
+        let ack = AckToken::new(seq, self.ack_tx.clone());
+        match outcome {
+            Ok(event) => {
+                self.shared.lines.fetch_add(1, Ordering::Relaxed);
+                self.normalizer.send(&self.sinks, event, Some(ack));
+            }
+            Err(letter) => self.dead_letters.send(letter, Some(ack)),
+        }
+        Ok(())
+    }
+
+    /// The event of the line ending at `end`; `None` for a blank line.
+    fn parse(
+        &self,
+        line: &[u8],
+        end: Position,
+    ) -> Result<Option<VirtualObjectEvent>, GatewayError> {
+        let text = String::from_utf8_lossy(line);
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(None);
+        }
+        match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(value) => {
+                let raw = RawAnalyticsEvent {
+                    device_id: String::new(),
+                    zone_id: String::new(),
+                    kind: self.kind.name().to_string(),
+                    payload: value,
+                    extra: serde_json::Map::new(),
+                };
+                let origin = format!("{}@{}", self.shared.name, end.offset);
+                Ok(Some(self.normalizer.event(raw, None, &origin)))
+            }
+            Err(err) => {
+                self.shared.skipped.fetch_add(1, Ordering::Relaxed);
+                eprintln!(
+                    "jetson-gateway: feed {}: JSON parse error: {err}",
+                    self.shared.name
+                );
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn wait_for_room(&mut self) -> Result<(), Stopped> {
+        loop {
+            let released = self.shared.released.notified();
+            if self.shared.progress.lock().unwrap().in_flight < self.max_in_flight {
+                return Ok(());
+            }
+            tokio::select! {
+                _ = released => {}
+                _ = self.stop.changed() => return Err(Stopped),
+            }
+        }
+    }
+}
+
+/// Watches the directory rather than the file, so creation, rotation and
+/// deletion are seen as well as appends.
+fn watch_dir(path: &Path) -> std::io::Result<EventStream<Vec<u8>>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let inotify = Inotify::init()?;
+    inotify.watches().add(
+        dir,
+        WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO,
+    )?;
+    inotify.into_event_stream(vec![0; 4096])
+}
+
+/// Waits for the next inotify event; `None` once the stream has ended.
+/// Without a stream it never returns.
+async fn next_event(events: &mut Option<EventStream<Vec<u8>>>) -> Option<()> {
+    match events {
+        Some(events) => events.next().await.and_then(|event| event.ok()).map(|_| ()),
+        None => std::future::pending().await,
+    }
+}
+
+/// Looks for the file with `inode` next to `path`, where logrotate leaves
+/// rotated files.
+fn find_inode(path: &Path, inode: u64) -> Option<PathBuf> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| {
+            entry
+                .metadata()
+                .is_ok_and(|meta| meta.is_file() && meta.ino() == inode)
+        })
+        .map(|entry| entry.path())
+}
+
+fn parse_checkpoint(text: &str) -> Option<Position> {
+    let mut parts = text.split_whitespace().map(|p| p.parse::<u64>());
+    Some(Position {
+        inode: parts.next()?.ok()?,
+        offset: parts.next()?.ok()?,
+    })
+}
+
+/// Replaces the checkpoint file atomically so a crash leaves either the old
+/// or the new position.
+fn write_checkpoint(path: &Path, position: Position) -> Result<(), GatewayError> {
+    let tmp = path.with_extension("tmp");
+    let mut file = StdFile::create(&tmp)?;
+    writeln!(file, "{} {}", position.inode, position.offset)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress() -> Progress {
+        Progress {
+            pending: VecDeque::new(),
+            first_seq: 0,
+            committed: None,
+            in_flight: 0,
+            held: false,
+        }
+    }
+
+    fn at(offset: u64) -> Position {
+        Position { inode: 1, offset }
+    }
+
+    #[test]
+    fn checkpoint_follows_released_lines_in_order() {
+        let mut progress = progress();
+        let first = progress.push(at(10), false);
+        let second = progress.push(at(20), false);
+        progress.push(at(25), true);
+        progress.release(second, true);
+        assert_eq!(progress.committed, None);
+        progress.release(first, true);
+        assert_eq!(progress.committed, Some(at(25)));
+        assert_eq!(progress.in_flight, 0);
+    }
+
+    #[test]
+    fn failed_line_holds_the_checkpoint() {
+        let mut progress = progress();
+        let first = progress.push(at(10), false);
+        let failed = progress.push(at(20), false);
+        let third = progress.push(at(30), false);
+        progress.release(failed, false);
+        progress.release(first, true);
+        progress.release(third, true);
+        assert_eq!(progress.committed, Some(at(10)));
+        assert!(progress.held);
+
+        let fourth = progress.push(at(40), false);
+        assert_eq!(progress.in_flight, 1);
+        progress.release(fourth, true);
+        assert_eq!(progress.committed, Some(at(10)));
+        assert_eq!(progress.in_flight, 0);
+        assert!(progress.pending.is_empty());
+    }
+}
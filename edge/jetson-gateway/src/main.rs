@@ -1,15 +1,90 @@
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
+mod ack;
+mod clock;
+mod codec;
+mod config;
+mod dead_letter;
+mod dedup;
+mod deepstream;
+mod event_id;
+mod excavator;
+mod feed;
+mod mapping;
+mod mqtt;
+mod schema;
+mod schema_drift;
+mod sink;
+mod spool;
+mod streamguard;
+mod supervisor;
+mod tls;
+mod topic_pattern;
+mod topic_template;
+
+use ack::AckToken;
+use clap::Parser;
+use clock::{ClockStatus, Clocks};
+use codec::Codecs;
+use config::{Cli, Command, Config, Defaults, FeedKind, PayloadProfile};
+use dead_letter::{DeadLetter, DeadLetterMonitor, DeadLetterStatus, DeadLetters, Reason};
+use dedup::{Dedup, DedupStatus};
+use event_id::EventIds;
+use feed::{FeedMonitor, FeedStatus, Feeds};
+use mapping::Mapping;
+use mqtt::{Client, Event, EventLoop, MessageProperties, Publish};
+use schema::{SchemaCounts, Schemas};
+use schema_drift::{SchemaDrift, SchemaDriftStatus};
 use serde::{Deserialize, Serialize};
+use sink::{Fanout, HealthReport};
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use supervisor::{ConnectionStatus, Supervisor};
+use topic_pattern::{TopicCheckStatus, TopicChecks};
 use thiserror::Error;
-use tokio::time::sleep;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
 
 #[derive(Debug, Error)]
 enum GatewayError {
     #[error("mqtt error: {0}")]
-    Mqtt(#[from] rumqttc::ConnectionError),
+    Mqtt(Box<rumqttc::ConnectionError>),
+    #[error("mqtt error: {0}")]
+    MqttV5(Box<rumqttc::v5::ConnectionError>),
+    #[error("mqtt client error: {0}")]
+    MqttClient(String),
     #[error("serde error: {0}")]
     Serde(#[from] serde_json::Error),
+    #[error("config error: {0}")]
+    Config(String),
+    #[error("sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("sink error: {0}")]
+    Sink(String),
+    #[error("spool error: {0}")]
+    Spool(String),
+    #[error("delivery failed, reconnecting for redelivery: {0}")]
+    Delivery(String),
+    #[error("subscribe to {0} failed: {1}")]
+    Subscribe(String, String),
+    #[error("tls error: {0}")]
+    Tls(String),
+    #[error("payload error: {0}")]
+    Payload(String),
+    #[error("payload is not UTF-8: {0}")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("decode error: {0}")]
+    Decode(String),
+}
+
+impl From<rumqttc::ConnectionError> for GatewayError {
+    fn from(err: rumqttc::ConnectionError) -> Self {
+        GatewayError::Mqtt(Box::new(err))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,16 +97,39 @@ struct RawAnalyticsEvent {
     kind: String,
     #[serde(default)]
     payload: serde_json::Value,
+    /// Whatever else the envelope carries, such as its timestamp.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct VirtualObjectEvent {
     event_id: String,
+    /// When it happened: the source timestamp if the event has one, else
+    /// the ingest time.
     ts_unix_ms: u64,
+    /// Timestamp the device sent, in ms since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_ts: Option<u64>,
+    /// When the gateway received it, in ms since the epoch.
+    #[serde(default)]
+    ingest_ts: u64,
+    /// The device clock is off by more than `timestamps.max_skew_ms`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    clock_skewed: bool,
     device_id: String,
     zone_id: String,
     category: String,
     fields: serde_json::Value,
+    /// Where `fields` breaks its category's schema.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    schema_violations: Vec<String>,
+    /// Goes only to the quarantine sinks.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    quarantined: bool,
+    /// MQTT v5 properties of the message the event came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mqtt: Option<MessageProperties>,
 }
 
 fn now_ms() -> u64 {
@@ -41,78 +139,643 @@ fn now_ms() -> u64 {
         .as_millis() as u64
 }
 
-fn normalize_event(raw: RawAnalyticsEvent) -> VirtualObjectEvent {
+/// Turns raw records into events, holding what that needs across records.
+/// Shared by the subscriber and the feeds.
+struct Normalizer {
+    defaults: Defaults,
+    topics: TopicChecks,
+    clocks: Clocks,
+    ids: EventIds,
+    dedup: Dedup,
+    schemas: Schemas,
+    drift: SchemaDrift,
+}
+
+impl Normalizer {
+    fn new(config: &Config, schemas: Schemas) -> Result<Normalizer, GatewayError> {
+        Ok(Normalizer {
+            defaults: config.defaults.clone(),
+            topics: TopicChecks::new(config),
+            clocks: Clocks::new(&config.timestamps),
+            ids: EventIds::new(&config.event_ids),
+            dedup: Dedup::start(config.dedup.as_ref())?,
+            schemas,
+            drift: SchemaDrift::start(config.schema_drift.as_ref())?,
+        })
+    }
+
+    /// `origin` tells records with the same content apart for deterministic
+    /// ids; see `EventIds::assign`.
+    fn event(&self, raw: RawAnalyticsEvent, properties: Option<MessageProperties>, origin: &str) -> VirtualObjectEvent {
+        let source_ts = self.clocks.source_ts(&raw);
+        let mut event = normalize_event(raw, properties, &self.defaults);
+        if let Some(source_ts) = source_ts {
+            event.clock_skewed = self.clocks.observe(&event.device_id, source_ts, event.ingest_ts);
+            event.ts_unix_ms = source_ts;
+            event.source_ts = Some(source_ts);
+        }
+        self.schemas.check(&mut event);
+        event.event_id = self.ids.assign(&event, origin);
+        event
+    }
+
+    /// Hands the event to the sinks, followed by a `schema-drift` event for
+    /// each change it makes to its stream's inferred schema.
+    fn send(&self, sinks: &Fanout, event: VirtualObjectEvent, ack: Option<AckToken>) {
+        let drifts: Vec<_> = self
+            .drift
+            .observe(&event)
+            .into_iter()
+            .map(|drift| drift.event(&event))
+            .collect();
+        let origin = event.event_id.clone();
+        sinks.send(event, ack);
+        for mut drift in drifts {
+            drift.event_id = self.ids.assign(&drift, &origin);
+            sinks.send(drift, None);
+        }
+    }
+}
+
+fn normalize_event(
+    raw: RawAnalyticsEvent,
+    properties: Option<MessageProperties>,
+    defaults: &Defaults,
+) -> VirtualObjectEvent {
     let ts = now_ms();
-    let event_id = format!(
-        "voevt_{}_{}",
-        ts,
-        rand_fragment()
-    );
-    let category = if raw.kind.is_empty() {
-        "analytics".to_string()
-    } else {
-        raw.kind
+    // Vision and audio records are normalized the way StreamGuard does it;
+    // ids on the envelope only fill in what the record leaves out.
+    let (device_id, zone_id, category, fields) = match FeedKind::from_name(&raw.kind) {
+        Some(kind) => {
+            let record = streamguard::normalize(kind, raw.payload);
+            let category = record.category().to_string();
+            let fields = serde_json::to_value(record.fields).expect("fields are plain JSON");
+            (
+                record.device_id.unwrap_or(raw.device_id),
+                record.zone_id.unwrap_or(raw.zone_id),
+                category,
+                fields,
+            )
+        }
+        None => {
+            let category = if raw.kind.is_empty() {
+                defaults.category.clone()
+            } else {
+                raw.kind
+            };
+            (raw.device_id, raw.zone_id, category, raw.payload)
+        }
     };
+    // v5 publishers may put the ids in user properties instead.
+    let property = |value: String, key: &str| match &properties {
+        Some(props) if value.is_empty() => props.user_property(key).unwrap_or_default().to_string(),
+        _ => value,
+    };
+    let device_id = property(device_id, "device_id");
+    let zone_id = property(zone_id, "zone_id");
     VirtualObjectEvent {
-        event_id,
+        // Assigned by the Normalizer once the event is complete.
+        event_id: String::new(),
         ts_unix_ms: ts,
-        device_id: raw.device_id,
-        zone_id: raw.zone_id,
+        source_ts: None,
+        ingest_ts: ts,
+        clock_skewed: false,
+        device_id: or_default(device_id, &defaults.device_id),
+        zone_id: or_default(zone_id, &defaults.zone_id),
         category,
-        fields: raw.payload,
+        fields,
+        schema_violations: Vec::new(),
+        quarantined: false,
+        mqtt: properties,
     }
 }
 
-fn rand_fragment() -> String {
-    // Simple non-cryptographic fragment for IDs
-    let n = now_ms() ^ 0x5f37_9bcd;
-    format!("{:x}", n & 0xfffff)
+fn parse_record(
+    value: serde_json::Value,
+    profile: PayloadProfile,
+    mapping: Option<&Mapping>,
+) -> Result<RawAnalyticsEvent, GatewayError> {
+    match profile {
+        PayloadProfile::Analytics => match mapping {
+            Some(mapping) => Ok(mapping.apply(value)),
+            None => Ok(serde_json::from_value(value)?),
+        },
+        PayloadProfile::DeepStream => {
+            let fields = deepstream::parse(value)?;
+            // Goes through normalize_event for the id fallbacks.
+            Ok(RawAnalyticsEvent {
+                device_id: fields.device_id().unwrap_or_default().to_string(),
+                zone_id: fields.zone_id().unwrap_or_default().to_string(),
+                kind: deepstream::CATEGORY.to_string(),
+                payload: serde_json::to_value(fields)?,
+                extra: serde_json::Map::new(),
+            })
+        }
+    }
 }
 
-async fn run_gateway() -> Result<(), GatewayError> {
-    let mut mqttoptions = MqttOptions::new("jetson-gateway", "localhost", 1883);
-    mqttoptions.set_keep_alive(Duration::from_secs(30));
+/// Logs why a message, or a record of a batch, was rejected and hands it to
+/// the dead-letter queue. Redelivery would fail the same way; the token
+/// acks the message once its dead letter is kept, and fails if it is not.
+fn reject(
+    dead_letters: &DeadLetters,
+    err: GatewayError,
+    mut letter: DeadLetter,
+    record: Option<usize>,
+    ack: Option<AckToken>,
+) {
+    let at = match record {
+        Some(index) => format!("record {index} on {}: ", letter.topic),
+        None => String::new(),
+    };
+    match &err {
+        GatewayError::Serde(err) => eprintln!("jetson-gateway: {at}JSON parse error: {err}"),
+        GatewayError::Utf8(err) => {
+            eprintln!("jetson-gateway: non-UTF-8 payload on {}: {err}", letter.topic);
+        }
+        err => eprintln!("jetson-gateway: {at}{err}"),
+    }
+    letter.record = record;
+    dead_letters.send(letter, ack);
+}
 
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+fn or_default(value: String, default: &Option<String>) -> String {
+    match default {
+        Some(d) if value.is_empty() => d.clone(),
+        _ => value,
+    }
+}
 
-    // Subscribe to generic analytics topics; these can be refined per deployment.
-    client
-        .subscribe("analytics/+/events", QoS::AtLeastOnce)
-        .await
-        .unwrap();
+/// SIGINT and SIGTERM, either of which starts a graceful shutdown.
+struct Signals {
+    interrupt: Signal,
+    terminate: Signal,
+}
 
-    println!("jetson-gateway: subscribed to analytics/+/events");
+impl Signals {
+    fn new() -> std::io::Result<Signals> {
+        Ok(Signals {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
 
-    loop {
-        let event = eventloop.poll().await?;
-        if let Event::Incoming(Incoming::Publish(p)) = event {
-            if let Ok(text) = String::from_utf8(p.payload.to_vec()) {
-                match serde_json::from_str::<RawAnalyticsEvent>(&text) {
-                    Ok(raw) => {
-                        let voevt = normalize_event(raw);
-                        let out = serde_json::to_string(&voevt)?;
-                        // For now, print to stdout; later, forward to a local
-                        // Javaspectre ingestion socket or file.
-                        println!("{}", out);
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+        }
+    }
+}
+
+/// The subscriber connection, handed back by `run_gateway` on shutdown so
+/// acks released while the sinks drain still reach the broker.
+struct Connection {
+    client: Client,
+    eventloop: EventLoop,
+    acks: JoinHandle<String>,
+    ack_tx: mpsc::UnboundedSender<ack::Released<Publish>>,
+}
+
+/// Runs the subscriber until the connection fails or a signal arrives. On a
+/// signal it stops handing new messages to the sinks and returns the still
+/// open connection; with a clean session it unsubscribes first and takes
+/// whatever the broker delivers before the UNSUBACK.
+async fn run_gateway(
+    config: &Config,
+    codecs: &Codecs,
+    normalizer: &Normalizer,
+    sinks: &Fanout,
+    dead_letters: &DeadLetters,
+    signals: &mut Signals,
+    supervisor: &Supervisor,
+) -> Result<Connection, GatewayError> {
+    supervisor.connecting();
+    let broker = &config.broker;
+    let (client, mut eventloop) = mqtt::connect(broker, &broker.client_id, true)?;
+
+    // Filters awaiting their SUBACK, which arrive in request order.
+    let mut subscribing = VecDeque::new();
+    for sub in &config.subscriptions {
+        client
+            .subscribe(&sub.topic, sub.qos())
+            .await
+            .map_err(|err| GatewayError::Subscribe(sub.topic.clone(), err.to_string()))?;
+        subscribing.push_back(sub.topic.as_str());
+    }
+
+    // With manual acks, a failed delivery ends the connection; reconnecting
+    // makes the broker resend everything left unacknowledged in the session.
+    let (ack_tx, ack_rx) = mpsc::unbounded_channel();
+    let mut acks = tokio::spawn(ack::send_acks(client.clone(), ack_rx));
+    // UNSUBACKs still outstanding once shutting down.
+    let mut unsubscribing: Option<usize> = None;
+    let result = loop {
+        if unsubscribing == Some(0) {
+            break Ok(());
+        }
+        let event = tokio::select! {
+            event = eventloop.poll() => event,
+            reason = &mut acks => {
+                break Err(GatewayError::Delivery(reason.unwrap_or_else(|err| err.to_string())));
+            }
+            name = signals.recv(), if unsubscribing.is_none() => {
+                println!("jetson-gateway: {name} received, shutting down");
+                // A persistent session keeps its subscriptions so the broker
+                // queues messages until we are back.
+                let mut pending = 0;
+                if broker.clean_session {
+                    for sub in &config.subscriptions {
+                        if client.try_unsubscribe(&sub.topic).is_ok() {
+                            pending += 1;
+                        }
                     }
+                }
+                unsubscribing = Some(pending);
+                continue;
+            }
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => break Err(err),
+        };
+        match event {
+            Event::Publish(p) => {
+                if unsubscribing.is_some() && !broker.clean_session {
+                    // Not acked, so the broker redelivers it next session.
+                    continue;
+                }
+                let ack = (broker.manual_acks && p.needs_ack())
+                    .then(|| AckToken::new((*p).clone(), ack_tx.clone()));
+                let topic = p.topic();
+                let sub = config.subscription_for(&topic);
+                let properties = p.properties();
+                let letter = |body: &[u8], err: &GatewayError| {
+                    let reason = Reason::from_error(err);
+                    DeadLetter::new(topic.to_string(), body, reason, properties.clone())
+                };
+                let records = match codecs.decode(p.payload(), sub, properties.as_ref()) {
+                    Ok(records) => records,
                     Err(err) => {
-                        eprintln!("jetson-gateway: JSON parse error: {err}");
+                        let letter = letter(p.payload(), &err);
+                        reject(dead_letters, err, letter, None, ack);
+                        continue;
+                    }
+                };
+                // Every record holds the message's ack until it is durable.
+                let profile = sub.map(|sub| sub.profile).unwrap_or_default();
+                let pattern = sub.and_then(|sub| sub.topic_pattern.as_ref());
+                let mapping = sub.and_then(|sub| sub.mapping.as_ref());
+                for record in records {
+                    let body = record.body.as_deref().unwrap_or(p.payload());
+                    let key = normalizer.dedup.key(&topic, body, record.value.as_ref().ok());
+                    let raw = record
+                        .value
+                        .and_then(|value| parse_record(value, profile, mapping));
+                    match raw {
+                        Ok(mut raw) => {
+                            // Duplicates stop here, before they count toward
+                            // clocks, schemas or drift; dedup holds their
+                            // share of the ack while the original is in flight.
+                            let Ok(ack) = normalizer.dedup.admit(key, ack.clone()) else {
+                                continue;
+                            };
+                            // Payload first, then the topic, then v5 user properties and defaults.
+                            if let Some(pattern) = pattern {
+                                normalizer.topics.apply(pattern, &topic, &mut raw);
+                            }
+                            let origin = match record.index {
+                                Some(index) => format!("{topic}[{index}]"),
+                                None => topic.to_string(),
+                            };
+                            let voevt = normalizer.event(raw, properties.clone(), &origin);
+                            normalizer.send(sinks, voevt, ack);
+                        }
+                        Err(err) => {
+                            let letter = letter(body, &err);
+                            reject(dead_letters, err, letter, record.index, ack.clone());
+                        }
                     }
                 }
             }
+            Event::ConnAck => supervisor.connected(),
+            Event::SubAck { rejected } => {
+                let topic = subscribing.pop_front().unwrap_or_default();
+                if rejected {
+                    break Err(GatewayError::Subscribe(
+                        topic.to_string(),
+                        "rejected by the broker".to_string(),
+                    ));
+                }
+                println!("jetson-gateway: subscribed to {topic}");
+            }
+            Event::UnsubAck => {
+                if let Some(pending) = unsubscribing.as_mut() {
+                    *pending = pending.saturating_sub(1);
+                }
+            }
+            _ => {}
+        }
+    };
+    match result {
+        Ok(()) => Ok(Connection {
+            client,
+            eventloop,
+            acks,
+            ack_tx,
+        }),
+        Err(err) => {
+            acks.abort();
+            Err(err)
+        }
+    }
+}
+
+/// Closes the sinks and, while still connected, keeps polling so the acks
+/// they release are sent, then disconnects from the broker. Returns whether
+/// every sink wrote out what it held.
+async fn drain(sinks: Fanout, connection: Option<Connection>) -> bool {
+    let Some(Connection {
+        client,
+        mut eventloop,
+        acks,
+        ack_tx,
+    }) = connection
+    else {
+        return sinks.close().await;
+    };
+    drop(ack_tx);
+    let stop_acks = acks.abort_handle();
+    let close = async {
+        let drained = sinks.close().await;
+        // Ends once every ack is sent, or at the first failed delivery.
+        let _ = acks.await;
+        drained
+    };
+    tokio::pin!(close);
+    let mut connected = true;
+    let drained = loop {
+        tokio::select! {
+            drained = &mut close => break drained,
+            event = eventloop.poll(), if connected => {
+                if let Err(err) = event {
+                    eprintln!("jetson-gateway: connection lost while draining: {err}");
+                    connected = false;
+                    stop_acks.abort();
+                }
+            }
+        }
+    };
+    if connected && client.try_disconnect().is_ok() {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Disconnected) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    }
+    drained
+}
+
+#[derive(Debug, Serialize)]
+struct GatewayHealth {
+    /// None when nothing is subscribed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection: Option<ConnectionStatus>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    feeds: Vec<FeedStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dead_letters: Option<DeadLetterStatus>,
+    /// None when no subscription has a topic_pattern.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic_mismatches: Option<TopicCheckStatus>,
+    /// None when source timestamps are off.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock_skew: Option<ClockStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dedup: Option<DedupStatus>,
+    /// Passed and failed events per category schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schemas: Option<BTreeMap<String, SchemaCounts>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schema_drift: Option<SchemaDriftStatus>,
+    #[serde(flatten)]
+    sinks: HealthReport,
+}
+
+async fn log_health(
+    sinks: Arc<Fanout>,
+    supervisor: Option<Arc<Supervisor>>,
+    feeds: FeedMonitor,
+    dead_letters: DeadLetterMonitor,
+    normalizer: Arc<Normalizer>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let health = GatewayHealth {
+            connection: supervisor.as_ref().map(|supervisor| supervisor.status()),
+            feeds: feeds.status(),
+            dead_letters: dead_letters.status(),
+            topic_mismatches: normalizer.topics.status(),
+            clock_skew: normalizer.clocks.status(),
+            dedup: normalizer.dedup.status(),
+            schemas: normalizer.schemas.status(),
+            schema_drift: normalizer.drift.status(),
+            sinks: sinks.status(),
+        };
+        match serde_json::to_string(&health) {
+            Ok(status) => println!("jetson-gateway: health {status}"),
+            Err(err) => eprintln!("jetson-gateway: health report failed: {err}"),
+        }
+    }
+}
+
+/// `replay-dlq`: exits 0 once every dead letter was republished and
+/// acknowledged with a subscription to take it, 3 if some were not (feed
+/// lines never are), 1 when the replay could not run.
+async fn replay_dlq(config: &Config, files: Vec<PathBuf>) -> i32 {
+    let files = match (files.is_empty(), &config.dead_letter) {
+        (false, _) => files,
+        (true, Some(dead_letter)) if dead_letter.path.is_some() => dead_letter.files(),
+        (true, _) => {
+            eprintln!("jetson-gateway: replay-dlq needs files, or dead_letter.path to read");
+            return 1;
+        }
+    };
+    match dead_letter::replay(config, &files).await {
+        Ok(summary) => {
+            println!(
+                "jetson-gateway: replayed {} dead letters ({} unreadable, {} unacknowledged, {} with no subscriber, {} feed lines skipped)",
+                summary.published,
+                summary.unreadable,
+                summary.unacked,
+                summary.unrouted,
+                summary.feed
+            );
+            if summary.unreadable > 0
+                || summary.unacked > 0
+                || summary.unrouted > 0
+                || summary.feed > 0
+            {
+                3
+            } else {
+                0
+            }
+        }
+        Err(err) => {
+            eprintln!("jetson-gateway: replay failed: {err}");
+            1
         }
     }
 }
 
 #[tokio::main]
 async fn main() {
-    loop {
-        match run_gateway().await {
-            Ok(()) => break,
-            Err(err) => {
-                eprintln!("jetson-gateway error: {err}; retrying in 3s");
-                sleep(Duration::from_secs(3)).await;
+    let mut cli = Cli::parse();
+    let command = cli.command.take();
+    let config = match Config::from_cli(cli) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("jetson-gateway: {err}");
+            std::process::exit(2);
+        }
+    };
+    if let Some(Command::ReplayDlq { files }) = command {
+        let code = replay_dlq(&config, files).await;
+        tls::remove_tunnels();
+        std::process::exit(code);
+    }
+    let codecs = match Codecs::load(&config) {
+        Ok(codecs) => codecs,
+        Err(err) => {
+            eprintln!("jetson-gateway: {err}");
+            std::process::exit(2);
+        }
+    };
+    let schemas = match Schemas::load(config.schemas.as_ref()) {
+        Ok(schemas) => schemas,
+        Err(err) => {
+            eprintln!("jetson-gateway: {err}");
+            std::process::exit(2);
+        }
+    };
+    let mut signals = match Signals::new() {
+        Ok(signals) => signals,
+        Err(err) => {
+            eprintln!("jetson-gateway: cannot install signal handlers: {err}");
+            std::process::exit(1);
+        }
+    };
+    let sinks = match Fanout::from_config(&config).await {
+        Ok(sinks) => Arc::new(sinks),
+        Err(err) => {
+            eprintln!("jetson-gateway: {err}");
+            std::process::exit(1);
+        }
+    };
+    let dead_letters = match DeadLetters::start(&config).await {
+        Ok(dead_letters) => Arc::new(dead_letters),
+        Err(err) => {
+            eprintln!("jetson-gateway: {err}");
+            std::process::exit(1);
+        }
+    };
+    let normalizer = match Normalizer::new(&config, schemas) {
+        Ok(normalizer) => Arc::new(normalizer),
+        Err(err) => {
+            eprintln!("jetson-gateway: {err}");
+            std::process::exit(1);
+        }
+    };
+    let mut feeds = match Feeds::start(&config, &normalizer, &sinks, &dead_letters) {
+        Ok(feeds) => feeds,
+        Err(err) => {
+            eprintln!("jetson-gateway: {err}");
+            std::process::exit(1);
+        }
+    };
+    let subscribed = !config.subscriptions.is_empty();
+    let supervisor = Arc::new(Supervisor::new(config.reconnect.clone()));
+    let health = (config.health.log_interval_secs > 0).then(|| {
+        tokio::spawn(log_health(
+            Arc::clone(&sinks),
+            subscribed.then(|| Arc::clone(&supervisor)),
+            feeds.monitor(),
+            dead_letters.monitor(),
+            Arc::clone(&normalizer),
+            Duration::from_secs(config.health.log_interval_secs),
+        ))
+    });
+    let connection = if !subscribed {
+        let name = signals.recv().await;
+        println!("jetson-gateway: {name} received, shutting down");
+        None
+    } else {
+        loop {
+            match run_gateway(&config, &codecs, &normalizer, &sinks, &dead_letters, &mut signals, &supervisor).await {
+                Ok(connection) => break Some(connection),
+                Err(err) => {
+                    let delay = supervisor.failed(&err);
+                    tokio::select! {
+                        _ = sleep(delay) => {}
+                        name = signals.recv() => {
+                            println!("jetson-gateway: {name} received, shutting down");
+                            break None;
+                        }
+                    }
+                }
             }
         }
+    };
+    feeds.stop().await;
+    if let Some(health) = health {
+        health.abort();
+        let _ = health.await;
     }
+    let Ok(sinks) = Arc::try_unwrap(sinks) else {
+        unreachable!("the health task and feed readers held the only other references");
+    };
+    let deadline = Duration::from_secs(config.shutdown.drain_timeout_secs);
+    // The feeds' final checkpoints follow the acks the draining sinks release.
+    let shutdown = async {
+        let drained = drain(sinks, connection).await;
+        feeds.finish().await;
+        let Ok(dead_letters) = Arc::try_unwrap(dead_letters) else {
+            unreachable!("the feed readers held the only other references");
+        };
+        dead_letters.close().await;
+        normalizer.dedup.close();
+        normalizer.drift.close();
+        drained
+    };
+    let code = tokio::select! {
+        drained = timeout(deadline, shutdown) => match drained {
+            Ok(true) => {
+                println!("jetson-gateway: shutdown complete");
+                0
+            }
+            Ok(false) => {
+                eprintln!("jetson-gateway: shutdown complete, some sinks left events undelivered");
+                3
+            }
+            Err(_) => {
+                eprintln!(
+                    "jetson-gateway: sinks not drained within {}s, exiting anyway",
+                    deadline.as_secs()
+                );
+                3
+            }
+        },
+        name = signals.recv() => {
+            eprintln!("jetson-gateway: {name} received again, exiting without draining");
+            3
+        }
+    };
+    // Connections still polled in the background do not get to close
+    // their tunnels.
+    tls::remove_tunnels();
+    std::process::exit(code);
 }
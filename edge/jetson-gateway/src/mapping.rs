@@ -0,0 +1,613 @@
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::RawAnalyticsEvent;
+
+/// `[subscriptions.mapping]`: rules that build the envelope of a vendor
+/// payload. Each rule reads the record at a JSON Pointer (`/meta/serial`) or
+/// JSONPath (`$.meta.serial`), may transform what it finds, and falls back
+/// to a default. Parsed at config load, so a bad path or regex is a config
+/// error.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "MappingSpec")]
+pub struct Mapping {
+    device_id: Rule,
+    zone_id: Rule,
+    category: Rule,
+    /// The projected `fields`; `None` keeps the whole record.
+    fields: Option<Vec<(String, Rule)>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingSpec {
+    #[serde(default)]
+    device_id: Option<Value>,
+    #[serde(default)]
+    zone_id: Option<Value>,
+    #[serde(default)]
+    category: Option<Value>,
+    #[serde(default)]
+    fields: Option<Map<String, Value>>,
+}
+
+/// A rule in full; a bare string is shorthand for `{ path = "..." }`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    /// One path, or several tried in order.
+    #[serde(default)]
+    path: Option<OneOrMany>,
+    #[serde(default)]
+    default: Option<Value>,
+    #[serde(default)]
+    transforms: Vec<TransformSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+enum TransformSpec {
+    Lowercase,
+    Uppercase,
+    Trim,
+    /// Keeps the `value` group, else the first group, else the whole match.
+    Regex(String),
+    Lookup(Map<String, Value>),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    paths: Vec<Path>,
+    default: Option<Value>,
+    transforms: Vec<Transform>,
+}
+
+#[derive(Debug, Clone)]
+enum Transform {
+    Lowercase,
+    Uppercase,
+    Trim,
+    Regex(Regex),
+    /// Values not in the table pass through.
+    Lookup(Map<String, Value>),
+}
+
+#[derive(Debug, Clone)]
+enum Path {
+    Pointer(String),
+    /// A JSONPath; `multiple` when it can select more than one node, which
+    /// then always yields an array.
+    JsonPath {
+        segments: Vec<Segment>,
+        multiple: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Child(String),
+    Index(i64),
+    Wildcard,
+    /// `..`: the selector applied to the node and all its descendants.
+    Descendants(Box<Segment>),
+}
+
+impl TryFrom<MappingSpec> for Mapping {
+    type Error = String;
+
+    fn try_from(spec: MappingSpec) -> Result<Mapping, String> {
+        // Unmapped ids are read where the analytics envelope keeps them.
+        let rule = |name: &str, spec: Option<Value>, envelope: &str| {
+            Rule::parse(name, spec.unwrap_or_else(|| Value::from(envelope)))
+        };
+        Ok(Mapping {
+            device_id: rule("device_id", spec.device_id, "/device_id")?,
+            zone_id: rule("zone_id", spec.zone_id, "/zone_id")?,
+            category: rule("category", spec.category, "/kind")?,
+            fields: spec
+                .fields
+                .map(|fields| {
+                    fields
+                        .into_iter()
+                        .map(|(name, spec)| {
+                            let rule = Rule::parse(&format!("fields.{name}"), spec)?;
+                            Ok((name, rule))
+                        })
+                        .collect::<Result<_, String>>()
+                })
+                .transpose()?,
+        })
+    }
+}
+
+impl Mapping {
+    /// Builds the envelope of `record`. Ids and category a rule does not
+    /// find stay empty for the topic, user properties and `[defaults]` to
+    /// fill in.
+    pub fn apply(&self, record: Value) -> RawAnalyticsEvent {
+        let text = |rule: &Rule| match rule.eval(&record) {
+            Some(Value::String(text)) => text,
+            Some(value @ (Value::Number(_) | Value::Bool(_))) => value.to_string(),
+            _ => String::new(),
+        };
+        let device_id = text(&self.device_id);
+        let zone_id = text(&self.zone_id);
+        let kind = text(&self.category);
+        let payload = match &self.fields {
+            Some(fields) => Value::Object(
+                fields
+                    .iter()
+                    .filter_map(|(name, rule)| Some((name.clone(), rule.eval(&record)?)))
+                    .collect(),
+            ),
+            None => record.clone(),
+        };
+        // Kept for the source timestamp lookup.
+        let extra = match record {
+            Value::Object(object) => object,
+            _ => Map::new(),
+        };
+        RawAnalyticsEvent {
+            device_id,
+            zone_id,
+            kind,
+            payload,
+            extra,
+        }
+    }
+}
+
+impl Rule {
+    fn parse(name: &str, spec: Value) -> Result<Rule, String> {
+        let invalid = |why: String| format!("mapping rule {name}: {why}");
+        let spec = match spec {
+            Value::String(path) => RuleSpec {
+                path: Some(OneOrMany::One(path)),
+                default: None,
+                transforms: Vec::new(),
+            },
+            spec => serde_json::from_value(spec).map_err(|err| invalid(err.to_string()))?,
+        };
+        let paths = match spec.path {
+            None => Vec::new(),
+            Some(OneOrMany::One(path)) => vec![path],
+            Some(OneOrMany::Many(paths)) => paths,
+        };
+        if paths.is_empty() && spec.default.is_none() {
+            return Err(invalid("needs a path or a default".to_string()));
+        }
+        let transforms = spec
+            .transforms
+            .into_iter()
+            .map(|transform| {
+                Ok(match transform {
+                    TransformSpec::Lowercase => Transform::Lowercase,
+                    TransformSpec::Uppercase => Transform::Uppercase,
+                    TransformSpec::Trim => Transform::Trim,
+                    TransformSpec::Regex(regex) => Transform::Regex(
+                        Regex::new(&regex).map_err(|err| invalid(err.to_string()))?,
+                    ),
+                    TransformSpec::Lookup(table) => Transform::Lookup(table),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Rule {
+            paths: paths
+                .iter()
+                .map(|path| Path::parse(path).map_err(&invalid))
+                .collect::<Result<_, String>>()?,
+            default: spec.default,
+            transforms,
+        })
+    }
+
+    /// The first path that finds something, transformed, or else the
+    /// default.
+    fn eval(&self, record: &Value) -> Option<Value> {
+        self.paths
+            .iter()
+            .find_map(|path| path.select(record))
+            .and_then(|value| {
+                self.transforms
+                    .iter()
+                    .try_fold(value, |value, transform| transform.apply(value))
+            })
+            .or_else(|| self.default.clone())
+    }
+}
+
+impl Transform {
+    /// `None` when a regex does not match. Arrays are transformed element
+    /// by element.
+    fn apply(&self, value: Value) -> Option<Value> {
+        if let Value::Array(items) = value {
+            let items: Vec<Value> = items.into_iter().filter_map(|v| self.apply(v)).collect();
+            return (!items.is_empty()).then_some(Value::Array(items));
+        }
+        let text = match &value {
+            Value::String(text) => text.clone(),
+            Value::Number(_) | Value::Bool(_) => value.to_string(),
+            _ => return Some(value),
+        };
+        Some(match self {
+            Transform::Lowercase => Value::String(text.to_lowercase()),
+            Transform::Uppercase => Value::String(text.to_uppercase()),
+            Transform::Trim => Value::String(text.trim().to_string()),
+            Transform::Regex(regex) => {
+                let captures = regex.captures(&text)?;
+                let found = captures
+                    .name("value")
+                    .or_else(|| captures.get(1))
+                    .or_else(|| captures.get(0))?;
+                Value::String(found.as_str().to_string())
+            }
+            Transform::Lookup(table) => table.get(&text).cloned().unwrap_or(value),
+        })
+    }
+}
+
+impl Path {
+    /// A JSON Pointer starts with `/`; a JSONPath with `$` and may use
+    /// `.name`, `['name']`, `[0]`, `[-1]`, `*` and `..`.
+    fn parse(path: &str) -> Result<Path, String> {
+        if path.is_empty() || path.starts_with('/') {
+            return Ok(Path::Pointer(path.to_string()));
+        }
+        let invalid = |why: &str| format!("invalid path {path:?}: {why}");
+        let Some(mut rest) = path.strip_prefix('$') else {
+            return Err(invalid(
+                "expected a JSON Pointer (/...) or a JSONPath ($...)",
+            ));
+        };
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            let descendants = rest.starts_with("..");
+            let (segment, tail) = if descendants {
+                match rest[2..].strip_prefix('[') {
+                    Some(tail) => bracket_segment(tail),
+                    None => name_segment(&rest[2..]),
+                }
+            } else if let Some(tail) = rest.strip_prefix('.') {
+                name_segment(tail)
+            } else if let Some(tail) = rest.strip_prefix('[') {
+                bracket_segment(tail)
+            } else {
+                Err("expected . or [")
+            }
+            .map_err(invalid)?;
+            segments.push(if descendants {
+                Segment::Descendants(Box::new(segment))
+            } else {
+                segment
+            });
+            rest = tail;
+        }
+        let multiple = segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Wildcard | Segment::Descendants(_)));
+        Ok(Path::JsonPath { segments, multiple })
+    }
+
+    /// What the path finds; null counts as nothing.
+    fn select(&self, record: &Value) -> Option<Value> {
+        match self {
+            Path::Pointer(pointer) => record.pointer(pointer).filter(|v| !v.is_null()).cloned(),
+            Path::JsonPath { segments, multiple } => {
+                let mut nodes = vec![record];
+                for segment in segments {
+                    nodes = nodes
+                        .into_iter()
+                        .flat_map(|node| segment.select(node))
+                        .collect();
+                }
+                nodes.retain(|node| !node.is_null());
+                if *multiple {
+                    (!nodes.is_empty()).then(|| Value::Array(nodes.into_iter().cloned().collect()))
+                } else {
+                    nodes.first().map(|node| (*node).clone())
+                }
+            }
+        }
+    }
+}
+
+impl Segment {
+    fn select<'v>(&self, node: &'v Value) -> Vec<&'v Value> {
+        match (self, node) {
+            (Segment::Child(name), Value::Object(object)) => object.get(name).into_iter().collect(),
+            (Segment::Index(index), Value::Array(items)) => {
+                let index = if *index < 0 {
+                    items.len().checked_sub(index.unsigned_abs() as usize)
+                } else {
+                    Some(*index as usize)
+                };
+                index.and_then(|i| items.get(i)).into_iter().collect()
+            }
+            (Segment::Wildcard, Value::Object(object)) => object.values().collect(),
+            (Segment::Wildcard, Value::Array(items)) => items.iter().collect(),
+            (Segment::Descendants(segment), node) => {
+                let mut found = segment.select(node);
+                let children: Vec<&Value> = match node {
+                    Value::Object(object) => object.values().collect(),
+                    Value::Array(items) => items.iter().collect(),
+                    _ => Vec::new(),
+                };
+                for child in children {
+                    found.extend(self.select(child));
+                }
+                found
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// `name` or `*`, up to the next `.` or `[`.
+fn name_segment(text: &str) -> Result<(Segment, &str), &'static str> {
+    let end = text.find(['.', '[']).unwrap_or(text.len());
+    let segment = match &text[..end] {
+        "" => return Err("empty name"),
+        "*" => Segment::Wildcard,
+        name => Segment::Child(name.to_string()),
+    };
+    Ok((segment, &text[end..]))
+}
+
+/// The inside of `[...]` and what follows it.
+fn bracket_segment(text: &str) -> Result<(Segment, &str), &'static str> {
+    let end = bracket_end(text).ok_or("unclosed [")?;
+    let inner = text[..end].trim();
+    let segment = if inner == "*" {
+        Segment::Wildcard
+    } else if let Some(name) = quoted(inner) {
+        Segment::Child(name)
+    } else if let Ok(index) = inner.parse() {
+        Segment::Index(index)
+    } else {
+        return Err("only names, indexes and * are supported in [...]");
+    };
+    Ok((segment, &text[end + 1..]))
+}
+
+/// Where the `]` closing a bracket selector is, skipping quoted text.
+fn bracket_end(text: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, ']') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The name in `'name'` or `"name"`.
+fn quoted(text: &str) -> Option<String> {
+    let quote = text.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    let name = text.strip_prefix(quote)?.strip_suffix(quote)?;
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn record() -> Value {
+        json!({
+            "meta": {
+                "serial": "SN-1",
+                "site": {"zone": "z1"},
+                "tags": ["a", "b"],
+                "empty": null,
+                "weird.key": 5
+            },
+            "readings": [{"id": 1, "t": 20.5}, {"id": 2, "t": 21}],
+            "a/b": 3
+        })
+    }
+
+    #[test]
+    fn paths_select_from_the_record() {
+        let cases = [
+            ("", Some(record())),
+            ("/meta/serial", Some(json!("SN-1"))),
+            ("/a~1b", Some(json!(3))),
+            ("/readings/1/t", Some(json!(21))),
+            ("/meta/empty", None),
+            ("/missing", None),
+            ("$", Some(record())),
+            ("$.meta.serial", Some(json!("SN-1"))),
+            ("$['meta']['weird.key']", Some(json!(5))),
+            ("$[\"meta\"].site.zone", Some(json!("z1"))),
+            ("$.readings[0].id", Some(json!(1))),
+            ("$.readings[-1].id", Some(json!(2))),
+            ("$.readings[2].id", None),
+            ("$.readings[-3]", None),
+            ("$.meta.empty", None),
+            ("$.meta.serial.more", None),
+            ("$.missing", None),
+            ("$.readings[*].t", Some(json!([20.5, 21]))),
+            ("$.meta.tags.*", Some(json!(["a", "b"]))),
+            (
+                "$.meta.*",
+                Some(json!(["SN-1", {"zone": "z1"}, ["a", "b"], 5])),
+            ),
+            ("$.meta.site[*]", Some(json!(["z1"]))),
+            ("$..id", Some(json!([1, 2]))),
+            ("$..zone", Some(json!(["z1"]))),
+            ("$..[0]", Some(json!(["a", {"id": 1, "t": 20.5}]))),
+            ("$.readings..t", Some(json!([20.5, 21]))),
+            ("$..missing", None),
+            ("$.readings[*].missing", None),
+        ];
+        for (path, expected) in cases {
+            let selected = Path::parse(path).unwrap().select(&record());
+            assert_eq!(selected, expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn malformed_paths_are_refused() {
+        for path in [
+            "meta.serial",
+            "$meta",
+            "$.",
+            "$..",
+            "$.meta..",
+            "$.meta[",
+            "$.meta['serial]",
+            "$[1:2]",
+            "$[?(@.id)]",
+        ] {
+            assert!(Path::parse(path).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn transforms_apply_in_order() {
+        let cases = [
+            (json!(["lowercase"]), json!("CAM-1"), Some(json!("cam-1"))),
+            (json!(["uppercase"]), json!("cam-1"), Some(json!("CAM-1"))),
+            (json!(["trim"]), json!("  x \n"), Some(json!("x"))),
+            (
+                json!(["trim", "uppercase"]),
+                json!(" dock a "),
+                Some(json!("DOCK A")),
+            ),
+            (
+                json!([{"regex": "^cam-(\\d+)-(?P<value>\\w+)$"}]),
+                json!("cam-12-north"),
+                Some(json!("north")),
+            ),
+            (
+                json!([{"regex": "^cam-(\\d+)"}]),
+                json!("cam-12-north"),
+                Some(json!("12")),
+            ),
+            (
+                json!([{"regex": "\\d+"}]),
+                json!("dock 42"),
+                Some(json!("42")),
+            ),
+            (json!([{"regex": "^\\d+$"}]), json!("dock"), None),
+            (json!([{"regex": "^4"}]), json!(42), Some(json!("4"))),
+            (
+                json!([{"lookup": {"1": "dock-a"}}]),
+                json!(1),
+                Some(json!("dock-a")),
+            ),
+            (
+                json!([{"lookup": {"1": "dock-a"}}]),
+                json!("2"),
+                Some(json!("2")),
+            ),
+            (json!(["lowercase"]), json!(true), Some(json!("true"))),
+            (
+                json!(["lowercase"]),
+                json!({"k": "V"}),
+                Some(json!({"k": "V"})),
+            ),
+            (
+                json!(["uppercase"]),
+                json!(["a", 1]),
+                Some(json!(["A", "1"])),
+            ),
+            (
+                json!([{"regex": "^a"}]),
+                json!(["ab", "b"]),
+                Some(json!(["a"])),
+            ),
+            (json!([{"regex": "^a"}]), json!(["b"]), None),
+        ];
+        for (transforms, value, expected) in cases {
+            let spec = json!({"path": "/v", "transforms": transforms});
+            let rule = Rule::parse("test", spec).unwrap();
+            assert_eq!(
+                rule.eval(&json!({"v": value})),
+                expected,
+                "{transforms} on {value}"
+            );
+        }
+    }
+
+    #[test]
+    fn rules_try_their_paths_then_the_default() {
+        let rule = Rule::parse(
+            "test",
+            json!({"path": ["/serial", "$.meta.serial"], "default": "none"}),
+        )
+        .unwrap();
+        assert_eq!(rule.eval(&record()), Some(json!("SN-1")));
+        assert_eq!(
+            rule.eval(&json!({"serial": "top", "meta": {"serial": "nested"}})),
+            Some(json!("top"))
+        );
+        assert_eq!(rule.eval(&json!({"serial": null})), Some(json!("none")));
+
+        // A regex that does not match falls back to the default too.
+        let spec = json!({"path": "/meta/serial", "default": "?", "transforms": [{"regex": "^X"}]});
+        let rule = Rule::parse("test", spec).unwrap();
+        assert_eq!(rule.eval(&record()), Some(json!("?")));
+        let rule = Rule::parse("test", json!({"default": 7})).unwrap();
+        assert_eq!(rule.eval(&record()), Some(json!(7)));
+
+        for spec in [
+            json!({}),
+            json!({"path": []}),
+            json!({"path": "meta"}),
+            json!({"path": "/v", "transforms": [{"regex": "("}]}),
+            json!({"path": "/v", "transforms": ["reverse"]}),
+            json!({"path": "/v", "fallback": 1}),
+        ] {
+            let err = Rule::parse("device_id", spec.clone()).unwrap_err();
+            assert!(err.starts_with("mapping rule device_id: "), "{spec}: {err}");
+        }
+    }
+
+    #[test]
+    fn mappings_build_the_envelope() {
+        let mapping: Mapping = toml::from_str(
+            r#"
+            device_id = { path = ["/serial", "$.meta.serial"], transforms = ["lowercase"] }
+            zone_id = { path = "$.meta.site.zone", default = "unzoned" }
+            category = { default = "sensor" }
+
+            [fields]
+            temps = "$.readings[*].t"
+            first = "$.readings[0]"
+            missing = "/nope"
+            "#,
+        )
+        .unwrap();
+        let raw = mapping.apply(record());
+        assert_eq!(raw.device_id, "sn-1");
+        assert_eq!(raw.zone_id, "z1");
+        assert_eq!(raw.kind, "sensor");
+        assert_eq!(
+            raw.payload,
+            json!({"temps": [20.5, 21], "first": {"id": 1, "t": 20.5}})
+        );
+        assert_eq!(Value::Object(raw.extra), record());
+
+        // Unmapped ids are read where the analytics envelope has them, and
+        // numbers become text; without fields the record is the payload.
+        let mapping: Mapping = toml::from_str("").unwrap();
+        let record = json!({"device_id": 7, "zone_id": {"id": 1}, "kind": "door"});
+        let raw = mapping.apply(record.clone());
+        assert_eq!(raw.device_id, "7");
+        assert_eq!(raw.zone_id, "");
+        assert_eq!(raw.kind, "door");
+        assert_eq!(raw.payload, record);
+    }
+}
@@ -0,0 +1,368 @@
+use rumqttc::v5;
+use rumqttc::v5::mqttbytes::v5::{Packet, PubAckReason, PublishProperties};
+use rumqttc::{Outgoing, QoS, SubscribeReasonCode};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::time::Duration;
+
+use crate::config::{BrokerConfig, MqttVersion};
+use crate::tls::SniTunnel;
+use crate::GatewayError;
+
+/// Content type set on republished events, whose payload is always the
+/// normalized event JSON.
+const EVENT_CONTENT_TYPE: &str = "application/json";
+
+/// MQTT v5 metadata of the message an event came from.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MessageProperties {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user_properties: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Message expiry interval as received, in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_expiry_secs: Option<u32>,
+}
+
+impl MessageProperties {
+    pub fn user_property(&self, key: &str) -> Option<&str> {
+        self.user_properties
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A client over either MQTT 3.1.1 or MQTT v5, whichever
+/// `broker.mqtt_version` selects.
+#[derive(Clone)]
+pub enum Client {
+    V4(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+/// The event loop of one connection, and the SNI tunnel it connects
+/// through, if any; the tunnel closes with it.
+pub struct EventLoop {
+    protocol: Protocol,
+    tunnel: Option<SniTunnel>,
+}
+
+// One per connection, so the size difference does not matter.
+#[allow(clippy::large_enum_variant)]
+enum Protocol {
+    V4(rumqttc::EventLoop),
+    V5(v5::EventLoop),
+}
+
+/// An incoming publish, kept whole so it can be acknowledged later.
+#[derive(Debug, Clone)]
+pub enum Publish {
+    V4(rumqttc::Publish),
+    V5(v5::mqttbytes::v5::Publish),
+}
+
+/// The parts of the event loop's traffic the gateway acts on.
+pub enum Event {
+    ConnAck,
+    Publish(Box<Publish>),
+    /// The broker acknowledged one of our publishes (PUBACK or PUBCOMP).
+    PubAck {
+        rejected: bool,
+        /// v5: accepted, but no subscription matched its topic.
+        unrouted: bool,
+    },
+    SubAck {
+        rejected: bool,
+    },
+    UnsubAck,
+    /// Our DISCONNECT went out.
+    Disconnected,
+    Other,
+}
+
+/// Opens a connection to the configured broker. The subscriber connection
+/// also takes the session and ack settings from `[broker]`.
+pub fn connect(
+    broker: &BrokerConfig,
+    client_id: &str,
+    subscriber: bool,
+) -> Result<(Client, EventLoop), GatewayError> {
+    let credentials = broker.credentials()?;
+    let (host, transport, tunnel) = broker.endpoint()?;
+    let keep_alive = Duration::from_secs(broker.keep_alive_secs);
+    match broker.mqtt_version {
+        MqttVersion::V311 => {
+            let mut options = rumqttc::MqttOptions::new(client_id, &host, broker.port);
+            options.set_keep_alive(keep_alive);
+            options.set_transport(transport);
+            if let Some((username, password)) = credentials {
+                options.set_credentials(username, password);
+            }
+            if subscriber {
+                options.set_clean_session(broker.clean_session);
+                options.set_manual_acks(broker.manual_acks);
+            }
+            let (client, eventloop) = rumqttc::AsyncClient::new(options, broker.channel_capacity);
+            let protocol = Protocol::V4(eventloop);
+            Ok((Client::V4(client), EventLoop { protocol, tunnel }))
+        }
+        MqttVersion::V5 => {
+            let mut options = v5::MqttOptions::new(client_id, &host, broker.port);
+            options.set_keep_alive(keep_alive);
+            options.set_transport(transport);
+            if let Some((username, password)) = credentials {
+                options.set_credentials(username, password);
+            }
+            if subscriber {
+                options.set_clean_start(broker.clean_session);
+                options.set_manual_acks(broker.manual_acks);
+                // Without an expiry interval a v5 session ends with the
+                // connection, whatever clean_start says.
+                if !broker.clean_session {
+                    options.set_connect_properties(v5::mqttbytes::v5::ConnectProperties {
+                        session_expiry_interval: Some(broker.session_expiry_secs),
+                        ..Default::default()
+                    });
+                }
+            }
+            let (client, eventloop) = v5::AsyncClient::new(options, broker.channel_capacity);
+            let protocol = Protocol::V5(eventloop);
+            Ok((Client::V5(client), EventLoop { protocol, tunnel }))
+        }
+    }
+}
+
+impl Client {
+    pub async fn subscribe(&self, filter: &str, qos: QoS) -> Result<(), GatewayError> {
+        match self {
+            Client::V4(client) => client.subscribe(filter, qos).await.map_err(client_err),
+            Client::V5(client) => client
+                .subscribe(filter, qos5(qos))
+                .await
+                .map_err(client_err),
+        }
+    }
+
+    pub fn try_unsubscribe(&self, filter: &str) -> Result<(), GatewayError> {
+        match self {
+            Client::V4(client) => client.try_unsubscribe(filter).map_err(client_err),
+            Client::V5(client) => client.try_unsubscribe(filter).map_err(client_err),
+        }
+    }
+
+    pub async fn ack(&self, publish: &Publish) -> Result<(), GatewayError> {
+        match (self, publish) {
+            (Client::V4(client), Publish::V4(publish)) => {
+                client.ack(publish).await.map_err(client_err)
+            }
+            (Client::V5(client), Publish::V5(publish)) => {
+                client.ack(publish).await.map_err(client_err)
+            }
+            _ => Err(GatewayError::MqttClient(
+                "ack for a publish of the other protocol version".to_string(),
+            )),
+        }
+    }
+
+    pub fn try_disconnect(&self) -> Result<(), GatewayError> {
+        match self {
+            Client::V4(client) => client.try_disconnect().map_err(client_err),
+            Client::V5(client) => client.try_disconnect().map_err(client_err),
+        }
+    }
+
+    /// Publishes `payload`. Over v5 it carries the user properties and the
+    /// remaining expiry of the original message.
+    pub async fn publish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: Option<&MessageProperties>,
+        expiry_secs: Option<u32>,
+    ) -> Result<(), GatewayError> {
+        match self {
+            Client::V4(client) => client
+                .publish(topic, qos, retain, payload)
+                .await
+                .map_err(client_err),
+            Client::V5(client) => {
+                let properties = PublishProperties {
+                    payload_format_indicator: Some(1),
+                    message_expiry_interval: expiry_secs,
+                    user_properties: properties
+                        .map(|p| p.user_properties.clone())
+                        .unwrap_or_default(),
+                    content_type: Some(EVENT_CONTENT_TYPE.to_string()),
+                    ..Default::default()
+                };
+                client
+                    .publish_with_properties(topic, qos5(qos), retain, payload, properties)
+                    .await
+                    .map_err(client_err)
+            }
+        }
+    }
+
+    /// Publishes a received message again as it was, v5 user properties
+    /// and content type included.
+    pub async fn republish(
+        &self,
+        topic: String,
+        qos: QoS,
+        payload: Vec<u8>,
+        properties: Option<&MessageProperties>,
+    ) -> Result<(), GatewayError> {
+        match self {
+            Client::V4(client) => client
+                .publish(topic, qos, false, payload)
+                .await
+                .map_err(client_err),
+            Client::V5(client) => {
+                let properties = PublishProperties {
+                    user_properties: properties
+                        .map(|p| p.user_properties.clone())
+                        .unwrap_or_default(),
+                    content_type: properties.and_then(|p| p.content_type.clone()),
+                    ..Default::default()
+                };
+                client
+                    .publish_with_properties(topic, qos5(qos), false, payload, properties)
+                    .await
+                    .map_err(client_err)
+            }
+        }
+    }
+}
+
+impl EventLoop {
+    pub async fn poll(&mut self) -> Result<Event, GatewayError> {
+        let event = self.protocol.poll().await;
+        // rumqttc only sees the tunnel close; the tunnel knows why.
+        event.map_err(
+            |err| match self.tunnel.as_mut().and_then(SniTunnel::error) {
+                Some(cause) if !requests_done(&err) => cause,
+                _ => err,
+            },
+        )
+    }
+}
+
+impl Protocol {
+    async fn poll(&mut self) -> Result<Event, GatewayError> {
+        match self {
+            Protocol::V4(eventloop) => {
+                use rumqttc::{Event as E, Incoming};
+                Ok(match eventloop.poll().await? {
+                    E::Incoming(Incoming::ConnAck(_)) => Event::ConnAck,
+                    E::Incoming(Incoming::Publish(p)) => Event::Publish(Box::new(Publish::V4(p))),
+                    E::Incoming(Incoming::PubAck(_) | Incoming::PubComp(_)) => Event::PubAck {
+                        rejected: false,
+                        unrouted: false,
+                    },
+                    E::Incoming(Incoming::SubAck(ack)) => Event::SubAck {
+                        rejected: ack.return_codes.contains(&SubscribeReasonCode::Failure),
+                    },
+                    E::Incoming(Incoming::UnsubAck(_)) => Event::UnsubAck,
+                    E::Outgoing(Outgoing::Disconnect) => Event::Disconnected,
+                    _ => Event::Other,
+                })
+            }
+            Protocol::V5(eventloop) => {
+                use v5::mqttbytes::v5::SubscribeReasonCode as Code;
+                use v5::Event as E;
+                let event = eventloop
+                    .poll()
+                    .await
+                    .map_err(|err| GatewayError::MqttV5(Box::new(err)))?;
+                Ok(match event {
+                    E::Incoming(Packet::ConnAck(_)) => Event::ConnAck,
+                    E::Incoming(Packet::Publish(p)) => Event::Publish(Box::new(Publish::V5(p))),
+                    E::Incoming(Packet::PubAck(ack)) => Event::PubAck {
+                        rejected: !matches!(
+                            ack.reason,
+                            PubAckReason::Success | PubAckReason::NoMatchingSubscribers
+                        ),
+                        unrouted: ack.reason == PubAckReason::NoMatchingSubscribers,
+                    },
+                    E::Incoming(Packet::PubComp(_)) => Event::PubAck {
+                        rejected: false,
+                        unrouted: false,
+                    },
+                    E::Incoming(Packet::SubAck(ack)) => Event::SubAck {
+                        rejected: ack
+                            .return_codes
+                            .iter()
+                            .any(|code| !matches!(code, Code::Success(_))),
+                    },
+                    E::Incoming(Packet::UnsubAck(_)) => Event::UnsubAck,
+                    E::Outgoing(Outgoing::Disconnect) => Event::Disconnected,
+                    _ => Event::Other,
+                })
+            }
+        }
+    }
+}
+
+/// Whether the event loop stopped because every client handle is gone.
+pub fn requests_done(err: &GatewayError) -> bool {
+    match err {
+        GatewayError::Mqtt(err) => matches!(**err, rumqttc::ConnectionError::RequestsDone),
+        GatewayError::MqttV5(err) => matches!(**err, v5::ConnectionError::RequestsDone),
+        _ => false,
+    }
+}
+
+impl Publish {
+    pub fn topic(&self) -> Cow<'_, str> {
+        match self {
+            Publish::V4(p) => Cow::Borrowed(&p.topic),
+            Publish::V5(p) => String::from_utf8_lossy(&p.topic),
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Publish::V4(p) => &p.payload,
+            Publish::V5(p) => &p.payload,
+        }
+    }
+
+    /// QoS 0 publishes have nothing to acknowledge.
+    pub fn needs_ack(&self) -> bool {
+        match self {
+            Publish::V4(p) => p.qos != QoS::AtMostOnce,
+            Publish::V5(p) => p.qos != v5::mqttbytes::QoS::AtMostOnce,
+        }
+    }
+
+    /// The v5 metadata worth keeping; `None` over 3.1.1 or when the publish
+    /// carries none.
+    pub fn properties(&self) -> Option<MessageProperties> {
+        let Publish::V5(p) = self else {
+            return None;
+        };
+        let props = p.properties.as_ref()?;
+        let properties = MessageProperties {
+            user_properties: props.user_properties.clone(),
+            content_type: props.content_type.clone(),
+            message_expiry_secs: props.message_expiry_interval,
+        };
+        (properties != MessageProperties::default()).then_some(properties)
+    }
+}
+
+fn qos5(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+fn client_err(err: impl std::fmt::Display) -> GatewayError {
+    GatewayError::MqttClient(err.to_string())
+}
@@ -0,0 +1,1086 @@
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::{SchemaConfig, SchemaPolicy};
+use crate::{GatewayError, VirtualObjectEvent};
+
+/// `$ref`s followed within one another before a schema is taken to loop.
+const MAX_REF_DEPTH: usize = 64;
+
+/// Validation keywords we do not implement. A schema using one is refused
+/// rather than quietly passing everything it was meant to catch.
+const UNSUPPORTED: &[&str] = &[
+    "$dynamicRef",
+    "$recursiveRef",
+    "contains",
+    "dependencies",
+    "dependentRequired",
+    "dependentSchemas",
+    "else",
+    "if",
+    "maxContains",
+    "minContains",
+    "prefixItems",
+    "propertyNames",
+    "then",
+    "unevaluatedItems",
+    "unevaluatedProperties",
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaCounts {
+    pub passed: u64,
+    pub failed: u64,
+}
+
+struct Entry {
+    schema: Schema,
+    passed: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// The `[schemas]` directory: one JSON Schema per category, which that
+/// category's `fields` must match. Without `[schemas]` nothing is checked.
+pub struct Schemas {
+    config: Option<SchemaConfig>,
+    by_category: HashMap<String, Entry>,
+}
+
+impl Schemas {
+    /// Compiles every `<category>.json` in `dir`. Any file that is not a
+    /// schema we can check is a config error.
+    pub fn load(config: Option<&SchemaConfig>) -> Result<Schemas, GatewayError> {
+        let Some(config) = config else {
+            return Ok(Schemas {
+                config: None,
+                by_category: HashMap::new(),
+            });
+        };
+        let dir = &config.dir;
+        let unreadable = |err: std::io::Error| {
+            GatewayError::Config(format!("cannot read {}: {err}", dir.display()))
+        };
+        let mut by_category = HashMap::new();
+        for entry in fs::read_dir(dir).map_err(unreadable)? {
+            let path = entry.map_err(unreadable)?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(category) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let schema = fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| serde_json::from_str(&text).map_err(|err| err.to_string()))
+                .and_then(|document| Schema::compile(&document))
+                .map_err(|err| GatewayError::Config(format!("{}: {err}", path.display())))?;
+            let entry = Entry {
+                schema,
+                passed: AtomicU64::new(0),
+                failed: AtomicU64::new(0),
+            };
+            by_category.insert(category.to_string(), entry);
+        }
+        if by_category.is_empty() {
+            return Err(GatewayError::Config(format!(
+                "schemas.dir {} holds no <category>.json schemas",
+                dir.display()
+            )));
+        }
+        println!(
+            "jetson-gateway: loaded {} schemas from {}",
+            by_category.len(),
+            dir.display()
+        );
+        Ok(Schemas {
+            config: Some(config.clone()),
+            by_category,
+        })
+    }
+
+    /// Checks the event's fields against its category's schema. What does
+    /// not match is listed in `schema_violations`, and under the quarantine
+    /// policy the event is marked for the quarantine sinks.
+    pub fn check(&self, event: &mut VirtualObjectEvent) {
+        let (Some(config), Some(entry)) = (&self.config, self.by_category.get(&event.category))
+        else {
+            return;
+        };
+        let violations = entry.schema.validate(&event.fields, config.max_violations);
+        if violations.is_empty() {
+            entry.passed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if entry.failed.fetch_add(1, Ordering::Relaxed) == 0 {
+            eprintln!(
+                "jetson-gateway: {} event from device {:?} does not match its schema: {}",
+                event.category,
+                event.device_id,
+                violations.join("; ")
+            );
+        }
+        event.quarantined = config.policy == SchemaPolicy::Quarantine;
+        event.schema_violations = violations;
+    }
+
+    /// Per category; `None` without `[schemas]`.
+    pub fn status(&self) -> Option<BTreeMap<String, SchemaCounts>> {
+        self.config.as_ref()?;
+        Some(
+            self.by_category
+                .iter()
+                .map(|(category, entry)| {
+                    let counts = SchemaCounts {
+                        passed: entry.passed.load(Ordering::Relaxed),
+                        failed: entry.failed.load(Ordering::Relaxed),
+                    };
+                    (category.clone(), counts)
+                })
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Null,
+    Boolean,
+    Object,
+    Array,
+    Number,
+    String,
+    Integer,
+}
+
+impl Type {
+    fn parse(name: &str) -> Option<Type> {
+        Some(match name {
+            "null" => Type::Null,
+            "boolean" => Type::Boolean,
+            "object" => Type::Object,
+            "array" => Type::Array,
+            "number" => Type::Number,
+            "string" => Type::String,
+            "integer" => Type::Integer,
+            _ => return None,
+        })
+    }
+
+    fn of(value: &Value) -> Type {
+        match value {
+            Value::Null => Type::Null,
+            Value::Bool(_) => Type::Boolean,
+            Value::Object(_) => Type::Object,
+            Value::Array(_) => Type::Array,
+            Value::String(_) => Type::String,
+            Value::Number(_) if Type::Integer.matches(value) => Type::Integer,
+            Value::Number(_) => Type::Number,
+        }
+    }
+
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            Type::Null => value.is_null(),
+            Type::Boolean => value.is_boolean(),
+            Type::Object => value.is_object(),
+            Type::Array => value.is_array(),
+            Type::Number => value.is_number(),
+            Type::String => value.is_string(),
+            // 1.0 is an integer too.
+            Type::Integer => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Type::Null => "null",
+            Type::Boolean => "boolean",
+            Type::Object => "object",
+            Type::Array => "array",
+            Type::Number => "number",
+            Type::String => "string",
+            Type::Integer => "integer",
+        }
+    }
+}
+
+enum Node {
+    /// `true` allows anything, `false` nothing.
+    Bool(bool),
+    Keywords(Box<Keywords>),
+}
+
+#[derive(Default)]
+struct Keywords {
+    reference: Option<String>,
+    types: Vec<Type>,
+    enumeration: Option<Vec<Value>>,
+    constant: Option<Value>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    multiple_of: Option<f64>,
+    min_length: Option<u64>,
+    max_length: Option<u64>,
+    pattern: Option<Regex>,
+    items: Option<Node>,
+    min_items: Option<u64>,
+    max_items: Option<u64>,
+    unique_items: bool,
+    required: Vec<String>,
+    min_properties: Option<u64>,
+    max_properties: Option<u64>,
+    properties: HashMap<String, Node>,
+    pattern_properties: Vec<(Regex, Node)>,
+    additional_properties: Option<Node>,
+    all_of: Vec<Node>,
+    any_of: Vec<Node>,
+    one_of: Vec<Node>,
+    not: Option<Node>,
+}
+
+/// A compiled JSON Schema document: the validation keywords of draft 4
+/// through 2020-12, minus those in `UNSUPPORTED`. References must point
+/// into the same document (`#/$defs/...`). Annotations such as `format`
+/// and `title` are ignored.
+struct Schema {
+    root: Node,
+    /// `$ref` targets by reference.
+    refs: HashMap<String, Node>,
+}
+
+impl Schema {
+    fn compile(document: &Value) -> Result<Schema, String> {
+        let mut pending = Vec::new();
+        let root = compile(document, "#", &mut pending)?;
+        let mut refs = HashMap::new();
+        while let Some(reference) = pending.pop() {
+            if refs.contains_key(&reference) {
+                continue;
+            }
+            let target = document
+                .pointer(&reference[1..])
+                .ok_or_else(|| format!("$ref {reference:?} points nowhere"))?;
+            let node = compile(target, &reference, &mut pending)?;
+            refs.insert(reference, node);
+        }
+        Ok(Schema { root, refs })
+    }
+
+    /// Up to `limit` violations, each `<JSON Pointer into value>: <what>`.
+    fn validate(&self, value: &Value, limit: usize) -> Vec<String> {
+        let mut violations = Violations::new(limit);
+        self.check(&self.root, value, "", 0, &mut violations);
+        violations.list
+    }
+
+    fn matches(&self, node: &Node, value: &Value, depth: usize) -> bool {
+        let mut violations = Violations::new(1);
+        self.check(node, value, "", depth, &mut violations);
+        violations.list.is_empty()
+    }
+
+    fn check(&self, node: &Node, value: &Value, path: &str, depth: usize, out: &mut Violations) {
+        if out.full() {
+            return;
+        }
+        let k = match node {
+            Node::Bool(true) => return,
+            Node::Bool(false) => return out.push(path, "not allowed".to_string()),
+            Node::Keywords(k) => k,
+        };
+        if let Some(reference) = &k.reference {
+            if depth >= MAX_REF_DEPTH {
+                return out.push(path, format!("$ref {reference:?} nests too deep"));
+            }
+            self.check(&self.refs[reference], value, path, depth + 1, out);
+        }
+        if !k.types.is_empty() && !k.types.iter().any(|ty| ty.matches(value)) {
+            let expected: Vec<_> = k.types.iter().map(|ty| ty.name()).collect();
+            // The other keywords would only restate the mismatch.
+            return out.push(
+                path,
+                format!(
+                    "expected {}, got {}",
+                    expected.join(" or "),
+                    Type::of(value).name()
+                ),
+            );
+        }
+        if let Some(values) = &k.enumeration {
+            if !values.iter().any(|allowed| equal(allowed, value)) {
+                let allowed: Vec<_> = values.iter().map(brief).collect();
+                out.push(
+                    path,
+                    format!("{} is not one of {}", brief(value), allowed.join(", ")),
+                );
+            }
+        }
+        if let Some(constant) = &k.constant {
+            if !equal(constant, value) {
+                out.push(path, format!("{} is not {}", brief(value), brief(constant)));
+            }
+        }
+        if let Some(n) = value.as_f64() {
+            if let Some(min) = k.minimum.filter(|&min| n < min) {
+                out.push(path, format!("{n} is less than the minimum {min}"));
+            }
+            if let Some(max) = k.maximum.filter(|&max| n > max) {
+                out.push(path, format!("{n} is greater than the maximum {max}"));
+            }
+            if let Some(min) = k.exclusive_minimum.filter(|&min| n <= min) {
+                out.push(path, format!("{n} is not greater than {min}"));
+            }
+            if let Some(max) = k.exclusive_maximum.filter(|&max| n >= max) {
+                out.push(path, format!("{n} is not less than {max}"));
+            }
+            if let Some(step) = k.multiple_of {
+                let quotient = n / step;
+                if (quotient - quotient.round()).abs() > 1e-9 {
+                    out.push(path, format!("{n} is not a multiple of {step}"));
+                }
+            }
+        }
+        if let Value::String(text) = value {
+            let length = text.chars().count() as u64;
+            if let Some(min) = k.min_length.filter(|&min| length < min) {
+                out.push(path, format!("shorter than {min} characters"));
+            }
+            if let Some(max) = k.max_length.filter(|&max| length > max) {
+                out.push(path, format!("longer than {max} characters"));
+            }
+            if let Some(pattern) = k.pattern.as_ref().filter(|pattern| !pattern.is_match(text)) {
+                out.push(
+                    path,
+                    format!("{} does not match {:?}", brief(value), pattern.as_str()),
+                );
+            }
+        }
+        if let Value::Array(items) = value {
+            let count = items.len() as u64;
+            if let Some(min) = k.min_items.filter(|&min| count < min) {
+                out.push(path, format!("fewer than {min} items"));
+            }
+            if let Some(max) = k.max_items.filter(|&max| count > max) {
+                out.push(path, format!("more than {max} items"));
+            }
+            if k.unique_items
+                && (1..items.len()).any(|i| items[..i].iter().any(|item| equal(item, &items[i])))
+            {
+                out.push(path, "items are not unique".to_string());
+            }
+            if let Some(node) = &k.items {
+                for (index, item) in items.iter().enumerate() {
+                    self.check(node, item, &format!("{path}/{index}"), depth, out);
+                }
+            }
+        }
+        if let Value::Object(object) = value {
+            for name in &k.required {
+                if !object.contains_key(name) {
+                    out.push(path, format!("missing required property {name:?}"));
+                }
+            }
+            let count = object.len() as u64;
+            if let Some(min) = k.min_properties.filter(|&min| count < min) {
+                out.push(path, format!("fewer than {min} properties"));
+            }
+            if let Some(max) = k.max_properties.filter(|&max| count > max) {
+                out.push(path, format!("more than {max} properties"));
+            }
+            for (name, item) in object {
+                let item_path = format!("{path}/{}", name.replace('~', "~0").replace('/', "~1"));
+                let mut declared = false;
+                if let Some(node) = k.properties.get(name) {
+                    declared = true;
+                    self.check(node, item, &item_path, depth, out);
+                }
+                for (pattern, node) in &k.pattern_properties {
+                    if pattern.is_match(name) {
+                        declared = true;
+                        self.check(node, item, &item_path, depth, out);
+                    }
+                }
+                if let (false, Some(node)) = (declared, &k.additional_properties) {
+                    self.check(node, item, &item_path, depth, out);
+                }
+            }
+        }
+        for node in &k.all_of {
+            self.check(node, value, path, depth, out);
+        }
+        if !k.any_of.is_empty() && !k.any_of.iter().any(|node| self.matches(node, value, depth)) {
+            out.push(path, "matches none of the anyOf schemas".to_string());
+        }
+        if !k.one_of.is_empty() {
+            let matched = k
+                .one_of
+                .iter()
+                .filter(|node| self.matches(node, value, depth))
+                .count();
+            if matched != 1 {
+                out.push(
+                    path,
+                    format!("matches {matched} of the oneOf schemas instead of 1"),
+                );
+            }
+        }
+        if let Some(node) = &k.not {
+            if self.matches(node, value, depth) {
+                out.push(path, "matches the schema under not".to_string());
+            }
+        }
+    }
+}
+
+struct Violations {
+    list: Vec<String>,
+    limit: usize,
+}
+
+impl Violations {
+    fn new(limit: usize) -> Violations {
+        Violations {
+            list: Vec::new(),
+            limit,
+        }
+    }
+
+    fn full(&self) -> bool {
+        self.list.len() >= self.limit
+    }
+
+    fn push(&mut self, path: &str, message: String) {
+        if !self.full() {
+            let at = if path.is_empty() { "/" } else { path };
+            self.list.push(format!("{at}: {message}"));
+        }
+    }
+}
+
+/// Compiles the schema at `at`, a pointer into the document used in
+/// errors, and queues the `$ref`s it makes.
+fn compile(value: &Value, at: &str, refs: &mut Vec<String>) -> Result<Node, String> {
+    let object = match value {
+        Value::Bool(allowed) => return Ok(Node::Bool(*allowed)),
+        Value::Object(object) => object,
+        _ => return Err(format!("{at}: a schema must be an object or a boolean")),
+    };
+    if let Some(keyword) = UNSUPPORTED
+        .iter()
+        .find(|keyword| object.contains_key(**keyword))
+    {
+        return Err(format!("{at}: {keyword} is not supported"));
+    }
+    let mut k = Keywords::default();
+    if let Some(reference) = object.get("$ref") {
+        let reference = reference
+            .as_str()
+            .filter(|reference| reference.starts_with('#'))
+            .ok_or_else(|| {
+                format!("{at}/$ref: only references into the same file (#/...) are supported")
+            })?;
+        refs.push(reference.to_string());
+        k.reference = Some(reference.to_string());
+    }
+    let type_name = |name: &Value| {
+        name.as_str()
+            .and_then(Type::parse)
+            .ok_or_else(|| format!("{at}/type: unknown type {name}"))
+    };
+    match object.get("type") {
+        None => {}
+        Some(Value::Array(names)) => {
+            for name in names {
+                k.types.push(type_name(name)?);
+            }
+        }
+        Some(name) => k.types.push(type_name(name)?),
+    }
+    k.enumeration = match object.get("enum") {
+        None => None,
+        Some(Value::Array(values)) => Some(values.clone()),
+        Some(_) => return Err(format!("{at}/enum: must be a list")),
+    };
+    k.constant = object.get("const").cloned();
+    k.minimum = number(object, "minimum", at)?;
+    k.maximum = number(object, "maximum", at)?;
+    // Draft 4 marks minimum and maximum exclusive with a flag instead.
+    match object.get("exclusiveMinimum") {
+        Some(Value::Bool(true)) => k.exclusive_minimum = k.minimum.take(),
+        Some(Value::Bool(false)) | None => {}
+        Some(_) => k.exclusive_minimum = number(object, "exclusiveMinimum", at)?,
+    }
+    match object.get("exclusiveMaximum") {
+        Some(Value::Bool(true)) => k.exclusive_maximum = k.maximum.take(),
+        Some(Value::Bool(false)) | None => {}
+        Some(_) => k.exclusive_maximum = number(object, "exclusiveMaximum", at)?,
+    }
+    k.multiple_of = number(object, "multipleOf", at)?;
+    if k.multiple_of.is_some_and(|step| step <= 0.0) {
+        return Err(format!("{at}/multipleOf: must be greater than 0"));
+    }
+    k.min_length = count(object, "minLength", at)?;
+    k.max_length = count(object, "maxLength", at)?;
+    k.pattern = match object.get("pattern") {
+        None => None,
+        Some(pattern) => Some(regex(pattern, &format!("{at}/pattern"))?),
+    };
+    k.items = match object.get("items") {
+        None => None,
+        Some(Value::Array(_)) => {
+            return Err(format!("{at}/items: a list of schemas is not supported"))
+        }
+        Some(items) => Some(compile(items, &format!("{at}/items"), refs)?),
+    };
+    k.min_items = count(object, "minItems", at)?;
+    k.max_items = count(object, "maxItems", at)?;
+    k.unique_items = match object.get("uniqueItems") {
+        None => false,
+        Some(Value::Bool(unique)) => *unique,
+        Some(_) => return Err(format!("{at}/uniqueItems: must be true or false")),
+    };
+    k.required = match object.get("required") {
+        None => Vec::new(),
+        Some(Value::Array(names)) => names
+            .iter()
+            .map(|name| name.as_str().map(str::to_string))
+            .collect::<Option<_>>()
+            .ok_or_else(|| format!("{at}/required: must be a list of names"))?,
+        Some(_) => return Err(format!("{at}/required: must be a list of names")),
+    };
+    k.min_properties = count(object, "minProperties", at)?;
+    k.max_properties = count(object, "maxProperties", at)?;
+    for (name, schema) in schema_map(object, "properties", at)? {
+        let node = compile(schema, &format!("{at}/properties/{name}"), refs)?;
+        k.properties.insert(name.clone(), node);
+    }
+    for (pattern, schema) in schema_map(object, "patternProperties", at)? {
+        let at = format!("{at}/patternProperties/{pattern}");
+        let node = compile(schema, &at, refs)?;
+        k.pattern_properties
+            .push((regex(&Value::String(pattern.clone()), &at)?, node));
+    }
+    k.additional_properties = match object.get("additionalProperties") {
+        None => None,
+        Some(schema) => Some(compile(
+            schema,
+            &format!("{at}/additionalProperties"),
+            refs,
+        )?),
+    };
+    k.all_of = schema_list(object, "allOf", at, refs)?;
+    k.any_of = schema_list(object, "anyOf", at, refs)?;
+    k.one_of = schema_list(object, "oneOf", at, refs)?;
+    k.not = match object.get("not") {
+        None => None,
+        Some(schema) => Some(compile(schema, &format!("{at}/not"), refs)?),
+    };
+    Ok(Node::Keywords(Box::new(k)))
+}
+
+fn number(object: &Map<String, Value>, key: &str, at: &str) -> Result<Option<f64>, String> {
+    match object.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_f64()
+            .map(Some)
+            .ok_or_else(|| format!("{at}/{key}: must be a number")),
+    }
+}
+
+fn count(object: &Map<String, Value>, key: &str, at: &str) -> Result<Option<u64>, String> {
+    match object.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("{at}/{key}: must be a whole number of at least 0")),
+    }
+}
+
+fn regex(pattern: &Value, at: &str) -> Result<Regex, String> {
+    let pattern = pattern
+        .as_str()
+        .ok_or_else(|| format!("{at}: must be a regular expression"))?;
+    Regex::new(pattern).map_err(|err| format!("{at}: {err}"))
+}
+
+fn schema_map<'a>(
+    object: &'a Map<String, Value>,
+    key: &str,
+    at: &str,
+) -> Result<impl Iterator<Item = (&'a String, &'a Value)>, String> {
+    match object.get(key) {
+        None => Ok(None.into_iter().flatten()),
+        Some(Value::Object(schemas)) => Ok(Some(schemas.iter()).into_iter().flatten()),
+        Some(_) => Err(format!("{at}/{key}: must map names to schemas")),
+    }
+}
+
+fn schema_list(
+    object: &Map<String, Value>,
+    key: &str,
+    at: &str,
+    refs: &mut Vec<String>,
+) -> Result<Vec<Node>, String> {
+    match object.get(key) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(schemas)) if !schemas.is_empty() => schemas
+            .iter()
+            .enumerate()
+            .map(|(index, schema)| compile(schema, &format!("{at}/{key}/{index}"), refs))
+            .collect(),
+        Some(_) => Err(format!("{at}/{key}: must be a non-empty list of schemas")),
+    }
+}
+
+/// JSON equality, under which 1 and 1.0 are the same number.
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64() == y.as_f64(),
+        (Value::Array(x), Value::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(x, y)| equal(x, y))
+        }
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len()
+                && x.iter()
+                    .all(|(key, x)| y.get(key).is_some_and(|y| equal(x, y)))
+        }
+        _ => a == b,
+    }
+}
+
+/// `value` as JSON, cut short for a violation message.
+fn brief(value: &Value) -> String {
+    let text = value.to_string();
+    match text.char_indices().nth(40) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn violations(schema: Value, instance: Value) -> Vec<String> {
+        Schema::compile(&schema).unwrap().validate(&instance, 10)
+    }
+
+    #[test]
+    fn keywords_report_what_they_catch() {
+        let cases = [
+            (json!(true), json!({"any": 1}), vec![]),
+            (json!(false), json!(1), vec!["/: not allowed"]),
+            (json!({"type": "integer"}), json!(1.0), vec![]),
+            (
+                json!({"type": "integer", "minimum": 5}),
+                json!("x"),
+                vec!["/: expected integer, got string"],
+            ),
+            (
+                json!({"type": ["string", "null"]}),
+                json!(3),
+                vec!["/: expected string or null, got integer"],
+            ),
+            (json!({"enum": [1, "a"]}), json!(1.0), vec![]),
+            (
+                json!({"enum": [1, "a"]}),
+                json!("b"),
+                vec![r#"/: "b" is not one of 1, "a""#],
+            ),
+            (json!({"const": {"a": [1]}}), json!({"a": [1.0]}), vec![]),
+            (
+                json!({"const": {"a": [1]}}),
+                json!({"a": [2]}),
+                vec![r#"/: {"a":[2]} is not {"a":[1]}"#],
+            ),
+            (
+                json!({"minimum": 0, "maximum": 10}),
+                json!(-1),
+                vec!["/: -1 is less than the minimum 0"],
+            ),
+            (
+                json!({"minimum": 0, "maximum": 10}),
+                json!(10.5),
+                vec!["/: 10.5 is greater than the maximum 10"],
+            ),
+            (json!({"minimum": 0, "maximum": 10}), json!("11"), vec![]),
+            (json!({"multipleOf": 0.1}), json!(0.3), vec![]),
+            (
+                json!({"multipleOf": 0.1}),
+                json!(0.35),
+                vec!["/: 0.35 is not a multiple of 0.1"],
+            ),
+            (
+                json!({"minLength": 2, "maxLength": 3}),
+                json!("é"),
+                vec!["/: shorter than 2 characters"],
+            ),
+            (
+                json!({"minLength": 2, "maxLength": 3}),
+                json!("abcd"),
+                vec!["/: longer than 3 characters"],
+            ),
+            (
+                json!({"pattern": "^cam-"}),
+                json!("dock-1"),
+                vec![r#"/: "dock-1" does not match "^cam-""#],
+            ),
+            (
+                json!({"items": {"type": "number"}, "maxItems": 2, "uniqueItems": true}),
+                json!([1, 1.0, "x"]),
+                vec![
+                    "/: more than 2 items",
+                    "/: items are not unique",
+                    "/2: expected number, got string",
+                ],
+            ),
+            (
+                json!({"minItems": 1}),
+                json!([]),
+                vec!["/: fewer than 1 items"],
+            ),
+            (
+                json!({
+                    "required": ["speed"],
+                    "properties": {"lane": {"type": "integer"}},
+                    "patternProperties": {"^x-": {"type": "string"}},
+                    "additionalProperties": false,
+                }),
+                json!({"lane": 2, "x-a": 1, "a/b~": 3}),
+                vec![
+                    r#"/: missing required property "speed""#,
+                    "/x-a: expected string, got integer",
+                    "/a~1b~0: not allowed",
+                ],
+            ),
+            (
+                json!({"minProperties": 1, "maxProperties": 1}),
+                json!({}),
+                vec!["/: fewer than 1 properties"],
+            ),
+            (
+                json!({"allOf": [{"minimum": 0}, {"maximum": 1}]}),
+                json!(2),
+                vec!["/: 2 is greater than the maximum 1"],
+            ),
+            (
+                json!({"anyOf": [{"type": "string"}, {"minimum": 5}]}),
+                json!(3),
+                vec!["/: matches none of the anyOf schemas"],
+            ),
+            (
+                json!({"anyOf": [{"type": "string"}, {"minimum": 5}]}),
+                json!(6),
+                vec![],
+            ),
+            (
+                json!({"oneOf": [{"type": "integer"}, {"minimum": 0}]}),
+                json!(3),
+                vec!["/: matches 2 of the oneOf schemas instead of 1"],
+            ),
+            (
+                json!({"oneOf": [{"type": "integer"}, {"minimum": 0}]}),
+                json!(0.5),
+                vec![],
+            ),
+            (
+                json!({"not": {"type": "null"}}),
+                json!(null),
+                vec!["/: matches the schema under not"],
+            ),
+            // Annotations are not checked.
+            (
+                json!({"format": "date-time", "title": "t"}),
+                json!("x"),
+                vec![],
+            ),
+        ];
+        for (schema, instance, expected) in cases {
+            assert_eq!(
+                violations(schema.clone(), instance.clone()),
+                expected,
+                "{schema} on {instance}"
+            );
+        }
+    }
+
+    #[test]
+    fn exclusive_bounds_follow_the_draft() {
+        let cases = [
+            // Draft 4: flags on minimum and maximum.
+            (
+                json!({"minimum": 0, "exclusiveMinimum": true}),
+                json!(0),
+                vec!["/: 0 is not greater than 0"],
+            ),
+            (
+                json!({"minimum": 0, "exclusiveMinimum": true}),
+                json!(0.1),
+                vec![],
+            ),
+            (
+                json!({"maximum": 10, "exclusiveMaximum": true}),
+                json!(10),
+                vec!["/: 10 is not less than 10"],
+            ),
+            (
+                json!({"minimum": 0, "exclusiveMinimum": false}),
+                json!(0),
+                vec![],
+            ),
+            (
+                json!({"maximum": 10, "exclusiveMaximum": false}),
+                json!(11),
+                vec!["/: 11 is greater than the maximum 10"],
+            ),
+            // Draft 6 and later: bounds of their own.
+            (
+                json!({"exclusiveMinimum": 0}),
+                json!(0),
+                vec!["/: 0 is not greater than 0"],
+            ),
+            (json!({"exclusiveMaximum": 10}), json!(9.5), vec![]),
+            (
+                json!({"exclusiveMaximum": 10}),
+                json!(10),
+                vec!["/: 10 is not less than 10"],
+            ),
+            (
+                json!({"minimum": 5, "exclusiveMinimum": 0}),
+                json!(3),
+                vec!["/: 3 is less than the minimum 5"],
+            ),
+        ];
+        for (schema, instance, expected) in cases {
+            assert_eq!(
+                violations(schema.clone(), instance.clone()),
+                expected,
+                "{schema} on {instance}"
+            );
+        }
+    }
+
+    #[test]
+    fn refs_resolve_within_the_document() {
+        let cases = [
+            (
+                json!({
+                    "$defs": {"speed": {"type": "number", "minimum": 0}},
+                    "properties": {"speed": {"$ref": "#/$defs/speed"}},
+                }),
+                json!({"speed": -1}),
+                vec!["/speed: -1 is less than the minimum 0"],
+            ),
+            (
+                json!({
+                    "definitions": {"id": {"type": "string"}},
+                    "items": {"$ref": "#/definitions/id"},
+                }),
+                json!(["a", 2]),
+                vec!["/1: expected string, got integer"],
+            ),
+            // A recursive structure.
+            (
+                json!({
+                    "$ref": "#/$defs/node",
+                    "$defs": {"node": {
+                        "required": ["id"],
+                        "properties": {"next": {"$ref": "#/$defs/node"}},
+                    }},
+                }),
+                json!({"id": 1, "next": {"next": {"id": 3}}}),
+                vec![r#"/next: missing required property "id""#],
+            ),
+            // A reference to itself never gets anywhere.
+            (
+                json!({"$ref": "#"}),
+                json!(1),
+                vec![r##"/: $ref "#" nests too deep"##],
+            ),
+        ];
+        for (schema, instance, expected) in cases {
+            assert_eq!(
+                violations(schema.clone(), instance.clone()),
+                expected,
+                "{schema} on {instance}"
+            );
+        }
+    }
+
+    #[test]
+    fn schemas_that_cannot_be_checked_are_refused() {
+        let cases = [
+            (json!({"if": {}, "then": {}}), "#: if is not supported"),
+            (
+                json!({"properties": {"a": {"contains": {}}}}),
+                "#/properties/a: contains is not supported",
+            ),
+            (
+                json!({"anyOf": [{"unevaluatedProperties": false}]}),
+                "#/anyOf/0: unevaluatedProperties is not supported",
+            ),
+            (
+                json!({"$defs": {"a": {"prefixItems": []}}, "$ref": "#/$defs/a"}),
+                "#/$defs/a: prefixItems is not supported",
+            ),
+            (
+                json!({"$ref": "other.json#/a"}),
+                "#/$ref: only references into the same file (#/...) are supported",
+            ),
+            (
+                json!({"$ref": "#/$defs/missing"}),
+                r##"$ref "#/$defs/missing" points nowhere"##,
+            ),
+            (
+                json!({"items": [{}]}),
+                "#/items: a list of schemas is not supported",
+            ),
+            (json!({"type": "float"}), r#"#/type: unknown type "float""#),
+            (
+                json!({"multipleOf": 0}),
+                "#/multipleOf: must be greater than 0",
+            ),
+            (
+                json!({"exclusiveMinimum": "0"}),
+                "#/exclusiveMinimum: must be a number",
+            ),
+            (
+                json!({"minLength": -1}),
+                "#/minLength: must be a whole number of at least 0",
+            ),
+            (
+                json!({"anyOf": []}),
+                "#/anyOf: must be a non-empty list of schemas",
+            ),
+            (
+                json!({"required": [1]}),
+                "#/required: must be a list of names",
+            ),
+            (json!(3), "#: a schema must be an object or a boolean"),
+        ];
+        for (schema, expected) in cases {
+            match Schema::compile(&schema) {
+                Ok(_) => panic!("{schema} compiled"),
+                Err(err) => assert_eq!(err, expected, "{schema}"),
+            }
+        }
+        // Every keyword listed, wherever it appears.
+        for keyword in UNSUPPORTED {
+            let schema = json!({"properties": {"a": {*keyword: {}}}});
+            assert!(Schema::compile(&schema).is_err(), "{keyword}");
+        }
+    }
+
+    #[test]
+    fn violations_stop_at_the_limit() {
+        let schema = Schema::compile(&json!({
+            "required": ["a"],
+            "additionalProperties": {"type": "string"},
+            "anyOf": [{"required": ["b"]}],
+        }))
+        .unwrap();
+        let instance = json!({"x": 1, "y": 2, "z": 3});
+        assert_eq!(schema.validate(&instance, 10).len(), 5);
+        assert_eq!(
+            schema.validate(&instance, 2),
+            [
+                r#"/: missing required property "a""#,
+                "/x: expected string, got integer",
+            ]
+        );
+        assert_eq!(schema.validate(&instance, 1).len(), 1);
+    }
+
+    fn schemas(policy: SchemaPolicy, max_violations: usize) -> Schemas {
+        let schema = Schema::compile(&json!({
+            "type": "object",
+            "required": ["speed"],
+            "properties": {"speed": {"type": "number", "minimum": 0}, "lane": {"type": "integer"}},
+        }))
+        .unwrap();
+        let entry = Entry {
+            schema,
+            passed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        };
+        Schemas {
+            config: Some(SchemaConfig {
+                dir: "schemas".into(),
+                policy,
+                max_violations,
+            }),
+            by_category: HashMap::from([("vehicle".to_string(), entry)]),
+        }
+    }
+
+    fn event(category: &str, fields: Value) -> VirtualObjectEvent {
+        serde_json::from_value(json!({
+            "event_id": "e", "ts_unix_ms": 0, "device_id": "cam-1",
+            "zone_id": "z", "category": category, "fields": fields,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn the_policy_tags_or_quarantines_what_does_not_match() {
+        for (policy, quarantined) in [(SchemaPolicy::Tag, false), (SchemaPolicy::Quarantine, true)]
+        {
+            let schemas = schemas(policy, 10);
+
+            let mut passing = event("vehicle", json!({"speed": 3, "lane": 1}));
+            schemas.check(&mut passing);
+            assert!(passing.schema_violations.is_empty());
+            assert!(!passing.quarantined);
+
+            let mut failing = event("vehicle", json!({"speed": -3, "lane": "left"}));
+            schemas.check(&mut failing);
+            assert_eq!(
+                failing.schema_violations,
+                [
+                    "/speed: -3 is less than the minimum 0",
+                    "/lane: expected integer, got string",
+                ]
+            );
+            assert_eq!(failing.quarantined, quarantined, "{policy:?}");
+
+            // Categories without a schema pass unchecked.
+            let mut unchecked = event("person", json!(null));
+            schemas.check(&mut unchecked);
+            assert!(unchecked.schema_violations.is_empty());
+            assert!(!unchecked.quarantined);
+
+            let counts = &schemas.status().unwrap()["vehicle"];
+            assert_eq!((counts.passed, counts.failed), (1, 1));
+        }
+    }
+
+    #[test]
+    fn events_list_at_most_max_violations() {
+        let schemas = schemas(SchemaPolicy::Quarantine, 1);
+        let mut failing = event("vehicle", json!({"lane": "left"}));
+        schemas.check(&mut failing);
+        assert_eq!(
+            failing.schema_violations,
+            [r#"/: missing required property "speed""#]
+        );
+        assert!(failing.quarantined);
+    }
+
+    #[test]
+    fn no_schemas_checks_nothing() {
+        let schemas = Schemas::load(None).unwrap();
+        let mut event = event("vehicle", json!({"speed": -3}));
+        schemas.check(&mut event);
+        assert!(event.schema_violations.is_empty());
+        assert!(schemas.status().is_none());
+    }
+}
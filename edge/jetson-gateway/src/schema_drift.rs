@@ -0,0 +1,603 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::config::SchemaDriftConfig;
+use crate::{now_ms, GatewayError, VirtualObjectEvent};
+
+/// Category of the events that report drift.
+pub const CATEGORY: &str = "schema-drift";
+
+/// Examples kept per field.
+const MAX_EXAMPLES: usize = 3;
+
+/// Presence rate below which a field that left the schema is forgotten.
+const FORGET_BELOW: f64 = 0.01;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaDriftStatus {
+    pub streams: usize,
+    pub drift_events: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Appeared,
+    Disappeared,
+    TypeChanged,
+}
+
+/// One change to a stream's schema: the fields of a `schema-drift` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct Drift {
+    /// Category of the stream that changed.
+    pub category: String,
+    pub change: Change,
+    pub path: String,
+    /// The field's type now; its last type when it disappeared.
+    #[serde(rename = "type")]
+    pub field_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_type: Option<String>,
+    pub presence_rate: f64,
+    /// Samples seen from the stream.
+    pub samples: u64,
+    /// The stream schema's confidence, as `JsonSchemaInferer` rates it.
+    pub confidence: f64,
+}
+
+impl Drift {
+    /// The event reporting the drift, from the device and zone of the event
+    /// that showed it. The caller assigns its id.
+    pub fn event(self, source: &VirtualObjectEvent) -> VirtualObjectEvent {
+        let ts = now_ms();
+        VirtualObjectEvent {
+            event_id: String::new(),
+            ts_unix_ms: ts,
+            source_ts: None,
+            ingest_ts: ts,
+            clock_skewed: false,
+            device_id: source.device_id.clone(),
+            zone_id: source.zone_id.clone(),
+            category: CATEGORY.to_string(),
+            fields: serde_json::to_value(self).expect("drift is plain JSON"),
+            schema_violations: Vec::new(),
+            quarantined: false,
+            mqtt: None,
+        }
+    }
+}
+
+/// What is known about one path of a stream's payloads. Rates decay over
+/// `window` samples, so they follow the stream as it changes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Field {
+    /// Samples the field was in.
+    seen: f64,
+    /// Occurrences per type.
+    types: BTreeMap<String, f64>,
+    examples: Vec<Value>,
+    /// Its type in the stream's schema; `None` while not in the schema.
+    schema_type: Option<String>,
+    /// In at least `min_presence` of samples when last seen.
+    expected: bool,
+    /// Samples in a row without it.
+    absent: u64,
+}
+
+impl Field {
+    /// Presence rate times the dominant type's share of occurrences.
+    fn confidence(&self, weight: f64) -> f64 {
+        let total: f64 = self.types.values().sum();
+        let dominant = self
+            .types
+            .values()
+            .copied()
+            .max_by(f64::total_cmp)
+            .unwrap_or_default();
+        if total > 0.0 {
+            (self.seen / weight).min(1.0) * dominant / total
+        } else {
+            0.0
+        }
+    }
+
+    /// The type with the most recent occurrences, nulls aside; `null` if
+    /// it only ever was.
+    fn dominant_type(&self) -> &str {
+        self.types
+            .iter()
+            .filter(|(ty, _)| *ty != "null")
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map_or("null", |(ty, _)| ty.as_str())
+    }
+}
+
+/// The payloads of one `(device_id, category)`, and the schema inferred
+/// from them. A line of the state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Stream {
+    device_id: String,
+    category: String,
+    samples: u64,
+    /// Samples, decayed like the field rates.
+    weight: f64,
+    confidence: f64,
+    last_seen_ms: u64,
+    fields: BTreeMap<String, Field>,
+}
+
+impl Stream {
+    /// Adds a payload. The first `min_samples` make up the schema; after
+    /// that, each field that appears, disappears or whose recent values are
+    /// mostly of another type changes the schema and is returned.
+    fn observe(&mut self, payload: &Value, config: &SchemaDriftConfig, now: u64) -> Vec<Drift> {
+        let decay = 1.0 - 1.0 / config.window as f64;
+        let mut sample = BTreeMap::new();
+        walk(payload, "#", &mut sample);
+        self.samples += 1;
+        self.weight = self.weight * decay + 1.0;
+        self.last_seen_ms = now;
+        let weight = self.weight;
+        let settled = self.samples > config.min_samples;
+        let mut changes = Vec::new();
+
+        for (path, field) in &mut self.fields {
+            field.seen *= decay;
+            field.types.values_mut().for_each(|count| *count *= decay);
+            if sample.contains_key(path) {
+                continue;
+            }
+            field.absent += 1;
+            if settled && field.expected && field.absent == config.absent_after {
+                if let Some(ty) = field.schema_type.take() {
+                    changes.push((path.clone(), Change::Disappeared, ty, None));
+                }
+            }
+        }
+        for (path, values) in sample {
+            if !self.fields.contains_key(&path) && self.fields.len() >= config.max_fields {
+                continue;
+            }
+            let field = self.fields.entry(path.clone()).or_default();
+            field.seen += 1.0;
+            field.absent = 0;
+            field.expected = field.seen / weight >= config.min_presence;
+            for value in &values {
+                *field.types.entry(type_of(value).to_string()).or_default() += 1.0;
+                if field.examples.len() < MAX_EXAMPLES && !field.examples.contains(value) {
+                    field.examples.push((*value).clone());
+                }
+            }
+            // A null says nothing about the field's type.
+            let ty = values
+                .iter()
+                .map(|value| type_of(value))
+                .find(|ty| *ty != "null")
+                .unwrap_or("null");
+            match field.schema_type.as_deref() {
+                None if settled => {
+                    field.schema_type = Some(ty.to_string());
+                    changes.push((path, Change::Appeared, ty.to_string(), None));
+                }
+                None => {}
+                Some(known) => {
+                    let dominant = field.dominant_type();
+                    if dominant != known && dominant != "null" {
+                        let dominant = dominant.to_string();
+                        // A field only seen null takes its first type quietly.
+                        if known != "null" {
+                            let known = known.to_string();
+                            changes.push((
+                                path,
+                                Change::TypeChanged,
+                                dominant.clone(),
+                                Some(known),
+                            ));
+                        }
+                        field.schema_type = Some(dominant);
+                    }
+                }
+            }
+        }
+        if self.samples == config.min_samples {
+            for field in self.fields.values_mut() {
+                field.schema_type = Some(field.dominant_type().to_string());
+            }
+        }
+        self.fields
+            .retain(|_, field| field.schema_type.is_some() || field.seen / weight >= FORGET_BELOW);
+
+        let in_schema: Vec<_> = self
+            .fields
+            .values()
+            .filter(|field| field.schema_type.is_some())
+            .collect();
+        self.confidence = if in_schema.is_empty() {
+            0.0
+        } else {
+            in_schema
+                .iter()
+                .map(|field| field.confidence(weight))
+                .sum::<f64>()
+                / in_schema.len() as f64
+        };
+        changes
+            .into_iter()
+            .map(|(path, change, field_type, previous_type)| Drift {
+                category: self.category.clone(),
+                change,
+                presence_rate: self
+                    .fields
+                    .get(&path)
+                    .map_or(0.0, |field| (field.seen / weight).min(1.0)),
+                path,
+                field_type,
+                previous_type,
+                samples: self.samples,
+                confidence: self.confidence,
+            })
+            .collect()
+    }
+}
+
+/// Collects every scalar in `value` under its path the way
+/// `JsonSchemaInferer` names them: `a.b` for nested keys, `a[]` for array
+/// elements and `#` for a payload that is itself a scalar. Empty objects and
+/// arrays leave nothing.
+fn walk<'a>(value: &'a Value, path: &str, out: &mut BTreeMap<String, Vec<&'a Value>>) {
+    match value {
+        Value::Array(items) => {
+            let path = format!("{path}[]");
+            for item in items {
+                walk(item, &path, out);
+            }
+        }
+        Value::Object(object) => {
+            for (key, item) in object {
+                let path = if path == "#" {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                walk(item, &path, out);
+            }
+        }
+        _ => out.entry(path.to_string()).or_default().push(value),
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[derive(Default)]
+struct State {
+    streams: HashMap<(String, String), Stream>,
+    /// Changed since the state file was written.
+    dirty: bool,
+}
+
+struct Shared {
+    config: SchemaDriftConfig,
+    state: Mutex<State>,
+    drift_events: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Shared {
+    /// Replaces the state file atomically, one stream per line.
+    fn save(&self) {
+        let lines: Vec<String> = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty {
+                return;
+            }
+            state.dirty = false;
+            state
+                .streams
+                .values()
+                .filter_map(|stream| serde_json::to_string(stream).ok())
+                .collect()
+        };
+        let result = (|| {
+            let path = &self.config.path;
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)?;
+            }
+            let tmp = path.with_extension("tmp");
+            let mut file = BufWriter::new(File::create(&tmp)?);
+            for line in &lines {
+                writeln!(file, "{line}")?;
+            }
+            file.into_inner()?.sync_all()?;
+            fs::rename(&tmp, path)
+        })();
+        if let Err(err) = result {
+            // Try again at the next checkpoint.
+            self.state.lock().unwrap().dirty = true;
+            let err = format!("schema drift state write failed: {err}");
+            let mut last_error = self.last_error.lock().unwrap();
+            if last_error.as_deref() != Some(err.as_str()) {
+                eprintln!("jetson-gateway: {err}");
+            }
+            *last_error = Some(err);
+        }
+    }
+}
+
+/// Infers a schema for the payloads of each `(device_id, category)` stream,
+/// as `src/ingest/JsonSchemaInferer.js` does for HAR endpoints, and reports
+/// changes to it as `schema-drift` events. The schemas are kept across
+/// restarts in the state file. Without `[schema_drift]` nothing is tracked.
+pub struct SchemaDrift {
+    shared: Option<Arc<Shared>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl SchemaDrift {
+    /// Loads the state file and starts writing it every
+    /// `checkpoint_interval_ms`.
+    pub fn start(config: Option<&SchemaDriftConfig>) -> Result<SchemaDrift, GatewayError> {
+        let Some(config) = config else {
+            return Ok(SchemaDrift {
+                shared: None,
+                task: Mutex::new(None),
+            });
+        };
+        let shared = Arc::new(Shared {
+            config: config.clone(),
+            state: Mutex::new(load(&config.path)?),
+            drift_events: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+        });
+        let interval = Duration::from_millis(config.checkpoint_interval_ms.max(1));
+        let task = tokio::spawn({
+            let shared = Arc::clone(&shared);
+            async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    let shared = Arc::clone(&shared);
+                    let _ = tokio::task::spawn_blocking(move || shared.save()).await;
+                }
+            }
+        });
+        Ok(SchemaDrift {
+            shared: Some(shared),
+            task: Mutex::new(Some(task)),
+        })
+    }
+
+    /// Adds the event's payload to its stream and returns how the stream's
+    /// schema changed.
+    pub fn observe(&self, event: &VirtualObjectEvent) -> Vec<Drift> {
+        let Some(shared) = &self.shared else {
+            return Vec::new();
+        };
+        if event.category == CATEGORY {
+            return Vec::new();
+        }
+        let config = &shared.config;
+        let mut state = shared.state.lock().unwrap();
+        let key = (event.device_id.clone(), event.category.clone());
+        if !state.streams.contains_key(&key) && state.streams.len() >= config.max_streams {
+            let oldest = state
+                .streams
+                .iter()
+                .min_by_key(|(_, stream)| stream.last_seen_ms)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                state.streams.remove(&oldest);
+            }
+        }
+        let stream = state.streams.entry(key).or_insert_with(|| Stream {
+            device_id: event.device_id.clone(),
+            category: event.category.clone(),
+            ..Stream::default()
+        });
+        let drifts = stream.observe(&event.fields, config, now_ms());
+        state.dirty = true;
+        if let Some(drift) = drifts.first() {
+            let count = drifts.len() as u64;
+            if shared.drift_events.fetch_add(count, Ordering::Relaxed) == 0 {
+                eprintln!(
+                    "jetson-gateway: {} payloads from device {:?} drifted ({:?} {}); sending {CATEGORY} events",
+                    drift.category, event.device_id, drift.change, drift.path
+                );
+            }
+        }
+        drifts
+    }
+
+    /// `None` without `[schema_drift]`.
+    pub fn status(&self) -> Option<SchemaDriftStatus> {
+        let shared = self.shared.as_ref()?;
+        Some(SchemaDriftStatus {
+            streams: shared.state.lock().unwrap().streams.len(),
+            drift_events: shared.drift_events.load(Ordering::Relaxed),
+            last_error: shared.last_error.lock().unwrap().clone(),
+        })
+    }
+
+    /// Stops the checkpoints and writes the state file a last time.
+    pub fn close(&self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        if let Some(shared) = &self.shared {
+            shared.save();
+        }
+    }
+}
+
+/// Reads a state file written by `save`. A missing file is no streams;
+/// unreadable lines are skipped.
+fn load(path: &Path) -> Result<State, GatewayError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(State::default()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut state = State::default();
+    let mut skipped = 0;
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str::<Stream>(line) {
+            Ok(stream) => {
+                let key = (stream.device_id.clone(), stream.category.clone());
+                state.streams.insert(key, stream);
+            }
+            Err(_) => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        eprintln!(
+            "jetson-gateway: skipped {skipped} unreadable lines of {}",
+            path.display()
+        );
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config() -> SchemaDriftConfig {
+        SchemaDriftConfig {
+            absent_after: 3,
+            ..SchemaDriftConfig::default()
+        }
+    }
+
+    fn changes(drifts: &[Drift]) -> Vec<(String, String, String, Option<String>)> {
+        drifts
+            .iter()
+            .map(|drift| {
+                (
+                    format!("{:?}", drift.change),
+                    drift.path.clone(),
+                    drift.field_type.clone(),
+                    drift.previous_type.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// A stream past its first `min_samples` of `payload`.
+    fn settled(payload: &Value, config: &SchemaDriftConfig) -> Stream {
+        let mut stream = Stream::default();
+        for _ in 0..config.min_samples {
+            assert!(stream.observe(payload, config, 0).is_empty());
+        }
+        stream
+    }
+
+    #[test]
+    fn a_new_field_appears() {
+        let config = config();
+        let mut stream = settled(&json!({"speed": 1}), &config);
+        let drifts = stream.observe(&json!({"speed": 1, "zone": {"id": "a"}}), &config, 0);
+        assert_eq!(
+            changes(&drifts),
+            [("Appeared".into(), "zone.id".into(), "string".into(), None)]
+        );
+        assert!(stream
+            .observe(&json!({"speed": 1, "zone": {"id": "b"}}), &config, 0)
+            .is_empty());
+    }
+
+    #[test]
+    fn an_expected_field_disappears_after_absent_after_samples() {
+        let config = config();
+        let mut stream = settled(&json!({"speed": 1, "tags": ["a"]}), &config);
+        for _ in 1..config.absent_after {
+            assert!(stream.observe(&json!({"speed": 1}), &config, 0).is_empty());
+        }
+        let drifts = stream.observe(&json!({"speed": 1}), &config, 0);
+        assert_eq!(
+            changes(&drifts),
+            [("Disappeared".into(), "tags[]".into(), "string".into(), None)]
+        );
+        assert!(stream.observe(&json!({"speed": 1}), &config, 0).is_empty());
+    }
+
+    #[test]
+    fn a_type_change_waits_for_the_new_type_to_dominate() {
+        let config = config();
+        let mut stream = settled(&json!({"speed": 1}), &config);
+        // Nulls and a stray string leave the schema alone.
+        assert!(stream
+            .observe(&json!({"speed": null}), &config, 0)
+            .is_empty());
+        assert!(stream
+            .observe(&json!({"speed": "1"}), &config, 0)
+            .is_empty());
+        assert!(stream.observe(&json!({"speed": 1}), &config, 0).is_empty());
+        let mut drifts = Vec::new();
+        for _ in 0..10 {
+            drifts.extend(stream.observe(&json!({"speed": "fast"}), &config, 0));
+        }
+        assert_eq!(
+            changes(&drifts),
+            [(
+                "TypeChanged".into(),
+                "speed".into(),
+                "string".into(),
+                Some("number".into())
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn schemas_are_kept_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("schema-drift-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = SchemaDriftConfig {
+            path: dir.join("state.ndjson"),
+            ..config()
+        };
+        let event = |fields: Value| -> VirtualObjectEvent {
+            serde_json::from_value(json!({
+                "event_id": "e", "ts_unix_ms": 0, "ingest_ts": 0, "device_id": "cam-1",
+                "zone_id": "z", "category": "vehicle", "fields": fields,
+            }))
+            .unwrap()
+        };
+
+        let drift = SchemaDrift::start(Some(&config)).unwrap();
+        for _ in 0..config.min_samples {
+            assert!(drift.observe(&event(json!({"speed": 1}))).is_empty());
+        }
+        drift.close();
+
+        let drift = SchemaDrift::start(Some(&config)).unwrap();
+        assert_eq!(drift.status().unwrap().streams, 1);
+        let drifts = drift.observe(&event(json!({"speed": 1, "lane": 2})));
+        assert_eq!(
+            changes(&drifts),
+            [("Appeared".into(), "lane".into(), "number".into(), None)]
+        );
+        drift.close();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use super::Sink;
+use crate::config::FileSinkConfig;
+use crate::{GatewayError, VirtualObjectEvent};
+
+/// Appends newline-delimited event JSON to a local file.
+pub struct FileSink {
+    out: BufWriter<File>,
+    fsync: bool,
+}
+
+impl FileSink {
+    pub async fn open(config: &FileSinkConfig) -> Result<FileSink, GatewayError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .await?;
+        Ok(FileSink {
+            out: BufWriter::new(file),
+            fsync: config.fsync,
+        })
+    }
+}
+
+impl Sink for FileSink {
+    async fn write(&mut self, event: &VirtualObjectEvent) -> Result<(), GatewayError> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.out.write_all(&line).await?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), GatewayError> {
+        self.out.flush().await?;
+        if self.fsync {
+            self.out.get_ref().sync_data().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,482 @@
+pub mod file;
+pub mod mqtt;
+pub mod snapshots;
+pub mod socket;
+pub mod sqlite;
+pub mod stdout;
+
+use serde::Serialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+
+use crate::ack::AckToken;
+use crate::config::{Config, SinkConfig, SinkKind};
+use crate::spool::{Spool, SpoolReader, SpoolStatus};
+use crate::{GatewayError, VirtualObjectEvent};
+
+/// How often an idle sink is asked to flush, so buffered writes and
+/// reconnects make progress without new events arriving.
+const IDLE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An output for normalized events.
+///
+/// Each sink is driven by its own task and only ever sees events from its
+/// own queue, so `write` may block or fail without affecting other sinks or
+/// the MQTT event loop.
+///
+/// `write` returning an error means the event was not accepted. A sink may
+/// accept events into its own buffer; `flush` must then hand off everything
+/// accepted so far, and when it fails the sink keeps that data for the next
+/// `flush`.
+pub trait Sink: Send + 'static {
+    fn write(
+        &mut self,
+        event: &VirtualObjectEvent,
+    ) -> impl Future<Output = Result<(), GatewayError>> + Send;
+
+    /// Called when the queue runs empty, periodically while idle and once
+    /// before shutdown.
+    fn flush(&mut self) -> impl Future<Output = Result<(), GatewayError>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Called once after the final `flush` succeeded, for a sink that
+    /// writes out something of its own when it stops.
+    fn close(&mut self) -> impl Future<Output = Result<(), GatewayError>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Counters and last error for one sink, shared between its task and the
+/// fan-out.
+#[derive(Debug, Default)]
+struct SinkHealth {
+    name: String,
+    healthy: AtomicBool,
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    /// Unread spool records; unused without a spool.
+    backlog: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl SinkHealth {
+    fn wrote(&self, result: Result<(), GatewayError>) -> bool {
+        match result {
+            Ok(()) => {
+                self.delivered.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(err) => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                self.unhealthy(err);
+                false
+            }
+        }
+    }
+
+    fn flushed(&self, result: Result<(), GatewayError>) -> bool {
+        match result {
+            Ok(()) => {
+                if !self.healthy.swap(true, Ordering::Relaxed) {
+                    println!("jetson-gateway: sink {} recovered", self.name);
+                }
+                true
+            }
+            Err(err) => {
+                self.unhealthy(err);
+                false
+            }
+        }
+    }
+
+    fn unhealthy(&self, err: GatewayError) {
+        if self.healthy.swap(false, Ordering::Relaxed) {
+            eprintln!("jetson-gateway: sink {} unhealthy: {err}", self.name);
+        }
+        *self.last_error.lock().unwrap() = Some(err.to_string());
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SinkStatus {
+    pub name: String,
+    pub healthy: bool,
+    pub queued: u64,
+    pub delivered: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub sinks: Vec<SinkStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spool: Option<SpoolStatus>,
+}
+
+/// An event on a sink's in-memory queue, with the ack the sink holds until
+/// it has flushed the event.
+type Queued = (Arc<VirtualObjectEvent>, Option<AckToken>);
+
+struct SinkHandle {
+    /// In-memory queue; `None` when the sink reads from the spool.
+    tx: Option<mpsc::Sender<Queued>>,
+    health: Arc<SinkHealth>,
+    /// Takes quarantined events instead of the others.
+    quarantine: bool,
+    /// Resolves to whether the sink wrote out everything before stopping.
+    task: JoinHandle<bool>,
+}
+
+/// Hands every event to all configured sinks, either through one bounded
+/// in-memory queue per sink or, with `[spool]` configured, through the
+/// durable spool that each sink reads at its own pace. Quarantined events
+/// go to the quarantine sinks instead.
+pub struct Fanout {
+    sinks: Vec<SinkHandle>,
+    spool: Option<Arc<Spool>>,
+}
+
+impl Fanout {
+    pub async fn from_config(config: &Config) -> Result<Fanout, GatewayError> {
+        let spool = config.spool.as_ref().map(Spool::open).transpose()?;
+        if let (Some(spool), Some(spool_config)) = (&spool, &config.spool) {
+            if spool_config.fsync_interval_ms > 0 {
+                tokio::spawn(sync_spool(
+                    Arc::clone(spool),
+                    Duration::from_millis(spool_config.fsync_interval_ms),
+                ));
+            }
+        }
+        // Every cursor is registered before any sink reads, so the first
+        // sink to commit cannot collect segments another still needs.
+        let mut readers = match &spool {
+            Some(spool) => config
+                .sinks
+                .iter()
+                .map(|sink_config| spool.reader(&sink_config.name()))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        }
+        .into_iter();
+        let mut sinks = Vec::with_capacity(config.sinks.len());
+        for sink_config in &config.sinks {
+            let spool = spool.as_ref().zip(readers.next());
+            let handle = match &sink_config.kind {
+                SinkKind::Stdout => spawn(sink_config, spool, stdout::StdoutSink),
+                SinkKind::File(file) => {
+                    spawn(sink_config, spool, file::FileSink::open(file).await?)
+                }
+                SinkKind::Mqtt(mqtt) => spawn(
+                    sink_config,
+                    spool,
+                    mqtt::MqttSink::connect(&config.broker, &sink_config.name(), mqtt)?,
+                ),
+                SinkKind::Socket(socket) => {
+                    spawn(sink_config, spool, socket::SocketSink::new(socket))
+                }
+                SinkKind::Sqlite(sqlite) => {
+                    spawn(sink_config, spool, sqlite::SqliteSink::open(sqlite)?)
+                }
+                SinkKind::Snapshots(snapshots) => spawn(
+                    sink_config,
+                    spool,
+                    snapshots::SnapshotSink::open(snapshots)?,
+                ),
+            };
+            println!("jetson-gateway: sink {} ready", handle.health.name);
+            sinks.push(handle);
+        }
+        Ok(Fanout { sinks, spool })
+    }
+
+    /// Hands the event to every sink without waiting. Without a spool, a
+    /// sink whose queue is full misses the event and counts it as dropped.
+    ///
+    /// `ack` is released once the event is durable: when the spool has
+    /// synced it, or else when every sink has flushed it. It is failed
+    /// instead if the spool or any sink could not take the event.
+    pub fn send(&self, event: VirtualObjectEvent, ack: Option<AckToken>) {
+        if let Some(spool) = &self.spool {
+            match tokio::task::block_in_place(|| spool.append(&event)) {
+                Ok(seq) => {
+                    if let Some(ack) = ack {
+                        spool.ack_when_synced(seq, ack);
+                    }
+                }
+                Err(err) => {
+                    eprintln!(
+                        "jetson-gateway: spool append failed for {}: {err}",
+                        event.event_id
+                    );
+                    if let Some(ack) = ack {
+                        ack.fail(&format!("spool append failed: {err}"));
+                    }
+                }
+            }
+            return;
+        }
+        let event = Arc::new(event);
+        for sink in &self.sinks {
+            if sink.quarantine != event.quarantined {
+                continue;
+            }
+            let Some(tx) = &sink.tx else { continue };
+            match tx.try_send((Arc::clone(&event), ack.clone())) {
+                Ok(()) => {}
+                Err(TrySendError::Full((_, ack))) | Err(TrySendError::Closed((_, ack))) => {
+                    sink.health.dropped.fetch_add(1, Ordering::Relaxed);
+                    if let Some(ack) = ack {
+                        ack.fail(&format!("sink {} dropped an event", sink.health.name));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn status(&self) -> HealthReport {
+        let sinks = self
+            .sinks
+            .iter()
+            .map(|sink| SinkStatus {
+                name: sink.health.name.clone(),
+                healthy: sink.health.healthy.load(Ordering::Relaxed),
+                queued: match &sink.tx {
+                    Some(tx) => (tx.max_capacity() - tx.capacity()) as u64,
+                    None => sink.health.backlog.load(Ordering::Relaxed),
+                },
+                delivered: sink.health.delivered.load(Ordering::Relaxed),
+                failed: sink.health.failed.load(Ordering::Relaxed),
+                dropped: sink.health.dropped.load(Ordering::Relaxed),
+                last_error: sink.health.last_error.lock().unwrap().clone(),
+            })
+            .collect();
+        HealthReport {
+            sinks,
+            spool: self.spool.as_ref().map(|spool| spool.status()),
+        }
+    }
+
+    /// Stops accepting events and waits for the sinks to write out what they
+    /// still hold. Returns false if any sink stopped with events undelivered.
+    pub async fn close(self) -> bool {
+        if let Some(spool) = &self.spool {
+            spool.close();
+        }
+        let mut tasks = Vec::with_capacity(self.sinks.len());
+        for sink in self.sinks {
+            drop(sink.tx);
+            tasks.push(sink.task);
+        }
+        let mut drained = true;
+        for task in tasks {
+            drained &= task.await.unwrap_or(false);
+        }
+        if let Some(spool) = &self.spool {
+            if let Err(err) = tokio::task::block_in_place(|| spool.sync()) {
+                eprintln!("jetson-gateway: spool sync failed: {err}");
+                drained = false;
+            }
+        }
+        drained
+    }
+}
+
+fn spawn<S: Sink>(
+    config: &SinkConfig,
+    spool: Option<(&Arc<Spool>, SpoolReader)>,
+    sink: S,
+) -> SinkHandle {
+    let health = Arc::new(SinkHealth {
+        name: config.name(),
+        healthy: AtomicBool::new(true),
+        ..SinkHealth::default()
+    });
+    if let Some((spool, reader)) = spool {
+        let task = tokio::spawn(drive_spooled(
+            sink,
+            reader,
+            Arc::clone(spool),
+            Arc::clone(&health),
+            config.quarantine,
+        ));
+        return SinkHandle {
+            tx: None,
+            health,
+            quarantine: config.quarantine,
+            task,
+        };
+    }
+    let (tx, rx) = mpsc::channel(config.queue_capacity);
+    let task = tokio::spawn(drive(sink, rx, Arc::clone(&health)));
+    SinkHandle {
+        tx: Some(tx),
+        health,
+        quarantine: config.quarantine,
+        task,
+    }
+}
+
+/// Feeds one sink from its in-memory queue. Acks of written events are held
+/// until the sink has flushed them.
+async fn drive<S: Sink>(
+    mut sink: S,
+    mut rx: mpsc::Receiver<Queued>,
+    health: Arc<SinkHealth>,
+) -> bool {
+    let mut idle = tokio::time::interval(IDLE_FLUSH_INTERVAL);
+    let mut unflushed = Vec::new();
+    loop {
+        tokio::select! {
+            queued = rx.recv() => match queued {
+                Some((event, ack)) => {
+                    if health.wrote(sink.write(&event).await) {
+                        unflushed.extend(ack);
+                    } else if let Some(ack) = ack {
+                        ack.fail(&format!("sink {} failed to write an event", health.name));
+                    }
+                    if rx.is_empty() && health.flushed(sink.flush().await) {
+                        unflushed.clear();
+                    }
+                }
+                None => {
+                    if health.flushed(sink.flush().await) {
+                        return closed(&mut sink, &health).await;
+                    }
+                    for ack in &unflushed {
+                        ack.fail(&format!("sink {} closed unflushed", health.name));
+                    }
+                    return false;
+                }
+            },
+            _ = idle.tick() => {
+                if health.flushed(sink.flush().await) {
+                    unflushed.clear();
+                }
+            }
+        }
+    }
+}
+
+/// Feeds one sink from its spool cursor. The cursor only moves past events
+/// once the sink has flushed them, so whatever a failing sink has not taken
+/// is replayed in order when it recovers, including after a restart.
+async fn drive_spooled<S: Sink>(
+    mut sink: S,
+    mut reader: SpoolReader,
+    spool: Arc<Spool>,
+    health: Arc<SinkHealth>,
+    quarantine: bool,
+) -> bool {
+    let mut idle = tokio::time::interval(IDLE_FLUSH_INTERVAL);
+    let mut last_flush = Instant::now();
+    let mut flush_failed = false;
+    let drained = loop {
+        let appended = spool.appended();
+        tokio::pin!(appended);
+        appended.as_mut().enable();
+
+        // The sink still holds what it failed to flush; get that out before
+        // reading anything new.
+        if flush_failed {
+            if spool.is_closed() {
+                break false;
+            }
+            health.backlog.store(reader.lag(), Ordering::Relaxed);
+            idle.tick().await;
+            flush_failed = !flush_and_commit(&mut sink, &mut reader, &health).await;
+            continue;
+        }
+
+        let mut stalled = false;
+        match reader.next() {
+            Ok(Some((event, skipped))) => {
+                health.dropped.fetch_add(skipped, Ordering::Relaxed);
+                // Events for the other kind of sink are passed over.
+                if event.quarantined == quarantine && !health.wrote(sink.write(&event).await) {
+                    reader.unread();
+                    stalled = true;
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                health.unhealthy(err);
+                stalled = true;
+            }
+        }
+        let lag = reader.lag();
+        health.backlog.store(lag, Ordering::Relaxed);
+        let caught_up = !stalled && lag == 0;
+
+        if reader.uncommitted()
+            && (caught_up || stalled || last_flush.elapsed() >= IDLE_FLUSH_INTERVAL)
+        {
+            last_flush = Instant::now();
+            flush_failed = !flush_and_commit(&mut sink, &mut reader, &health).await;
+        }
+
+        if spool.is_closed() && (caught_up || stalled) {
+            break caught_up && !flush_failed && closed(&mut sink, &health).await;
+        }
+        if !caught_up && !stalled {
+            continue;
+        }
+        tokio::select! {
+            _ = &mut appended, if !stalled => {}
+            _ = idle.tick() => {
+                if !reader.uncommitted() {
+                    health.flushed(sink.flush().await);
+                }
+            }
+        }
+    };
+    if let Err(err) = tokio::task::block_in_place(|| reader.checkpoint()) {
+        eprintln!(
+            "jetson-gateway: sink {} checkpoint failed: {err}",
+            health.name
+        );
+    }
+    drained
+}
+
+/// Closes the sink; false if that failed.
+async fn closed<S: Sink>(sink: &mut S, health: &SinkHealth) -> bool {
+    match sink.close().await {
+        Ok(()) => true,
+        Err(err) => {
+            health.unhealthy(err);
+            false
+        }
+    }
+}
+
+async fn flush_and_commit<S: Sink>(
+    sink: &mut S,
+    reader: &mut SpoolReader,
+    health: &SinkHealth,
+) -> bool {
+    if !health.flushed(sink.flush().await) {
+        return false;
+    }
+    if let Err(err) = tokio::task::block_in_place(|| reader.commit()) {
+        health.unhealthy(err);
+    }
+    true
+}
+
+async fn sync_spool(spool: Arc<Spool>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    while !spool.is_closed() {
+        ticker.tick().await;
+        if let Err(err) = tokio::task::block_in_place(|| spool.sync()) {
+            eprintln!("jetson-gateway: spool sync failed: {err}");
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use rumqttc::QoS;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+
+use super::Sink;
+use crate::config::{BrokerConfig, MqttSinkConfig};
+use crate::mqtt::{self, Client, Event};
+use crate::topic_template::TopicTemplate;
+use crate::{now_ms, GatewayError, VirtualObjectEvent};
+
+/// How long `flush` waits for the broker to acknowledge outstanding
+/// publishes before reporting the sink as failing.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Republishes events to the broker on a templated topic.
+///
+/// Uses its own connection so a backed-up publish path never holds up the
+/// subscriber's event loop. At QoS 1 and 2 an event only counts as flushed
+/// once the broker has acknowledged it.
+///
+/// Over MQTT v5 the event keeps the user properties of the message it came
+/// from and the rest of its expiry interval; expired events are not
+/// forwarded.
+pub struct MqttSink {
+    client: Client,
+    topic: TopicTemplate,
+    qos: QoS,
+    retain: bool,
+    sent: u64,
+    acked: Arc<AtomicU64>,
+    /// Publishes the broker refused (v5 only), and how many of those
+    /// `flush` has reported.
+    rejected: Arc<AtomicU64>,
+    reported_rejections: u64,
+    ack_received: Arc<Notify>,
+}
+
+impl MqttSink {
+    pub fn connect(
+        broker: &BrokerConfig,
+        name: &str,
+        config: &MqttSinkConfig,
+    ) -> Result<MqttSink, GatewayError> {
+        let topic = TopicTemplate::parse(&config.topic)?;
+        let client_id = config
+            .client_id
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", broker.client_id, name));
+        let (client, mut eventloop) = mqtt::connect(broker, &client_id, false)?;
+
+        let acked = Arc::new(AtomicU64::new(0));
+        let rejected = Arc::new(AtomicU64::new(0));
+        let ack_received = Arc::new(Notify::new());
+        let (task_acked, task_rejected, task_ack_received) = (
+            Arc::clone(&acked),
+            Arc::clone(&rejected),
+            Arc::clone(&ack_received),
+        );
+
+        // rumqttc reconnects on the next poll after an error and resends
+        // unacknowledged publishes; the loop ends once the sink and its
+        // client are dropped.
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::PubAck { rejected, .. }) => {
+                        if rejected {
+                            task_rejected.fetch_add(1, Ordering::SeqCst);
+                        }
+                        task_acked.fetch_add(1, Ordering::SeqCst);
+                        task_ack_received.notify_waiters();
+                    }
+                    Ok(_) => {}
+                    Err(err) if mqtt::requests_done(&err) => return,
+                    Err(err) => {
+                        eprintln!("jetson-gateway: {client_id}: {err}; reconnecting in 1s");
+                        sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+
+        Ok(MqttSink {
+            client,
+            topic,
+            qos: config.qos(),
+            retain: config.retain,
+            sent: 0,
+            acked,
+            rejected,
+            reported_rejections: 0,
+            ack_received,
+        })
+    }
+
+    fn unacked(&self) -> u64 {
+        self.sent.saturating_sub(self.acked.load(Ordering::SeqCst))
+    }
+}
+
+impl Sink for MqttSink {
+    async fn write(&mut self, event: &VirtualObjectEvent) -> Result<(), GatewayError> {
+        let properties = event.mqtt.as_ref();
+        let expiry_secs = match properties.and_then(|p| p.message_expiry_secs) {
+            Some(secs) => {
+                let elapsed = now_ms().saturating_sub(event.ingest_ts) / 1000;
+                if elapsed >= u64::from(secs) {
+                    return Ok(());
+                }
+                Some(secs - elapsed as u32)
+            }
+            None => None,
+        };
+        let payload = serde_json::to_vec(event)?;
+        let topic = self.topic.render(event);
+        self.client
+            .publish(
+                topic.clone(),
+                self.qos,
+                self.retain,
+                payload,
+                properties,
+                expiry_secs,
+            )
+            .await
+            .map_err(|err| GatewayError::Sink(format!("publish to {topic}: {err}")))?;
+        if self.qos != QoS::AtMostOnce {
+            self.sent += 1;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), GatewayError> {
+        let acks = async {
+            loop {
+                let ack_received = self.ack_received.notified();
+                tokio::pin!(ack_received);
+                ack_received.as_mut().enable();
+                if self.unacked() == 0 {
+                    return;
+                }
+                ack_received.await;
+            }
+        };
+        timeout(ACK_TIMEOUT, acks).await.map_err(|_| {
+            GatewayError::Sink(format!(
+                "{} publishes not acknowledged by the broker",
+                self.unacked()
+            ))
+        })?;
+        let rejected = self.rejected.load(Ordering::SeqCst);
+        if rejected > self.reported_rejections {
+            let count = rejected - self.reported_rejections;
+            self.reported_rejections = rejected;
+            return Err(GatewayError::Sink(format!(
+                "broker rejected {count} publishes"
+            )));
+        }
+        Ok(())
+    }
+}
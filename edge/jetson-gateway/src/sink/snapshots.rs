@@ -0,0 +1,134 @@
+use rusqlite::{params, Connection, TransactionBehavior};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+use super::Sink;
+use crate::config::SnapshotSinkConfig;
+use crate::excavator::{Excavation, Excavator};
+use crate::{GatewayError, VirtualObjectEvent};
+
+const CATALOG_SCHEMA: &str = include_str!("../../../../db/schema.sql");
+
+const ISO_NOW: &str = "strftime('%Y-%m-%dT%H:%M:%fZ', 'now')";
+
+/// Excavates every event's fields and writes the virtual objects found as
+/// one row of the catalog's `snapshots` table per interval, in the shape
+/// `Persistence.saveSnapshot` stores, so Javaspectre sessions can be built
+/// from edge traffic.
+///
+/// Snapshots are derived data: an event counts as written once excavated.
+/// What was excavated since the last snapshot is written when the sink
+/// closes.
+pub struct SnapshotSink {
+    conn: Connection,
+    excavator: Excavator,
+    config: SnapshotSinkConfig,
+    pending: Excavation,
+    events: u64,
+    skipped: u64,
+    since: Instant,
+}
+
+impl SnapshotSink {
+    pub fn open(config: &SnapshotSinkConfig) -> Result<SnapshotSink, GatewayError> {
+        let conn = Connection::open(&config.path)?;
+        conn.busy_timeout(Duration::from_millis(config.busy_timeout_ms))?;
+        // What Persistence.js applies to a new catalog; safe on an existing one.
+        conn.execute_batch(CATALOG_SCHEMA)?;
+        let metadata = json!({ "source": "jetson-gateway" }).to_string();
+        conn.execute(
+            &format!(
+                "INSERT OR IGNORE INTO sessions (session_id, created_at_iso, metadata_json)
+                 VALUES (?1, {ISO_NOW}, ?2)"
+            ),
+            params![config.session_id, metadata],
+        )?;
+        Ok(SnapshotSink {
+            conn,
+            excavator: Excavator::new(config.max_depth, config.max_array_sample),
+            config: config.clone(),
+            pending: Excavation::default(),
+            events: 0,
+            skipped: 0,
+            since: Instant::now(),
+        })
+    }
+
+    /// Writes the snapshot of what was excavated since the last one. On
+    /// failure it is kept and retried by the next commit.
+    fn commit(&mut self) -> Result<(), GatewayError> {
+        if self.events + self.skipped > 0 {
+            tokio::task::block_in_place(|| self.insert_snapshot()).map_err(|err| {
+                GatewayError::Sink(format!("sqlite snapshot insert failed: {err}"))
+            })?;
+        }
+        self.pending = Excavation::default();
+        self.events = 0;
+        self.skipped = 0;
+        self.since = Instant::now();
+        Ok(())
+    }
+
+    /// Numbers snapshots `<session_id>:<n>` after the highest the session
+    /// has, as `ExcavationSessionManager.addSnapshot` does. The immediate
+    /// transaction keeps another writer from taking the same number.
+    fn insert_snapshot(&mut self) -> rusqlite::Result<()> {
+        let metrics = json!({
+            "virtualObjects": self.pending.virtual_objects.len(),
+            "relationships": self.pending.relationships.len(),
+            "domSheets": self.pending.dom_sheets.len(),
+            "events": self.events,
+            "skippedEvents": self.skipped,
+        });
+        let result = serde_json::to_string(&self.pending)
+            .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+        let session_id = &self.config.session_id;
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let next: i64 = tx.query_row(
+            "SELECT COALESCE(MAX(CAST(substr(snapshot_id, length(?1) + 2) AS INTEGER)) + 1, 0)
+             FROM snapshots WHERE session_id = ?1 AND snapshot_id LIKE ?1 || ':%'",
+            [session_id],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            &format!(
+                "INSERT INTO snapshots
+                     (snapshot_id, session_id, label, captured_at_iso, metrics_json, result_json)
+                 VALUES (?1, ?2, ?3, {ISO_NOW}, ?4, ?5)"
+            ),
+            params![
+                format!("{session_id}:{next}"),
+                session_id,
+                self.config.label,
+                metrics.to_string(),
+                result,
+            ],
+        )?;
+        tx.commit()
+    }
+}
+
+impl Sink for SnapshotSink {
+    async fn write(&mut self, event: &VirtualObjectEvent) -> Result<(), GatewayError> {
+        if self.events as usize >= self.config.max_events {
+            self.skipped += 1;
+        } else {
+            self.excavator.excavate(&event.fields, &mut self.pending);
+            self.events += 1;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), GatewayError> {
+        if self.since.elapsed() >= Duration::from_secs(self.config.interval_secs) {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), GatewayError> {
+        self.commit()
+    }
+}
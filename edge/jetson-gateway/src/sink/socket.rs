@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use tokio::time::Instant;
+
+use super::Sink;
+use crate::config::SocketSinkConfig;
+use crate::{GatewayError, VirtualObjectEvent};
+
+/// Streams newline-delimited event JSON to a local ingestion process
+/// listening on a Unix socket.
+///
+/// Reconnects on its own whenever the listener goes away and keeps up to
+/// `buffer_capacity` lines while disconnected; once that is full, further
+/// events are refused until the listener is back.
+pub struct SocketSink {
+    path: PathBuf,
+    buffer_capacity: usize,
+    reconnect_delay: Duration,
+    pending: VecDeque<Vec<u8>>,
+    stream: Option<UnixStream>,
+    next_connect: Instant,
+}
+
+impl SocketSink {
+    pub fn new(config: &SocketSinkConfig) -> SocketSink {
+        SocketSink {
+            path: config.path.clone(),
+            buffer_capacity: config.buffer_capacity,
+            reconnect_delay: Duration::from_millis(config.reconnect_delay_ms),
+            pending: VecDeque::new(),
+            stream: None,
+            next_connect: Instant::now(),
+        }
+    }
+
+    async fn connect(&mut self) {
+        if Instant::now() < self.next_connect {
+            return;
+        }
+        match UnixStream::connect(&self.path).await {
+            Ok(stream) => {
+                eprintln!(
+                    "jetson-gateway: socket sink connected to {} ({} buffered)",
+                    self.path.display(),
+                    self.pending.len()
+                );
+                self.stream = Some(stream);
+            }
+            Err(_) => self.next_connect = Instant::now() + self.reconnect_delay,
+        }
+    }
+
+    async fn drain(&mut self) -> Result<(), GatewayError> {
+        if self.stream.is_none() {
+            self.connect().await;
+        }
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(GatewayError::Sink(format!(
+                "{}: not connected ({} lines buffered)",
+                self.path.display(),
+                self.pending.len()
+            )));
+        };
+        while let Some(line) = self.pending.front() {
+            if let Err(err) = stream.write_all(line).await {
+                self.stream = None;
+                self.next_connect = Instant::now();
+                return Err(GatewayError::Sink(format!(
+                    "{}: {err}",
+                    self.path.display()
+                )));
+            }
+            self.pending.pop_front();
+        }
+        Ok(())
+    }
+}
+
+impl Sink for SocketSink {
+    async fn write(&mut self, event: &VirtualObjectEvent) -> Result<(), GatewayError> {
+        if self.pending.len() >= self.buffer_capacity {
+            self.drain().await?;
+        }
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.pending.push_back(line);
+        // Accepted either way; a failed drain is reported by `flush`.
+        let _ = self.drain().await;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), GatewayError> {
+        self.drain().await
+    }
+}
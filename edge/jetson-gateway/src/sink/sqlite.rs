@@ -0,0 +1,107 @@
+use rusqlite::{params, Connection};
+use std::time::Duration;
+
+use super::Sink;
+use crate::config::SqliteSinkConfig;
+use crate::{GatewayError, VirtualObjectEvent};
+
+const VO_EVENTS_MIGRATION: &str = include_str!("../../../../db/migrations/002_add_vo_events.sql");
+const VO_EVENT_TIMESTAMPS_MIGRATION: &str =
+    include_str!("../../../../db/migrations/003_add_vo_event_timestamps.sql");
+
+const INSERT_EVENT_SQL: &str = "
+    INSERT OR IGNORE INTO vo_events
+        (event_id, ts_unix_ms, ts_iso, device_id, zone_id, category, fields_json,
+         source_ts_ms, ingest_ts_ms, clock_skewed)
+    VALUES
+        (?1, ?2, strftime('%Y-%m-%dT%H:%M:%fZ', ?2 / 1000.0, 'unixepoch'), ?3, ?4, ?5, ?6,
+         ?7, ?8, ?9)
+";
+
+/// Writes events into the Javaspectre catalog's `vo_events` table, one
+/// transaction per batch.
+pub struct SqliteSink {
+    conn: Connection,
+    batch: Vec<VirtualObjectEvent>,
+    batch_size: usize,
+}
+
+impl SqliteSink {
+    pub fn open(config: &SqliteSinkConfig) -> Result<SqliteSink, GatewayError> {
+        let conn = Connection::open(&config.path)?;
+        // Same pragmas Persistence.js sets on the catalog.
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        conn.busy_timeout(Duration::from_millis(config.busy_timeout_ms))?;
+        conn.execute_batch(VO_EVENTS_MIGRATION)?;
+        // ALTER TABLE has no IF NOT EXISTS; look for the columns instead.
+        let migrated: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('vo_events') WHERE name = 'ingest_ts_ms'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !migrated {
+            conn.execute_batch(VO_EVENT_TIMESTAMPS_MIGRATION)?;
+        }
+
+        Ok(SqliteSink {
+            conn,
+            batch: Vec::with_capacity(config.batch_size),
+            batch_size: config.batch_size,
+        })
+    }
+
+    /// Inserts the pending batch. On failure the batch is kept and retried
+    /// by the next commit; `INSERT OR IGNORE` makes retries harmless.
+    fn commit(&mut self) -> Result<(), GatewayError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        // SQLite blocks; keep it off the async worker's hot path.
+        tokio::task::block_in_place(|| insert_batch(&mut self.conn, &self.batch)).map_err(
+            |err| {
+                GatewayError::Sink(format!(
+                    "sqlite insert of {} events failed: {err}",
+                    self.batch.len()
+                ))
+            },
+        )?;
+        self.batch.clear();
+        Ok(())
+    }
+}
+
+impl Sink for SqliteSink {
+    async fn write(&mut self, event: &VirtualObjectEvent) -> Result<(), GatewayError> {
+        if self.batch.len() >= self.batch_size {
+            self.commit()?;
+        }
+        self.batch.push(event.clone());
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), GatewayError> {
+        self.commit()
+    }
+}
+
+fn insert_batch(conn: &mut Connection, batch: &[VirtualObjectEvent]) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(INSERT_EVENT_SQL)?;
+        for event in batch {
+            stmt.execute(params![
+                event.event_id,
+                event.ts_unix_ms as i64,
+                event.device_id,
+                event.zone_id,
+                event.category,
+                event.fields.to_string(),
+                event.source_ts.map(|ts| ts as i64),
+                event.ingest_ts as i64,
+                event.clock_skewed,
+            ])?;
+        }
+    }
+    tx.commit()
+}
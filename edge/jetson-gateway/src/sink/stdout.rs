@@ -0,0 +1,20 @@
+use std::io::Write;
+
+use super::Sink;
+use crate::{GatewayError, VirtualObjectEvent};
+
+/// Prints one JSON line per event, the gateway's original output.
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    async fn write(&mut self, event: &VirtualObjectEvent) -> Result<(), GatewayError> {
+        let out = serde_json::to_string(event)?;
+        println!("{}", out);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), GatewayError> {
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+}
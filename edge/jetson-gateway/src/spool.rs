@@ -0,0 +1,913 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+use crate::ack::AckToken;
+use crate::config::{OverflowPolicy, SpoolConfig};
+use crate::{GatewayError, VirtualObjectEvent};
+
+const SEGMENT_EXT: &str = "seg";
+const CURSOR_EXT: &str = "cursor";
+const RECORD_HEADER_LEN: u64 = 8;
+
+/// On-disk store-and-forward queue between normalization and the sinks.
+///
+/// Events are appended to segment files named after the sequence number of
+/// their first record. Every record is `len: u32 LE, crc32: u32 LE, json`,
+/// so a torn write at the tail is detected and cut off when the spool is
+/// reopened, and a corrupt record is stepped over to the next intact one. Each sink reads through its own cursor, checkpointed to
+/// `<sink>.cursor`, and segments are deleted once every cursor is past them.
+pub struct Spool {
+    dir: PathBuf,
+    segment_bytes: u64,
+    max_bytes: u64,
+    overflow: OverflowPolicy,
+    checkpoint_interval: Duration,
+    sync_every_append: bool,
+    inner: Mutex<SpoolInner>,
+    appended: Notify,
+    closed: AtomicBool,
+}
+
+struct SpoolInner {
+    /// Oldest first; the last one is being appended to.
+    segments: VecDeque<Segment>,
+    active: File,
+    next_seq: u64,
+    total_bytes: u64,
+    unsynced: bool,
+    /// Every record before this one is on disk.
+    synced_seq: u64,
+    /// Acks released once their record is on disk, in sequence order.
+    awaiting_sync: VecDeque<(u64, AckToken)>,
+    /// Committed positions of the live readers, by sink name.
+    cursors: HashMap<String, Position>,
+    rejected: u64,
+    evicted: u64,
+}
+
+struct Segment {
+    base: u64,
+    len: u64,
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position {
+    seq: u64,
+    segment: u64,
+    offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SpoolStatus {
+    pub segments: usize,
+    pub bytes: u64,
+    pub next_seq: u64,
+    pub rejected: u64,
+    pub evicted: u64,
+}
+
+impl Spool {
+    pub fn open(config: &SpoolConfig) -> Result<Arc<Spool>, GatewayError> {
+        fs::create_dir_all(&config.dir)?;
+        let mut bases = Vec::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+                continue;
+            }
+            if let Some(base) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                bases.push(base);
+            }
+        }
+        bases.sort_unstable();
+
+        let mut segments = VecDeque::new();
+        let mut total_bytes = 0;
+        for base in &bases {
+            let path = segment_path(&config.dir, *base);
+            let len = fs::metadata(&path)?.len();
+            total_bytes += len;
+            segments.push_back(Segment {
+                base: *base,
+                len,
+                path,
+            });
+        }
+
+        let next_seq = match segments.back_mut() {
+            Some(last) => {
+                let scan = scan_segment(&last.path)?;
+                if scan.corrupt_records > 0 {
+                    eprintln!(
+                        "jetson-gateway: spool skipped {} corrupt bytes of {} ({} records lost)",
+                        scan.corrupt_bytes,
+                        last.path.display(),
+                        scan.corrupt_records
+                    );
+                }
+                if scan.valid_len < last.len {
+                    eprintln!(
+                        "jetson-gateway: spool truncating torn tail of {} at byte {} ({} bytes, {} records lost)",
+                        last.path.display(),
+                        scan.valid_len,
+                        last.len - scan.valid_len,
+                        scan.torn_records
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&last.path)?
+                        .set_len(scan.valid_len)?;
+                    total_bytes -= last.len - scan.valid_len;
+                    last.len = scan.valid_len;
+                }
+                last.base + scan.records
+            }
+            None => {
+                let path = segment_path(&config.dir, 0);
+                File::create(&path)?;
+                segments.push_back(Segment {
+                    base: 0,
+                    len: 0,
+                    path,
+                });
+                0
+            }
+        };
+        let active = OpenOptions::new()
+            .append(true)
+            .open(&segments.back().expect("spool has a segment").path)?;
+
+        println!(
+            "jetson-gateway: spool at {} ({} segments, {} bytes, next seq {})",
+            config.dir.display(),
+            segments.len(),
+            total_bytes,
+            next_seq
+        );
+        Ok(Arc::new(Spool {
+            dir: config.dir.clone(),
+            segment_bytes: config.segment_bytes,
+            max_bytes: config.max_bytes,
+            overflow: config.overflow,
+            checkpoint_interval: Duration::from_millis(config.checkpoint_interval_ms),
+            sync_every_append: config.fsync_interval_ms == 0,
+            inner: Mutex::new(SpoolInner {
+                segments,
+                active,
+                next_seq,
+                total_bytes,
+                unsynced: false,
+                synced_seq: next_seq,
+                awaiting_sync: VecDeque::new(),
+                cursors: HashMap::new(),
+                rejected: 0,
+                evicted: 0,
+            }),
+            appended: Notify::new(),
+            closed: AtomicBool::new(false),
+        }))
+    }
+
+    /// Appends one event and returns its sequence number. The record is
+    /// visible to readers at once and durable after the next `sync`.
+    pub fn append(&self, event: &VirtualObjectEvent) -> Result<u64, GatewayError> {
+        let payload = serde_json::to_vec(event)?;
+        let mut record = Vec::with_capacity(payload.len() + RECORD_HEADER_LEN as usize);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        let record_len = record.len() as u64;
+
+        let mut inner = self.inner.lock().unwrap();
+        let active_len = inner.segments.back().map_or(0, |s| s.len);
+        if active_len > 0 && active_len + record_len > self.segment_bytes {
+            self.roll(&mut inner)?;
+        }
+        if inner.total_bytes + record_len > self.max_bytes {
+            match self.overflow {
+                OverflowPolicy::DropNewest => {
+                    inner.rejected += 1;
+                    return Err(GatewayError::Spool(
+                        "spool full, event rejected".to_string(),
+                    ));
+                }
+                OverflowPolicy::DropOldest => {
+                    while inner.total_bytes + record_len > self.max_bytes
+                        && inner.segments.len() > 1
+                    {
+                        let oldest = inner.segments.pop_front().expect("len checked");
+                        let first_kept = inner.segments.front().map_or(0, |s| s.base);
+                        inner.total_bytes -= oldest.len;
+                        inner.evicted += first_kept - oldest.base;
+                        fs::remove_file(&oldest.path)?;
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = inner.active.write_all(&record) {
+            // Cut off a partial record so later appends stay readable.
+            let len = inner.segments.back().map_or(0, |s| s.len);
+            let _ = inner.active.set_len(len);
+            return Err(err.into());
+        }
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.total_bytes += record_len;
+        inner.unsynced = true;
+        if let Some(active) = inner.segments.back_mut() {
+            active.len += record_len;
+        }
+        drop(inner);
+        self.appended.notify_waiters();
+        if self.sync_every_append {
+            self.sync()?;
+        }
+        Ok(seq)
+    }
+
+    fn roll(&self, inner: &mut SpoolInner) -> Result<(), GatewayError> {
+        inner.active.sync_data()?;
+        let base = inner.next_seq;
+        let path = segment_path(&self.dir, base);
+        inner.active = OpenOptions::new().create(true).append(true).open(&path)?;
+        inner.segments.push_back(Segment { base, len: 0, path });
+        inner.synced(base);
+        sync_dir(&self.dir)
+    }
+
+    /// Flushes appended records to disk. The fsync runs without the lock
+    /// held, so appends and readers carry on meanwhile.
+    pub fn sync(&self) -> Result<(), GatewayError> {
+        let (active, seq) = {
+            let inner = self.inner.lock().unwrap();
+            if !inner.unsynced {
+                return Ok(());
+            }
+            (inner.active.try_clone()?, inner.next_seq)
+        };
+        active.sync_data()?;
+        let mut inner = self.inner.lock().unwrap();
+        // A roll in between synced at least this far already.
+        if seq > inner.synced_seq {
+            inner.synced(seq);
+        }
+        Ok(())
+    }
+
+    /// Holds `ack` until record `seq` is on disk.
+    pub fn ack_when_synced(&self, seq: u64, ack: AckToken) {
+        let mut inner = self.inner.lock().unwrap();
+        if seq >= inner.synced_seq {
+            inner.awaiting_sync.push_back((seq, ack));
+        }
+    }
+
+    /// Opens the cursor for `name`, resuming from its last checkpoint or
+    /// from the oldest retained record.
+    pub fn reader(self: &Arc<Self>, name: &str) -> Result<SpoolReader, GatewayError> {
+        let cursor_path = self.dir.join(format!("{}.{CURSOR_EXT}", file_safe(name)));
+        let saved = match fs::read_to_string(&cursor_path) {
+            Ok(text) => parse_cursor(&text),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let oldest = inner.segments.front().map_or(0, |s| s.base);
+        let position = match saved {
+            Some(pos) if pos.seq <= inner.next_seq => pos,
+            _ => Position {
+                seq: oldest,
+                segment: oldest,
+                offset: 0,
+            },
+        };
+        inner.cursors.insert(name.to_string(), position);
+        Ok(SpoolReader {
+            spool: Arc::clone(self),
+            name: name.to_string(),
+            cursor_path,
+            position,
+            previous: position,
+            committed: position,
+            saved: position,
+            last_checkpoint: Instant::now(),
+            file: None,
+            skipped: 0,
+        })
+    }
+
+    pub fn status(&self) -> SpoolStatus {
+        let inner = self.inner.lock().unwrap();
+        SpoolStatus {
+            segments: inner.segments.len(),
+            bytes: inner.total_bytes,
+            next_seq: inner.next_seq,
+            rejected: inner.rejected,
+            evicted: inner.evicted,
+        }
+    }
+
+    /// Resolves on the next append. Enable it before checking for records so
+    /// an append in between is not missed.
+    pub fn appended(&self) -> Notified<'_> {
+        self.appended.notified()
+    }
+
+    /// Tells readers to stop once they have caught up.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.appended.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Deletes sealed segments that every live cursor has moved past.
+    fn collect_garbage(&self, inner: &mut SpoolInner) -> Result<(), GatewayError> {
+        let Some(min_segment) = inner.cursors.values().map(|p| p.segment).min() else {
+            return Ok(());
+        };
+        while inner.segments.len() > 1 && inner.segments[0].base < min_segment {
+            let done = inner.segments.pop_front().expect("len checked");
+            inner.total_bytes -= done.len;
+            fs::remove_file(&done.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        // Dropping a token would ack it; these records never reached disk.
+        if let Ok(inner) = self.inner.get_mut() {
+            for (_, ack) in inner.awaiting_sync.drain(..) {
+                ack.fail("spool closed before the event was synced");
+            }
+        }
+    }
+}
+
+impl SpoolInner {
+    /// Records that everything before `seq` is on disk and releases the
+    /// acks waiting for it.
+    fn synced(&mut self, seq: u64) {
+        self.unsynced = seq < self.next_seq;
+        self.synced_seq = seq;
+        while self
+            .awaiting_sync
+            .front()
+            .is_some_and(|(waiting, _)| *waiting < seq)
+        {
+            self.awaiting_sync.pop_front();
+        }
+    }
+}
+
+/// One sink's view of the spool.
+pub struct SpoolReader {
+    spool: Arc<Spool>,
+    name: String,
+    cursor_path: PathBuf,
+    /// Next record to read.
+    position: Position,
+    /// Position before the last record read.
+    previous: Position,
+    /// Everything before this has been flushed by the sink.
+    committed: Position,
+    /// Last position written to the cursor file.
+    saved: Position,
+    last_checkpoint: Instant,
+    file: Option<(u64, File)>,
+    /// Records lost to eviction or corruption, not yet reported by `next`.
+    skipped: u64,
+}
+
+impl SpoolReader {
+    /// Reads the next record, or `None` when caught up. Records that were
+    /// evicted or found corrupt before this reader got to them are skipped
+    /// and counted in the `u64` returned with the next record.
+    pub fn next(&mut self) -> Result<Option<(VirtualObjectEvent, u64)>, GatewayError> {
+        loop {
+            let end = {
+                let inner = self.spool.inner.lock().unwrap();
+                if self.position.seq >= inner.next_seq {
+                    return Ok(None);
+                }
+                let oldest = inner.segments.front().map_or(0, |s| s.base);
+                if self.position.segment < oldest {
+                    self.skipped += oldest.saturating_sub(self.position.seq);
+                    self.position = Position {
+                        seq: oldest,
+                        segment: oldest,
+                        offset: 0,
+                    };
+                }
+                let idx = inner
+                    .segments
+                    .iter()
+                    .position(|s| s.base == self.position.segment);
+                let Some(idx) = idx else {
+                    // Our segment was never there (stale cursor); restart at
+                    // the oldest one.
+                    self.position.segment = oldest;
+                    self.position.offset = 0;
+                    continue;
+                };
+                let segment = &inner.segments[idx];
+                if self.position.offset >= segment.len {
+                    match inner.segments.get(idx + 1) {
+                        Some(next) => {
+                            self.position = Position {
+                                seq: next.base,
+                                segment: next.base,
+                                offset: 0,
+                            };
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                segment.len
+            };
+
+            match self.read_record(end) {
+                Ok(Some((payload, record_len))) => {
+                    self.previous = self.position;
+                    self.position.seq += 1;
+                    self.position.offset += record_len;
+                    match serde_json::from_slice::<VirtualObjectEvent>(&payload) {
+                        Ok(mut event) => {
+                            // Spooled before events carried ingest_ts.
+                            if event.ingest_ts == 0 {
+                                event.ingest_ts = event.ts_unix_ms;
+                            }
+                            return Ok(Some((event, std::mem::take(&mut self.skipped))));
+                        }
+                        Err(err) => {
+                            eprintln!("jetson-gateway: spool skipping undecodable record: {err}");
+                        }
+                    }
+                }
+                Ok(None) => self.skip_corrupt(end)?,
+                Err(GatewayError::Io(err)) if err.kind() == ErrorKind::NotFound => {
+                    // Evicted between the check above and the open.
+                    self.file = None;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Steps over a corrupt record to the next intact one of the segment,
+    /// counting the records lost as reopening the spool does, or past the
+    /// rest of the segment if none is left.
+    fn skip_corrupt(&mut self, end: u64) -> Result<(), GatewayError> {
+        let (_, file) = self.file.as_mut().expect("read_record opened it");
+        file.seek(SeekFrom::Start(self.position.offset))?;
+        let mut data = Vec::new();
+        Read::by_ref(file)
+            .take(end - self.position.offset)
+            .read_to_end(&mut data)?;
+        match next_frame(&data, 0) {
+            Some(next) => {
+                let lost = records_lost(&data[..next]);
+                eprintln!(
+                    "jetson-gateway: spool segment {} corrupt at byte {}; skipped {next} bytes ({lost} records lost)",
+                    self.position.segment, self.position.offset
+                );
+                self.skipped += lost;
+                self.position.seq += lost;
+                self.position.offset += next as u64;
+            }
+            None => {
+                eprintln!(
+                    "jetson-gateway: spool segment {} corrupt at byte {}; skipping the rest of it",
+                    self.position.segment, self.position.offset
+                );
+                self.skip_segment();
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves past the rest of the current segment: to the next one, or to
+    /// the end of the records appended so far if this is the active one.
+    fn skip_segment(&mut self) {
+        let inner = self.spool.inner.lock().unwrap();
+        let Some(idx) = inner
+            .segments
+            .iter()
+            .position(|s| s.base == self.position.segment)
+        else {
+            // Evicted meanwhile; `next` restarts at the oldest segment.
+            return;
+        };
+        let position = match (inner.segments.get(idx + 1), inner.segments.back()) {
+            (Some(next), _) => Position {
+                seq: next.base,
+                segment: next.base,
+                offset: 0,
+            },
+            (None, Some(active)) => Position {
+                seq: inner.next_seq,
+                segment: active.base,
+                offset: active.len,
+            },
+            (None, None) => return,
+        };
+        self.skipped += position.seq.saturating_sub(self.position.seq);
+        self.position = position;
+    }
+
+    /// Reads the record at the current position; `None` if it fails its
+    /// checksum.
+    fn read_record(&mut self, end: u64) -> Result<Option<(Vec<u8>, u64)>, GatewayError> {
+        let segment = self.position.segment;
+        if self.file.as_ref().map(|(base, _)| *base) != Some(segment) {
+            let path = segment_path(&self.spool.dir, segment);
+            self.file = Some((segment, File::open(path)?));
+        }
+        if self.position.offset + RECORD_HEADER_LEN > end {
+            return Ok(None);
+        }
+        let (_, file) = self.file.as_mut().expect("opened above");
+        file.seek(SeekFrom::Start(self.position.offset))?;
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        let len = u32::from_le_bytes(header[0..4].try_into().expect("4 bytes")) as u64;
+        let crc = u32::from_le_bytes(header[4..8].try_into().expect("4 bytes"));
+        if self.position.offset + RECORD_HEADER_LEN + len > end {
+            return Ok(None);
+        }
+        let mut payload = vec![0u8; len as usize];
+        file.read_exact(&mut payload)?;
+        if crc32fast::hash(&payload) != crc {
+            return Ok(None);
+        }
+        Ok(Some((payload, RECORD_HEADER_LEN + len)))
+    }
+
+    /// Steps back over the record returned by the last `next`, so it is
+    /// read again.
+    pub fn unread(&mut self) {
+        self.position = self.previous;
+    }
+
+    /// Marks everything read so far as delivered. The cursor file is
+    /// rewritten at most once per checkpoint interval.
+    pub fn commit(&mut self) -> Result<(), GatewayError> {
+        if self.committed != self.position {
+            self.committed = self.position;
+            self.previous = self.position;
+            let mut inner = self.spool.inner.lock().unwrap();
+            inner.cursors.insert(self.name.clone(), self.committed);
+            self.spool.collect_garbage(&mut inner)?;
+        }
+        if self.last_checkpoint.elapsed() >= self.spool.checkpoint_interval {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the committed position to the cursor file now.
+    pub fn checkpoint(&mut self) -> Result<(), GatewayError> {
+        if self.saved != self.committed {
+            write_cursor(&self.cursor_path, self.committed)?;
+            self.saved = self.committed;
+        }
+        self.last_checkpoint = Instant::now();
+        Ok(())
+    }
+
+    /// Records appended but not yet read by this cursor.
+    pub fn lag(&self) -> u64 {
+        let next_seq = self.spool.inner.lock().unwrap().next_seq;
+        next_seq.saturating_sub(self.position.seq)
+    }
+
+    pub fn uncommitted(&self) -> bool {
+        self.committed != self.position
+    }
+}
+
+fn segment_path(dir: &Path, base: u64) -> PathBuf {
+    dir.join(format!("{base:020}.{SEGMENT_EXT}"))
+}
+
+/// What reopening found in a segment.
+#[derive(Debug, Default)]
+struct Scan {
+    /// Intact records, and the records guessed lost in corrupt stretches
+    /// between them, so later records keep their sequence numbers.
+    records: u64,
+    /// Where the last intact record ends.
+    valid_len: u64,
+    corrupt_bytes: u64,
+    corrupt_records: u64,
+    /// Records guessed lost in the torn tail after `valid_len`.
+    torn_records: u64,
+}
+
+/// Walks the records of a segment, stepping over corrupt ones to the next
+/// intact record.
+fn scan_segment(path: &Path) -> Result<Scan, GatewayError> {
+    let data = fs::read(path)?;
+    let mut scan = Scan::default();
+    let mut offset = 0;
+    loop {
+        if let Some(end) = frame_end(&data, offset) {
+            scan.records += 1;
+            scan.valid_len = end as u64;
+            offset = end;
+            continue;
+        }
+        let Some(next) = next_frame(&data, offset) else {
+            break;
+        };
+        let lost = records_lost(&data[offset..next]);
+        scan.records += lost;
+        scan.corrupt_records += lost;
+        scan.corrupt_bytes += (next - offset) as u64;
+        offset = next;
+    }
+    scan.torn_records = records_lost(&data[scan.valid_len as usize..]);
+    Ok(scan)
+}
+
+/// Where the intact record starting at `offset` ends, if one does. An
+/// event is a JSON object, so a payload is never empty and is enclosed in
+/// braces, which rules out most false starts before the checksum.
+fn frame_end(data: &[u8], offset: usize) -> Option<usize> {
+    let header = RECORD_HEADER_LEN as usize;
+    let record = data.get(offset..)?;
+    let len = u32::from_le_bytes(record.get(0..4)?.try_into().expect("4 bytes")) as usize;
+    let crc = u32::from_le_bytes(record.get(4..8)?.try_into().expect("4 bytes"));
+    let payload = record.get(header..header.checked_add(len)?)?;
+    let framed = payload.first() == Some(&b'{') && payload.last() == Some(&b'}');
+    (framed && crc32fast::hash(payload) == crc).then_some(offset + header + len)
+}
+
+/// The first offset after `from` where an intact record starts.
+fn next_frame(data: &[u8], from: usize) -> Option<usize> {
+    (from + 1..data.len()).find(|&offset| frame_end(data, offset).is_some())
+}
+
+/// A guess at how many records a corrupt stretch held: its headers are
+/// followed while their lengths fit, and whatever is left counts as one.
+fn records_lost(data: &[u8]) -> u64 {
+    let mut offset = 0usize;
+    let mut records = 0;
+    while offset < data.len() {
+        records += 1;
+        let Some(len) = data.get(offset..offset + 4) else {
+            break;
+        };
+        let len = u32::from_le_bytes(len.try_into().expect("4 bytes")) as usize;
+        offset = offset
+            .saturating_add(RECORD_HEADER_LEN as usize)
+            .saturating_add(len);
+    }
+    records
+}
+
+fn parse_cursor(text: &str) -> Option<Position> {
+    let mut parts = text.split_whitespace().map(|p| p.parse::<u64>());
+    Some(Position {
+        seq: parts.next()?.ok()?,
+        segment: parts.next()?.ok()?,
+        offset: parts.next()?.ok()?,
+    })
+}
+
+/// Replaces the cursor file atomically so a crash leaves either the old or
+/// the new checkpoint.
+fn write_cursor(path: &Path, position: Position) -> Result<(), GatewayError> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    writeln!(
+        file,
+        "{} {} {}",
+        position.seq, position.segment, position.offset
+    )?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn sync_dir(dir: &Path) -> Result<(), GatewayError> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// `name` with anything unsuitable for a file name replaced by `_`.
+pub fn file_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A fresh spool directory under the system temp dir.
+    fn spool_config(name: &str) -> SpoolConfig {
+        let dir = std::env::temp_dir().join(format!(
+            "jetson-gateway-spool-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        SpoolConfig {
+            dir,
+            fsync_interval_ms: 0,
+            checkpoint_interval_ms: 0,
+            ..SpoolConfig::default()
+        }
+    }
+
+    fn event(n: u64) -> VirtualObjectEvent {
+        serde_json::from_value(json!({
+            "event_id": format!("e{n}"),
+            "ts_unix_ms": n,
+            "ingest_ts": n,
+            "device_id": "cam-1",
+            "zone_id": "dock",
+            "category": "detection",
+            "fields": { "n": n },
+        }))
+        .unwrap()
+    }
+
+    fn append(spool: &Spool, range: std::ops::Range<u64>) {
+        for n in range {
+            spool.append(&event(n)).unwrap();
+        }
+    }
+
+    /// Reads until caught up; returns the event numbers and the skipped count.
+    fn drain(reader: &mut SpoolReader) -> (Vec<u64>, u64) {
+        let mut read = Vec::new();
+        let mut skipped = 0;
+        while let Some((event, lost)) = reader.next().unwrap() {
+            read.push(event.ts_unix_ms);
+            skipped += lost;
+        }
+        (read, skipped)
+    }
+
+    /// Flips a payload byte of the `index`th record of a segment.
+    fn corrupt_record(path: &Path, index: usize) {
+        let mut data = fs::read(path).unwrap();
+        let mut offset = 0;
+        for _ in 0..index {
+            let len = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+            offset += RECORD_HEADER_LEN as usize + len as usize;
+        }
+        data[offset + RECORD_HEADER_LEN as usize] ^= 0xff;
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn reopening_cuts_a_torn_tail() {
+        let config = spool_config("torn");
+        let spool = Spool::open(&config).unwrap();
+        append(&spool, 0..3);
+        drop(spool);
+        let segment = segment_path(&config.dir, 0);
+        let intact = fs::metadata(&segment).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[200, 0, 0, 0, 1, 2, 3, 4, b'{']).unwrap();
+        drop(file);
+
+        let spool = Spool::open(&config).unwrap();
+        assert_eq!(fs::metadata(&segment).unwrap().len(), intact);
+        assert_eq!(spool.status().next_seq, 3);
+        append(&spool, 3..4);
+        let mut reader = spool.reader("stdout").unwrap();
+        assert_eq!(drain(&mut reader), (vec![0, 1, 2, 3], 0));
+    }
+
+    #[test]
+    fn crc_mismatch_skips_the_rest_of_a_sealed_segment() {
+        let config = SpoolConfig {
+            segment_bytes: 1,
+            ..spool_config("crc")
+        };
+        let spool = Spool::open(&config).unwrap();
+        // One record per segment past the first.
+        append(&spool, 0..4);
+        corrupt_record(&segment_path(&config.dir, 1), 0);
+        let mut reader = spool.reader("stdout").unwrap();
+        assert_eq!(drain(&mut reader), (vec![0, 2, 3], 1));
+    }
+
+    #[test]
+    fn a_corrupt_record_in_the_active_segment_is_stepped_over() {
+        let config = spool_config("active");
+        let spool = Spool::open(&config).unwrap();
+        append(&spool, 0..3);
+        corrupt_record(&segment_path(&config.dir, 0), 1);
+        let mut reader = spool.reader("stdout").unwrap();
+        assert_eq!(drain(&mut reader), (vec![0, 2], 1));
+        assert_eq!(reader.lag(), 0);
+        append(&spool, 3..4);
+        assert_eq!(drain(&mut reader), (vec![3], 0));
+    }
+
+    #[test]
+    fn reopening_keeps_the_records_after_a_corrupt_one() {
+        let config = spool_config("middle");
+        let spool = Spool::open(&config).unwrap();
+        append(&spool, 0..6);
+        drop(spool);
+        let segment = segment_path(&config.dir, 0);
+        let len = fs::metadata(&segment).unwrap().len();
+        corrupt_record(&segment, 1);
+        // A length past the end of the segment: found again by its
+        // checksum alone. Single-digit events all take the same room.
+        let mut data = fs::read(&segment).unwrap();
+        let fourth = 3 * data.len() / 6;
+        data[fourth..fourth + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&segment, data).unwrap();
+
+        let scan = scan_segment(&segment).unwrap();
+        assert_eq!((scan.records, scan.corrupt_records), (6, 2));
+        let spool = Spool::open(&config).unwrap();
+        assert_eq!(fs::metadata(&segment).unwrap().len(), len);
+        assert_eq!(spool.status().next_seq, 6);
+        append(&spool, 6..7);
+        let mut reader = spool.reader("stdout").unwrap();
+        assert_eq!(drain(&mut reader), (vec![0, 2, 4, 5, 6], 2));
+        assert_eq!(reader.lag(), 0);
+    }
+
+    #[test]
+    fn cursor_resumes_after_restart() {
+        let config = spool_config("resume");
+        let spool = Spool::open(&config).unwrap();
+        append(&spool, 0..5);
+        let mut reader = spool.reader("file:/tmp/out").unwrap();
+        reader.next().unwrap().unwrap();
+        reader.next().unwrap().unwrap();
+        reader.commit().unwrap();
+        // Read but never committed: replayed after the restart.
+        reader.next().unwrap().unwrap();
+        drop(reader);
+        drop(spool);
+
+        let spool = Spool::open(&config).unwrap();
+        let mut reader = spool.reader("file:/tmp/out").unwrap();
+        assert_eq!(drain(&mut reader), (vec![2, 3, 4], 0));
+    }
+
+    #[test]
+    fn lagging_cursors_keep_their_segments_across_restart() {
+        let config = SpoolConfig {
+            segment_bytes: 1,
+            ..spool_config("lagging")
+        };
+        let spool = Spool::open(&config).unwrap();
+        append(&spool, 0..6);
+        let mut fast = spool.reader("fast").unwrap();
+        let mut slow = spool.reader("slow").unwrap();
+        fast.next().unwrap().unwrap();
+        fast.next().unwrap().unwrap();
+        fast.commit().unwrap();
+        slow.next().unwrap().unwrap();
+        slow.commit().unwrap();
+        drop((fast, slow));
+        drop(spool);
+
+        // Both cursors are open before either commits, as the fan-out does.
+        let spool = Spool::open(&config).unwrap();
+        let mut fast = spool.reader("fast").unwrap();
+        let mut slow = spool.reader("slow").unwrap();
+        assert_eq!(drain(&mut fast), (vec![2, 3, 4, 5], 0));
+        fast.commit().unwrap();
+        assert_eq!(drain(&mut slow), (vec![1, 2, 3, 4, 5], 0));
+        slow.commit().unwrap();
+        assert_eq!(spool.status().segments, 1);
+    }
+}
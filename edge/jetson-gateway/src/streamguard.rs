@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::FeedKind;
+
+/// The keys of a DeepStream or AugSound record that StreamGuard looks at.
+/// They stay untyped JSON because StreamGuard passes them through as they
+/// are, and so must we for the events to come out byte for byte the same.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct FeedRecord {
+    device_id: Value,
+    stream_id: Value,
+    microphone_id: Value,
+    zone: Value,
+    zone_id: Value,
+    frame_id: Value,
+    objects: Value,
+    peak_db: Value,
+    speech_prob: Value,
+    keywords: Value,
+    policy_flags: Value,
+}
+
+/// `fields` of a vision or audio event, in StreamGuard's key order.
+#[derive(Debug, Clone, Serialize)]
+pub struct ComplianceFields {
+    pub kind: FeedKind,
+    pub frame_id: Value,
+    pub objects: Value,
+    pub peak_db: Value,
+    pub speech_prob: Value,
+    pub keywords: Value,
+    pub policy_flags: Value,
+    pub raw: Value,
+}
+
+/// A record normalized the way `StreamGuard._normalizeToVirtualObjectEvent`
+/// does it. The ids are `None` where StreamGuard would fall back to its
+/// configured device or zone.
+#[derive(Debug, Clone)]
+pub struct ComplianceEvent {
+    pub device_id: Option<String>,
+    pub zone_id: Option<String>,
+    pub fields: ComplianceFields,
+}
+
+impl ComplianceEvent {
+    pub fn category(&self) -> &'static str {
+        self.fields.kind.category()
+    }
+}
+
+/// Applies StreamGuard's rules to one record: the device id falls back from
+/// `device_id` to `stream_id` and `microphone_id`, the zone from `zone` to
+/// `zone_id`, and the known keys are lifted into `fields` next to the whole
+/// record. Like JavaScript's `||`, empty strings, zeroes and `false` count
+/// as missing for the ids, `objects`, `keywords` and `policy_flags`.
+///
+/// Two differences remain: an id that is not a string becomes its JSON text,
+/// and numbers are written by serde_json, so `1.0` stays `1.0` where
+/// StreamGuard writes `1`.
+pub fn normalize(kind: FeedKind, raw: Value) -> ComplianceEvent {
+    // A record that is not an object has none of the keys (serde would
+    // read an array positionally).
+    let record = match &raw {
+        Value::Object(_) => FeedRecord::deserialize(&raw).unwrap_or_default(),
+        _ => FeedRecord::default(),
+    };
+    let device_id = [&record.device_id, &record.stream_id, &record.microphone_id]
+        .into_iter()
+        .find(|v| truthy(v))
+        .map(id_text);
+    let zone_id = [&record.zone, &record.zone_id]
+        .into_iter()
+        .find(|v| truthy(v))
+        .map(id_text);
+    let or = |value: Value, fallback: Value| if truthy(&value) { value } else { fallback };
+    ComplianceEvent {
+        device_id,
+        zone_id,
+        fields: ComplianceFields {
+            kind,
+            frame_id: record.frame_id,
+            objects: or(record.objects, Value::Null),
+            peak_db: record.peak_db,
+            speech_prob: record.speech_prob,
+            keywords: or(record.keywords, Value::Null),
+            policy_flags: or(record.policy_flags, Value::Array(Vec::new())),
+            raw,
+        },
+    }
+}
+
+/// JavaScript truthiness.
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|n| n != 0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(_) | Value::Object(_) => true,
+    }
+}
+
+fn id_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Defaults;
+    use crate::{normalize_event, RawAnalyticsEvent};
+
+    /// Generated by running StreamGuard itself over the `line`s.
+    const FIXTURES: &str = include_str!("../../../fixtures/streamguard/cases.json");
+
+    #[derive(Deserialize)]
+    struct Fixtures {
+        defaults: FixtureDefaults,
+        cases: Vec<Case>,
+    }
+
+    #[derive(Deserialize)]
+    struct FixtureDefaults {
+        device_id: String,
+        zone_id: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Case {
+        name: String,
+        kind: String,
+        line: String,
+        expected: String,
+    }
+
+    #[test]
+    fn feed_lines_normalize_like_streamguard() {
+        let fixtures: Fixtures = serde_json::from_str(FIXTURES).unwrap();
+        let defaults = Defaults {
+            device_id: Some(fixtures.defaults.device_id),
+            zone_id: Some(fixtures.defaults.zone_id),
+            ..Defaults::default()
+        };
+        for case in fixtures.cases {
+            let raw = RawAnalyticsEvent {
+                device_id: String::new(),
+                zone_id: String::new(),
+                kind: case.kind,
+                payload: serde_json::from_str(&case.line).unwrap(),
+                extra: serde_json::Map::new(),
+            };
+            let event = normalize_event(raw, None, &defaults);
+            let mut value = serde_json::to_value(&event).unwrap();
+            let object = value.as_object_mut().unwrap();
+            object.shift_remove("event_id");
+            object.shift_remove("ts_unix_ms");
+            object.shift_remove("ingest_ts");
+            assert_eq!(
+                serde_json::to_string(&value).unwrap(),
+                case.expected,
+                "fixture {}",
+                case.name
+            );
+        }
+    }
+}
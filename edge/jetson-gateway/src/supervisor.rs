@@ -0,0 +1,216 @@
+use rand::Rng;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::ReconnectConfig;
+use crate::GatewayError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    BackingOff,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    /// Set after `crash_loop_after` consecutive failures; cleared once a
+    /// connection has stayed up for `stable_after_secs`.
+    pub crash_loop: bool,
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    pub last_error: Option<String>,
+}
+
+/// Tracks the subscriber connection across reconnects and decides how long
+/// to wait before the next attempt.
+pub struct Supervisor {
+    config: ReconnectConfig,
+    inner: Mutex<SupervisorInner>,
+}
+
+struct SupervisorInner {
+    status: ConnectionStatus,
+    connected_at: Option<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(config: ReconnectConfig) -> Supervisor {
+        Supervisor {
+            config,
+            inner: Mutex::new(SupervisorInner {
+                status: ConnectionStatus {
+                    state: ConnectionState::Connecting,
+                    crash_loop: false,
+                    consecutive_failures: 0,
+                    total_failures: 0,
+                    last_error: None,
+                },
+                connected_at: None,
+            }),
+        }
+    }
+
+    pub fn connecting(&self) {
+        self.inner.lock().unwrap().status.state = ConnectionState::Connecting;
+    }
+
+    /// Called once the broker has accepted the connection.
+    pub fn connected(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.status.state = ConnectionState::Connected;
+        inner.connected_at = Some(Instant::now());
+    }
+
+    /// Records a failed or lost connection and returns how long to back off
+    /// before the next attempt.
+    pub fn failed(&self, err: &GatewayError) -> Duration {
+        let mut inner = self.inner.lock().unwrap();
+        self.settle(&mut inner);
+        inner.connected_at = None;
+        let status = &mut inner.status;
+        status.state = ConnectionState::BackingOff;
+        status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+        status.total_failures += 1;
+        status.last_error = Some(err.to_string());
+        let failures = status.consecutive_failures;
+        if !status.crash_loop && failures >= self.config.crash_loop_after {
+            status.crash_loop = true;
+            eprintln!("jetson-gateway: crash loop: {failures} consecutive connection failures");
+        }
+        let delay = self.delay(failures, rand::thread_rng().gen());
+        eprintln!(
+            "jetson-gateway: connection failed ({failures} in a row): {err}; retrying in {:.1}s",
+            delay.as_secs_f64()
+        );
+        delay
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        let mut inner = self.inner.lock().unwrap();
+        self.settle(&mut inner);
+        inner.status.clone()
+    }
+
+    /// Forgets earlier failures once the current connection has proven
+    /// stable.
+    fn settle(&self, inner: &mut SupervisorInner) {
+        let Some(connected_at) = inner.connected_at else {
+            return;
+        };
+        if connected_at.elapsed() < Duration::from_secs(self.config.stable_after_secs) {
+            return;
+        }
+        if inner.status.crash_loop {
+            println!("jetson-gateway: connection stable again, crash loop over");
+        }
+        inner.status.crash_loop = false;
+        inner.status.consecutive_failures = 0;
+    }
+
+    /// Exponential delay for the given failure count, capped and then
+    /// shortened by `random` (in `[0, 1)`) times `jitter` of it.
+    fn delay(&self, failures: u32, random: f64) -> Duration {
+        let exponent = failures.saturating_sub(1).min(64) as i32;
+        let base = (self.config.initial_delay_ms as f64 * self.config.multiplier.powi(exponent))
+            .min(self.config.max_delay_ms as f64);
+        let jittered = base * (1.0 - self.config.jitter * random);
+        Duration::from_millis(jittered as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supervisor(stable_after_secs: u64) -> Supervisor {
+        Supervisor::new(ReconnectConfig {
+            initial_delay_ms: 100,
+            max_delay_ms: 1_000,
+            multiplier: 2.0,
+            jitter: 0.5,
+            crash_loop_after: 3,
+            stable_after_secs,
+        })
+    }
+
+    fn err() -> GatewayError {
+        GatewayError::Delivery("connection refused".to_string())
+    }
+
+    #[test]
+    fn delays_grow_exponentially_up_to_the_cap() {
+        let supervisor = supervisor(60);
+        let delays: Vec<u64> = (1..=7)
+            .map(|failures| supervisor.delay(failures, 0.0).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1_000, 1_000, 1_000]);
+        // Huge failure counts neither overflow nor pass the cap.
+        assert_eq!(supervisor.delay(u32::MAX, 0.0), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_shortens_a_delay_by_at_most_its_fraction() {
+        let supervisor = supervisor(60);
+        assert_eq!(supervisor.delay(3, 0.0), Duration::from_millis(400));
+        assert_eq!(supervisor.delay(3, 0.5), Duration::from_millis(300));
+        let shortest = supervisor.delay(3, 1.0 - f64::EPSILON);
+        assert!(shortest >= Duration::from_millis(200) && shortest < Duration::from_millis(201));
+        for _ in 0..20 {
+            let delay = supervisor.failed(&err());
+            let base = supervisor.delay(supervisor.status().consecutive_failures, 0.0);
+            assert!(
+                delay <= base && delay >= base / 2,
+                "{delay:?} against {base:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn consecutive_failures_report_a_crash_loop() {
+        let supervisor = supervisor(3_600);
+        supervisor.failed(&err());
+        supervisor.failed(&err());
+        assert!(!supervisor.status().crash_loop);
+        supervisor.failed(&err());
+        let status = supervisor.status();
+        assert!(status.crash_loop);
+        assert_eq!(status.state, ConnectionState::BackingOff);
+        assert_eq!(
+            status.last_error.as_deref(),
+            Some(err().to_string().as_str())
+        );
+
+        // A connection that drops before it is stable keeps counting.
+        supervisor.connected();
+        assert!(supervisor.status().crash_loop);
+        supervisor.failed(&err());
+        let status = supervisor.status();
+        assert_eq!((status.consecutive_failures, status.total_failures), (4, 4));
+        assert!(status.crash_loop);
+    }
+
+    #[test]
+    fn a_stable_connection_resets_the_backoff() {
+        let supervisor = supervisor(0);
+        for _ in 0..3 {
+            supervisor.failed(&err());
+        }
+        assert!(supervisor.status().crash_loop);
+        supervisor.connected();
+        let status = supervisor.status();
+        assert_eq!(status.state, ConnectionState::Connected);
+        assert!(!status.crash_loop);
+        assert_eq!(status.consecutive_failures, 0);
+
+        // The next failure starts again from the initial delay.
+        supervisor.connected();
+        let delay = supervisor.failed(&err());
+        assert!(delay <= Duration::from_millis(100));
+        let status = supervisor.status();
+        assert_eq!((status.consecutive_failures, status.total_failures), (1, 4));
+    }
+}
@@ -0,0 +1,256 @@
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use std::fs::{self, DirBuilder};
+use std::io::{BufReader, Cursor};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsConnector;
+
+use crate::config::{read_secret, BrokerConfig, TlsConfig};
+use crate::GatewayError;
+
+/// SNI tunnels started by this process, numbering their directories.
+static TUNNELS: AtomicU64 = AtomicU64::new(0);
+
+/// Builds the rustls client config for the broker connection. Files are read
+/// on every call, so renewed certificates are picked up on reconnect.
+pub fn client_config(config: &TlsConfig) -> Result<Arc<ClientConfig>, GatewayError> {
+    let mut roots = RootCertStore::empty();
+    match &config.ca_file {
+        Some(path) => {
+            let certs = read_certs(path)?;
+            roots.add_parsable_certificates(certs);
+            if roots.is_empty() {
+                return Err(tls_err(&format!(
+                    "{}: no usable CA certificate",
+                    path.display()
+                )));
+            }
+        }
+        None => {
+            let certs = rustls_native_certs::load_native_certs()
+                .map_err(|err| tls_err(&format!("cannot load system CA certificates: {err}")))?;
+            roots.add_parsable_certificates(certs);
+        }
+    }
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+
+    let client_config = match &config.client_cert_file {
+        Some(cert_path) => {
+            let certs = read_certs(cert_path)?;
+            if certs.is_empty() {
+                return Err(tls_err(&format!(
+                    "{}: no client certificate",
+                    cert_path.display()
+                )));
+            }
+            let key = read_secret(
+                "broker.tls client key",
+                config.client_key_file.as_deref(),
+                config.client_key_env.as_deref(),
+            )?
+            .ok_or_else(|| tls_err("a client certificate needs a client key"))?;
+            builder
+                .with_client_auth_cert(certs, parse_key(&key)?)
+                .map_err(|err| tls_err(&format!("client certificate: {err}")))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(client_config))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, GatewayError> {
+    let pem = std::fs::read(path)
+        .map_err(|err| tls_err(&format!("cannot read {}: {err}", path.display())))?;
+    rustls_pemfile::certs(&mut BufReader::new(Cursor::new(pem)))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| tls_err(&format!("{}: {err}", path.display())))
+}
+
+fn parse_key(pem: &str) -> Result<PrivateKeyDer<'static>, GatewayError> {
+    rustls_pemfile::private_key(&mut BufReader::new(Cursor::new(pem.as_bytes())))
+        .map_err(|err| tls_err(&format!("client key: {err}")))?
+        .ok_or_else(|| tls_err("client key: no PEM private key found"))
+}
+
+fn tls_err(msg: &str) -> GatewayError {
+    GatewayError::Tls(msg.to_string())
+}
+
+pub fn server_name(name: &str) -> Result<ServerName<'static>, GatewayError> {
+    ServerName::try_from(name.to_string())
+        .map_err(|err| tls_err(&format!("invalid server_name {name:?}: {err}")))
+}
+
+/// A Unix socket rumqttc connects to instead of the broker, for the TLS
+/// connection to send `tls.server_name` as the SNI; rumqttc itself would
+/// always send `broker.host`.
+///
+/// Every connection to the socket is relayed to `broker.host` over TLS with
+/// the certificate checked against `server_name`. The TLS config is built
+/// per connection, so renewed certificates are picked up on reconnect. Each
+/// MQTT connection opens its own tunnel from its own config, and dropping
+/// the tunnel stops the relay and removes the directory, only this user can
+/// enter, that the socket sits in.
+pub struct SniTunnel {
+    dir: PathBuf,
+    relay: JoinHandle<()>,
+    /// Why relayed connections failed, such as a refused handshake.
+    errors: mpsc::UnboundedReceiver<GatewayError>,
+}
+
+impl SniTunnel {
+    pub fn start(
+        broker: &BrokerConfig,
+        tls: &TlsConfig,
+        name: &str,
+    ) -> Result<SniTunnel, GatewayError> {
+        let name = server_name(name)?;
+        let n = TUNNELS.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("{}{n}", dir_prefix()));
+        let _ = fs::remove_dir_all(&dir);
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let listener = match UnixListener::bind(dir.join("broker.sock")) {
+            Ok(listener) => listener,
+            Err(err) => {
+                let _ = fs::remove_dir_all(&dir);
+                return Err(err.into());
+            }
+        };
+        let (tx, errors) = mpsc::unbounded_channel();
+        let relay = tokio::spawn(relay(
+            listener,
+            (broker.host.clone(), broker.port),
+            tls.clone(),
+            name,
+            tx,
+        ));
+        Ok(SniTunnel { dir, relay, errors })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.join("broker.sock")
+    }
+
+    /// Why the last relayed connection failed, if one did since the last
+    /// call; rumqttc only sees its end of the socket close.
+    pub fn error(&mut self) -> Option<GatewayError> {
+        let mut last = None;
+        while let Ok(err) = self.errors.try_recv() {
+            last = Some(err);
+        }
+        last
+    }
+}
+
+impl Drop for SniTunnel {
+    fn drop(&mut self) {
+        self.relay.abort();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Directories of this process's tunnels start with this.
+fn dir_prefix() -> String {
+    format!("jetson-gateway-{}-", std::process::id())
+}
+
+/// Removes the directories of tunnels still open, for an exit that does not
+/// wait for the connections owning them to be dropped.
+pub fn remove_tunnels() {
+    let Ok(entries) = fs::read_dir(std::env::temp_dir()) else {
+        return;
+    };
+    let prefix = dir_prefix();
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+async fn relay(
+    listener: UnixListener,
+    broker: (String, u16),
+    tls: TlsConfig,
+    name: ServerName<'static>,
+    errors: mpsc::UnboundedSender<GatewayError>,
+) {
+    loop {
+        let mut local = match listener.accept().await {
+            Ok((local, _)) => local,
+            Err(err) => {
+                eprintln!("jetson-gateway: broker TLS tunnel: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let (broker, tls, name) = (broker.clone(), tls.clone(), name.clone());
+        let errors = errors.clone();
+        tokio::spawn(async move {
+            // Sent before `local` is dropped, which tells rumqttc; its
+            // connection error is then replaced with this one.
+            let to = format!("{}:{}", broker.0, broker.1);
+            if let Err(err) = tunnel(&mut local, broker, &tls, name).await {
+                let _ = errors.send(tls_err(&format!("tunnel to {to}: {err}")));
+            }
+        });
+    }
+}
+
+async fn tunnel(
+    local: &mut UnixStream,
+    broker: (String, u16),
+    tls: &TlsConfig,
+    name: ServerName<'static>,
+) -> Result<(), GatewayError> {
+    let config = client_config(tls)?;
+    let tcp = TcpStream::connect(broker).await?;
+    let mut remote = TlsConnector::from(config).connect(name, tcp).await?;
+    tokio::io::copy_bidirectional(local, &mut remote).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn a_tunnel_reports_why_it_failed_and_removes_its_socket() {
+        let broker = BrokerConfig {
+            host: "127.0.0.1".to_string(),
+            ..BrokerConfig::default()
+        };
+        let tls = TlsConfig {
+            ca_file: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..TlsConfig::default()
+        };
+        let mut tunnel = SniTunnel::start(&broker, &tls, "broker.example").unwrap();
+        let other = SniTunnel::start(&broker, &tls, "broker.example").unwrap();
+        assert_ne!(tunnel.path(), other.path());
+        assert!(tunnel.error().is_none());
+
+        // The relay closes the socket once the handshake fails.
+        let mut local = UnixStream::connect(tunnel.path()).await.unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(local.read(&mut buf).await.unwrap(), 0);
+        let err = tunnel.error().unwrap().to_string();
+        assert!(err.contains("cannot read /nonexistent/ca.pem"), "{err}");
+        assert!(tunnel.error().is_none());
+
+        let dir = tunnel.dir.clone();
+        drop(tunnel);
+        assert!(!dir.exists());
+        let dir = other.dir.clone();
+        remove_tunnels();
+        assert!(!dir.exists());
+    }
+}
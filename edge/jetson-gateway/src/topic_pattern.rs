@@ -0,0 +1,439 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::Config;
+use crate::RawAnalyticsEvent;
+
+/// Layout of incoming topics such as `sites/{site}/{zone_id}/{kind}`, read
+/// back from each message's topic. A level is a literal, `+`, a trailing
+/// `#`, or a capture with an optional literal prefix and suffix
+/// (`cam-{device_id}`). `device_id`, `zone_id` and `kind` captures fill the
+/// event; other names only match a level.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TopicPattern {
+    levels: Vec<Level>,
+}
+
+#[derive(Debug, Clone)]
+enum Level {
+    Literal(String),
+    Any,
+    Rest,
+    Capture {
+        prefix: String,
+        field: Option<Field>,
+        suffix: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    DeviceId,
+    ZoneId,
+    Kind,
+}
+
+impl Field {
+    fn name(self) -> &'static str {
+        match self {
+            Field::DeviceId => "device_id",
+            Field::ZoneId => "zone_id",
+            Field::Kind => "kind",
+        }
+    }
+}
+
+impl TryFrom<String> for TopicPattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<TopicPattern, String> {
+        let invalid = |why: &str| format!("invalid topic_pattern {pattern:?}: {why}");
+        let count = pattern.split('/').count();
+        let mut levels = Vec::new();
+        let mut fields = Vec::new();
+        for (n, level) in pattern.split('/').enumerate() {
+            let parsed = match level {
+                "+" => Level::Any,
+                "#" if n + 1 == count => Level::Rest,
+                "#" => return Err(invalid("# must be the last level")),
+                _ if !level.contains(['{', '}']) => {
+                    if level.contains(['+', '#']) {
+                        return Err(invalid("wildcards must fill a whole level"));
+                    }
+                    Level::Literal(level.to_string())
+                }
+                _ => {
+                    let (prefix, rest) = level.split_once('{').ok_or_else(|| invalid("stray }"))?;
+                    let (name, suffix) = rest
+                        .split_once('}')
+                        .ok_or_else(|| invalid("unterminated capture"))?;
+                    if name.is_empty()
+                        || [prefix, name, suffix]
+                            .iter()
+                            .any(|s| s.contains(['{', '}', '+', '#']))
+                    {
+                        return Err(invalid("one named capture per level"));
+                    }
+                    let field = match name {
+                        "device_id" => Some(Field::DeviceId),
+                        "zone_id" => Some(Field::ZoneId),
+                        "kind" => Some(Field::Kind),
+                        _ => None,
+                    };
+                    if let Some(field) = field {
+                        if fields.contains(&field) {
+                            return Err(invalid(&format!("{{{name}}} captured twice")));
+                        }
+                        fields.push(field);
+                    }
+                    Level::Capture {
+                        prefix: prefix.to_string(),
+                        field,
+                        suffix: suffix.to_string(),
+                    }
+                }
+            };
+            levels.push(parsed);
+        }
+        if fields.is_empty() {
+            return Err(invalid("no {device_id}, {zone_id} or {kind} capture"));
+        }
+        Ok(TopicPattern { levels })
+    }
+}
+
+impl TopicPattern {
+    /// Whether some topic fits both the pattern and the subscription filter.
+    pub fn overlaps(&self, filter: &str) -> bool {
+        let mut filter = filter.split('/');
+        for level in &self.levels {
+            let Some(other) = filter.next() else {
+                return matches!(level, Level::Rest);
+            };
+            match (level, other) {
+                (Level::Rest, _) | (_, "#") => return true,
+                (Level::Literal(literal), other) if other != "+" && other != literal => {
+                    return false;
+                }
+                (Level::Capture { prefix, suffix, .. }, other)
+                    if other != "+"
+                        && !(other.len() > prefix.len() + suffix.len()
+                            && other.starts_with(prefix.as_str())
+                            && other.ends_with(suffix.as_str())) =>
+                {
+                    return false;
+                }
+                _ => {}
+            }
+        }
+        matches!(filter.next(), None | Some("#"))
+    }
+
+    /// The captured fields of `topic`; `None` when it does not fit.
+    fn captures<'t>(&self, topic: &'t str) -> Option<Vec<(Field, &'t str)>> {
+        let mut captures = Vec::new();
+        let mut levels = topic.split('/');
+        for level in &self.levels {
+            match level {
+                Level::Rest => return Some(captures),
+                Level::Any => {
+                    levels.next()?;
+                }
+                Level::Literal(literal) => {
+                    if levels.next()? != literal {
+                        return None;
+                    }
+                }
+                Level::Capture {
+                    prefix,
+                    field,
+                    suffix,
+                } => {
+                    let value = levels
+                        .next()?
+                        .strip_prefix(prefix.as_str())?
+                        .strip_suffix(suffix.as_str())?;
+                    if value.is_empty() {
+                        return None;
+                    }
+                    if let Some(field) = field {
+                        captures.push((*field, value));
+                    }
+                }
+            }
+        }
+        levels.next().is_none().then_some(captures)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopicCheckStatus {
+    /// Messages whose payload disagreed with the topic, per field.
+    pub device_id: u64,
+    pub zone_id: u64,
+    pub kind: u64,
+    /// Topics the subscription's pattern did not fit.
+    pub unmatched: u64,
+}
+
+/// Applies topic patterns and counts what did not line up.
+pub struct TopicChecks {
+    /// Some subscription has a pattern.
+    enabled: bool,
+    device_id: AtomicU64,
+    zone_id: AtomicU64,
+    kind: AtomicU64,
+    unmatched: AtomicU64,
+}
+
+impl TopicChecks {
+    pub fn new(config: &Config) -> TopicChecks {
+        TopicChecks {
+            enabled: config
+                .subscriptions
+                .iter()
+                .any(|sub| sub.topic_pattern.is_some()),
+            device_id: AtomicU64::new(0),
+            zone_id: AtomicU64::new(0),
+            kind: AtomicU64::new(0),
+            unmatched: AtomicU64::new(0),
+        }
+    }
+
+    /// Fills the fields `raw` leaves empty from the topic's captures. Where
+    /// both carry a value the payload's is kept, and a disagreement is
+    /// counted and logged.
+    pub fn apply(&self, pattern: &TopicPattern, topic: &str, raw: &mut RawAnalyticsEvent) {
+        let Some(captures) = pattern.captures(topic) else {
+            let n = self.unmatched.fetch_add(1, Ordering::Relaxed) + 1;
+            if sampled(n) {
+                eprintln!(
+                    "jetson-gateway: topic {topic} does not fit its topic_pattern ({n} so far)"
+                );
+            }
+            return;
+        };
+        for (field, value) in captures {
+            let (current, counter) = match field {
+                Field::DeviceId => (&mut raw.device_id, &self.device_id),
+                Field::ZoneId => (&mut raw.zone_id, &self.zone_id),
+                Field::Kind => (&mut raw.kind, &self.kind),
+            };
+            if current.is_empty() {
+                *current = value.to_string();
+            } else if current != value {
+                let n = counter.fetch_add(1, Ordering::Relaxed) + 1;
+                if sampled(n) {
+                    eprintln!(
+                        "jetson-gateway: topic {topic} has {} {value:?} but the payload says {current:?}; keeping the payload's ({n} so far)",
+                        field.name()
+                    );
+                }
+            }
+        }
+    }
+
+    /// `None` when no subscription has a pattern.
+    pub fn status(&self) -> Option<TopicCheckStatus> {
+        if !self.enabled {
+            return None;
+        }
+        Some(TopicCheckStatus {
+            device_id: self.device_id.load(Ordering::Relaxed),
+            zone_id: self.zone_id.load(Ordering::Relaxed),
+            kind: self.kind.load(Ordering::Relaxed),
+            unmatched: self.unmatched.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Logs the 1st, 10th, 100th, ... occurrence; the health report has the
+/// exact counts.
+fn sampled(n: u64) -> bool {
+    let mut power = 1;
+    while power < n {
+        power *= 10;
+    }
+    power == n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(text: &str) -> TopicPattern {
+        TopicPattern::try_from(text.to_string()).unwrap()
+    }
+
+    fn checks() -> TopicChecks {
+        TopicChecks {
+            enabled: true,
+            device_id: AtomicU64::new(0),
+            zone_id: AtomicU64::new(0),
+            kind: AtomicU64::new(0),
+            unmatched: AtomicU64::new(0),
+        }
+    }
+
+    fn raw(device_id: &str, zone_id: &str, kind: &str) -> RawAnalyticsEvent {
+        RawAnalyticsEvent {
+            device_id: device_id.to_string(),
+            zone_id: zone_id.to_string(),
+            kind: kind.to_string(),
+            payload: serde_json::Value::Null,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    #[test]
+    fn malformed_patterns_are_refused() {
+        let cases = [
+            (
+                "analytics/+/events",
+                "no {device_id}, {zone_id} or {kind} capture",
+            ),
+            (
+                "analytics/{site}/events",
+                "no {device_id}, {zone_id} or {kind} capture",
+            ),
+            ("#/{device_id}", "# must be the last level"),
+            ("a+/{device_id}", "wildcards must fill a whole level"),
+            ("{device_id", "unterminated capture"),
+            ("device_id}", "stray }"),
+            ("{}/{kind}", "one named capture per level"),
+            ("{zone_id}-{kind}", "one named capture per level"),
+            ("{kind}+", "one named capture per level"),
+            ("{device_id}/x/{device_id}", "{device_id} captured twice"),
+        ];
+        for (text, why) in cases {
+            let err = TopicPattern::try_from(text.to_string()).unwrap_err();
+            assert_eq!(err, format!("invalid topic_pattern {text:?}: {why}"));
+        }
+        for text in [
+            "analytics/{device_id}/events",
+            "sites/{site}/{zone_id}/{kind}",
+            "cam-{device_id}.v1/+/#",
+            "{kind}",
+        ] {
+            assert!(TopicPattern::try_from(text.to_string()).is_ok(), "{text}");
+        }
+    }
+
+    #[test]
+    fn overlaps_compares_levels_with_the_filter() {
+        let cases = [
+            ("analytics/{device_id}/events", "analytics/+/events", true),
+            ("analytics/{device_id}/events", "analytics/#", true),
+            ("analytics/{device_id}/events", "#", true),
+            (
+                "analytics/{device_id}/events",
+                "analytics/cam-1/events",
+                true,
+            ),
+            ("analytics/{device_id}/events", "telemetry/+/events", false),
+            ("analytics/{device_id}/events", "analytics/+", false),
+            (
+                "analytics/{device_id}/events",
+                "analytics/+/events/raw",
+                false,
+            ),
+            ("analytics/{device_id}/#", "analytics/+", true),
+            ("analytics/{device_id}/#", "analytics/+/a/b", true),
+            ("cam-{device_id}/+", "cam-7/x", true),
+            ("cam-{device_id}/+", "cam-/x", false),
+            ("cam-{device_id}/+", "dock-7/x", false),
+            ("+/{kind}", "a/b/#", true),
+        ];
+        for (text, filter, expected) in cases {
+            assert_eq!(
+                pattern(text).overlaps(filter),
+                expected,
+                "{text} on {filter}"
+            );
+        }
+    }
+
+    #[test]
+    fn captures_name_the_fields_of_a_topic() {
+        let sites = pattern("sites/{site}/{zone_id}/{kind}");
+        assert_eq!(
+            sites.captures("sites/north/dock-2/person"),
+            Some(vec![(Field::ZoneId, "dock-2"), (Field::Kind, "person")])
+        );
+        assert_eq!(sites.captures("sites/north/dock-2"), None);
+        assert_eq!(sites.captures("sites/north/dock-2/person/x"), None);
+        assert_eq!(sites.captures("sites/north//person"), None);
+
+        let cams = pattern("cam-{device_id}.v1/+/#");
+        assert_eq!(
+            cams.captures("cam-7.v1/raw/a/b"),
+            Some(vec![(Field::DeviceId, "7")])
+        );
+        assert_eq!(
+            cams.captures("cam-7.v1/raw"),
+            Some(vec![(Field::DeviceId, "7")])
+        );
+        assert_eq!(cams.captures("cam-.v1/raw"), None);
+        assert_eq!(cams.captures("cam-7.v2/raw"), None);
+    }
+
+    #[test]
+    fn apply_fills_empty_fields_and_counts_disagreements() {
+        let checks = checks();
+        let sites = pattern("sites/{site}/{zone_id}/{kind}");
+
+        let mut filled = raw("cam-1", "", "");
+        checks.apply(&sites, "sites/north/dock-2/person", &mut filled);
+        assert_eq!(
+            (filled.zone_id.as_str(), filled.kind.as_str()),
+            ("dock-2", "person")
+        );
+
+        // The payload's value is kept; only the disagreement is counted.
+        let mut disagrees = raw("cam-1", "dock-9", "person");
+        checks.apply(&sites, "sites/north/dock-2/vehicle", &mut disagrees);
+        assert_eq!(
+            (disagrees.zone_id.as_str(), disagrees.kind.as_str()),
+            ("dock-9", "person")
+        );
+        checks.apply(&sites, "sites/north/dock-3/person", &mut disagrees);
+
+        // A topic the pattern does not fit leaves the event alone.
+        let mut unmatched = raw("", "", "");
+        checks.apply(&sites, "sites/north", &mut unmatched);
+        assert_eq!(unmatched.zone_id, "");
+
+        let status = checks.status().unwrap();
+        assert_eq!(
+            (
+                status.device_id,
+                status.zone_id,
+                status.kind,
+                status.unmatched
+            ),
+            (0, 2, 1, 1)
+        );
+    }
+
+    #[test]
+    fn shared_subscriptions_are_checked_against_their_filter() {
+        let config: Config = toml::from_str(
+            r#"
+            [[subscriptions]]
+            topic = "$share/gateways/analytics/+/events"
+            topic_pattern = "analytics/{device_id}/events"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert!(config.subscriptions[0].matches("analytics/cam-1/events"));
+        assert!(TopicChecks::new(&config).status().is_some());
+    }
+
+    #[test]
+    fn warnings_are_sampled_by_powers_of_ten() {
+        let logged: Vec<u64> = (1..=1000).filter(|&n| sampled(n)).collect();
+        assert_eq!(logged, [1, 10, 100, 1000]);
+    }
+}
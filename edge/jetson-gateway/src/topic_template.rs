@@ -0,0 +1,93 @@
+use crate::{GatewayError, VirtualObjectEvent};
+
+/// Output topic such as `vo/{zone_id}/{category}`, rendered per event.
+#[derive(Debug, Clone)]
+pub struct TopicTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    DeviceId,
+    ZoneId,
+    Category,
+    EventId,
+}
+
+impl TopicTemplate {
+    pub fn parse(template: &str) -> Result<TopicTemplate, GatewayError> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| {
+                GatewayError::Config(format!("unterminated placeholder in topic {template:?}"))
+            })? + start;
+            let field = match &rest[start + 1..end] {
+                "device_id" => Field::DeviceId,
+                "zone_id" => Field::ZoneId,
+                "category" => Field::Category,
+                "event_id" => Field::EventId,
+                other => {
+                    return Err(GatewayError::Config(format!(
+                        "unknown placeholder {{{other}}} in topic {template:?}"
+                    )))
+                }
+            };
+            parts.push(Part::Field(field));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        let parsed = TopicTemplate { parts };
+        let sample = parsed.render_with(|_| "x");
+        if !rumqttc::valid_topic(&sample) || sample.contains('}') {
+            return Err(GatewayError::Config(format!(
+                "invalid output topic {template:?}"
+            )));
+        }
+        Ok(parsed)
+    }
+
+    pub fn render(&self, event: &VirtualObjectEvent) -> String {
+        self.render_with(|field| match field {
+            Field::DeviceId => &event.device_id,
+            Field::ZoneId => &event.zone_id,
+            Field::Category => &event.category,
+            Field::EventId => &event.event_id,
+        })
+    }
+
+    fn render_with<'a>(&self, value: impl Fn(Field) -> &'a str) -> String {
+        let mut topic = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => topic.push_str(text),
+                Part::Field(field) => push_segment(&mut topic, value(*field)),
+            }
+        }
+        topic
+    }
+}
+
+/// Substituted values must not add topic levels or wildcards.
+fn push_segment(topic: &mut String, value: &str) {
+    if value.is_empty() {
+        topic.push_str("unknown");
+        return;
+    }
+    topic.extend(value.chars().map(|c| match c {
+        '/' | '+' | '#' | '\0' => '_',
+        c => c,
+    }));
+}